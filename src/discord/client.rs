@@ -1,37 +1,42 @@
 use crate::discord::message::{read_discord_reply, DiscordMessage, Reply};
-use crate::https::persistent_client::PersistentClient;
-use crate::https::response::Response;
-use std::collections::HashMap;
-use std::io::Error;
+use bigeon_rust::https::error::HttpResult;
+use bigeon_rust::https::headers::HeaderMap;
+use bigeon_rust::https::middleware::{Auth, DefaultHeaders, Logger};
+use bigeon_rust::https::multipart::Multipart;
+use bigeon_rust::https::persistent_client::PersistentClient;
+use bigeon_rust::https::pool::ConnectionPool;
+use bigeon_rust::https::transport::Connector;
+use bigeon_rust::https::url::UrlBuilder;
 use std::str;
 
 const DISCORD_USER_AGENT: &str = "DiscordBot (Bigeon, 0.0.2)";
 const DISCORD_API_URL: &str = "https://discord.com/api/v10";
 
-type HeaderMap<'a> = HashMap<&'a str, String>;
-
 pub struct DiscordClient<'a> {
-    conn: PersistentClient<'a>,
+    conn: PersistentClient,
     token: &'a str,
-    headers: HeaderMap<'a>,
 }
 
 impl<'a> DiscordClient<'a> {
-    pub fn new(token: &'a str) -> Result<Self, Error> {
-        let token_string = format!("Bot {}", token);
-        let base_headers = vec![
-            ("Content-Type", "application/json".to_string()),
-            ("Authorization", token_string),
-        ]
-        .into_iter()
-        .collect::<HeaderMap<'a>>();
-        let conn = PersistentClient::new(DISCORD_USER_AGENT, "https://discord.com")?;
-
-        Ok(Self {
-            token,
-            conn,
-            headers: base_headers,
-        })
+    pub fn new(token: &'a str) -> HttpResult<Self> {
+        Self::with_connector(token, Connector::from_env())
+    }
+
+    // Opens its connection with `connector`, like a mock in tests
+    pub fn with_connector(token: &'a str, connector: Connector) -> HttpResult<Self> {
+        let mut conn = PersistentClient::with_connector(
+            DISCORD_USER_AGENT,
+            "https://discord.com",
+            ConnectionPool::global().clone(),
+            connector,
+        )?;
+        // multipart uploads bring their own Content-Type
+        let json = HeaderMap::from_pairs(&[("Content-Type", "application/json")])?;
+        conn.add_middleware(Logger::new());
+        conn.add_middleware(Auth::new(&format!("Bot {}", token)));
+        conn.add_middleware(DefaultHeaders::new(json));
+
+        Ok(Self { token, conn })
     }
     pub fn send_message(
         &mut self,
@@ -39,15 +44,154 @@ impl<'a> DiscordClient<'a> {
         channel_id: &'a str,
     ) -> Result<Box<dyn Reply>, Box<dyn std::error::Error>> {
         let msg_bytes = msg.to_vec()?;
-        let url = format!("{}/channels/{}/messages", DISCORD_API_URL, channel_id);
-        let reply = match self.conn.post(&url) {
-            Ok(mut r) => r.content(&msg_bytes).headers(&self.headers).execute()?,
-            Err(e) => panic!("{}", e),
-        };
-        let resp = Response::from_slice(&reply)?;
-
-        println!("{:#?}", resp);
+        let url = UrlBuilder::new(DISCORD_API_URL)?
+            .segments(&["channels", channel_id, "messages"])
+            .build()
+            .to_string();
+        let resp = self
+            .conn
+            .post(&url)?
+            .content(&msg_bytes)
+            .execute(&mut self.conn)?;
+
+        let discord_response = str::from_utf8(&resp.content)?;
+        read_discord_reply(discord_response)
+    }
+
+    // Sends a message with attachments, given as (filename, content) pairs
+    pub fn send_files(
+        &mut self,
+        msg: DiscordMessage,
+        channel_id: &'a str,
+        files: &[(&str, Vec<u8>)],
+    ) -> Result<Box<dyn Reply>, Box<dyn std::error::Error>> {
+        let msg_json = String::from_utf8(msg.to_vec()?)?;
+        let mut form = Multipart::new().text("payload_json", &msg_json);
+        for (i, (filename, content)) in files.iter().enumerate() {
+            form = form.file(&format!("files[{}]", i), filename, content.clone());
+        }
+
+        let url = UrlBuilder::new(DISCORD_API_URL)?
+            .segments(&["channels", channel_id, "messages"])
+            .build()
+            .to_string();
+        let resp = self
+            .conn
+            .post(&url)?
+            .multipart(&form)
+            .execute(&mut self.conn)?;
+
         let discord_response = str::from_utf8(&resp.content)?;
         read_discord_reply(discord_response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::message::MessageBuilder;
+    use bigeon_rust::https::cassette::{replay, Recorder};
+    use bigeon_rust::https::client::Methods;
+    use bigeon_rust::https::mock::{Mock, Route};
+    use serde_json::json;
+    use std::fs;
+
+    const MESSAGES_URL: &str = "https://discord.com/api/v10/channels/123/messages";
+
+    fn client(mock: &Mock) -> DiscordClient<'static> {
+        DiscordClient::with_connector("secret", Connector::new().dialer(mock.clone())).unwrap()
+    }
+
+    #[test]
+    fn send_message() {
+        let mock = Mock::new().route(
+            Route::post(MESSAGES_URL)
+                .unwrap()
+                .json(&json!({"content": "Ahaha!", "tts": false}))
+                .times(1),
+        );
+        let mut client = client(&mock);
+        let msg = MessageBuilder::new().content("Ahaha!").build();
+        let reply = client.send_message(msg, "123").unwrap();
+        assert!(!reply.is_error());
+        assert!(reply.to_str().unwrap().contains("Ahaha!"));
+
+        assert!(mock.is_done());
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        let req = &requests[0];
+        assert_eq!(req.method, Methods::POST);
+        assert_eq!(req.url.to_string(), MESSAGES_URL);
+        assert_eq!(req.headers.get("Authorization"), Some("Bot secret"));
+        assert_eq!(req.headers.get("User-Agent"), Some(DISCORD_USER_AGENT));
+        assert_eq!(req.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(req.body_str(), r#"{"content":"Ahaha!"}"#);
+    }
+
+    #[test]
+    fn messages_reuse_the_connection() {
+        let mock = Mock::new().route(Route::post(MESSAGES_URL).unwrap().json(&json!({})).times(2));
+        let mut client = client(&mock);
+        for text in ["one", "two"] {
+            let msg = MessageBuilder::new().content(text).build();
+            client.send_message(msg, "123").unwrap();
+        }
+        assert!(mock.is_done());
+        assert_eq!(mock.requests().len(), 2);
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[test]
+    fn send_files() {
+        let mock = Mock::new().route(Route::post(MESSAGES_URL).unwrap().json(&json!({})).times(1));
+        let mut client = client(&mock);
+        let msg = MessageBuilder::new().content("look").build();
+        client
+            .send_files(msg, "123", &[("a.txt", b"file body".to_vec())])
+            .unwrap();
+
+        assert!(mock.is_done());
+        let req = &mock.requests()[0];
+        assert_eq!(req.headers.get("Authorization"), Some("Bot secret"));
+        let content_type = req.headers.get("Content-Type").unwrap();
+        assert!(content_type.starts_with("multipart/form-data; boundary="));
+        let body = req.body_str();
+        assert!(body.contains("name=\"payload_json\"\r\n\r\n{\"content\":\"look\"}\r\n"));
+        assert!(body.contains("name=\"files[0]\"; filename=\"a.txt\""));
+        assert!(body.contains("\r\n\r\nfile body\r\n"));
+    }
+
+    #[test]
+    fn send_message_from_a_cassette() {
+        let path = std::env::temp_dir().join(format!("bigeon-discord-{}.json", std::process::id()));
+        let discord = Mock::new().route(
+            Route::post(MESSAGES_URL)
+                .unwrap()
+                .json(&json!({"content": "recorded"})),
+        );
+        let recorder = Recorder::new(&path, Connector::new().dialer(discord));
+        let mut client =
+            DiscordClient::with_connector("secret", Connector::new().dialer(recorder)).unwrap();
+        let msg = MessageBuilder::new().content("recorded").build();
+        client.send_message(msg, "123").unwrap();
+
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("Bot secret"));
+        assert!(saved.contains("<redacted>"));
+        assert!(saved.contains(MESSAGES_URL));
+
+        let replayed = replay(&path).unwrap();
+        let mut client =
+            DiscordClient::with_connector("other", Connector::new().dialer(replayed.clone()))
+                .unwrap();
+        let msg = MessageBuilder::new().content("recorded").build();
+        let reply = client.send_message(msg, "123").unwrap();
+        assert!(reply.to_str().unwrap().contains("recorded"));
+        assert!(replayed.is_done());
+        assert_eq!(
+            replayed.requests()[0].headers.get("Authorization"),
+            Some("Bot other")
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -110,9 +110,9 @@ pub trait Reply {
 impl Debug for dyn Reply {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.is_error() {
-            return write!(f, "DiscordError");
+            write!(f, "DiscordError")
         } else {
-            return write!(f, "DiscordMessage");
+            write!(f, "DiscordMessage")
         }
     }
 }
@@ -140,7 +140,6 @@ impl Reply for DiscordError {
 pub fn read_discord_reply(json: &str) -> Result<Box<dyn Reply>, Box<dyn std::error::Error>> {
     println!("Test: {}", json);
     let msg: Box<dyn Reply>;
-    let error: DiscordError;
     match serde_json::from_str::<DiscordMessage>(json) {
         Ok(out) => msg = Box::new(out),
         Err(e) => {
@@ -148,7 +147,7 @@ pub fn read_discord_reply(json: &str) -> Result<Box<dyn Reply>, Box<dyn std::err
                 let d = serde_json::from_str::<DiscordError>(json).unwrap();
                 return Ok(Box::new(d));
             } else {
-                return Err(e).unwrap();
+                return Err(Box::new(e));
             }
         }
     };
@@ -0,0 +1,519 @@
+use super::client::Methods;
+use super::error::{HttpError, HttpResult};
+use super::headers::HeaderMap;
+use super::pool::{PoolKey, DEFAULT_IDLE_TIMEOUT};
+use super::redirect::RedirectPolicy;
+use super::request::RequestBuilder;
+use super::response::Response;
+use super::retry::RetryPolicy;
+use super::timeout::Timeouts;
+use super::transport::{AsyncTransport, Connector};
+use super::url::{Url, UrlError};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::io::{AsyncBufRead, AsyncWriteExt, BufReader};
+
+// The async clients don't share a pool, every client keeps
+// its own idle connections, one per (scheme, host, port).
+
+type TLSResult<T> = Result<T, Error>;
+
+pub type AsyncConnection = BufReader<Box<dyn AsyncTransport>>;
+
+struct IdleConnection {
+    conn: AsyncConnection,
+    since: Instant,
+}
+
+// Checks, without waiting, if the peer closed an idle connection.
+// Unexpected data also counts as closed.
+fn is_closed(conn: &mut AsyncConnection) -> bool {
+    let mut cx = Context::from_waker(Waker::noop());
+    !matches!(Pin::new(conn).poll_fill_buf(&mut cx), Poll::Pending)
+}
+
+#[derive(Default)]
+struct Connections {
+    idle: HashMap<PoolKey, IdleConnection>,
+    connector: Connector,
+}
+
+impl Connections {
+    // Takes the idle connection to the url's host, or opens a new one
+    async fn checkout(&mut self, url: &Url, timeouts: &Timeouts) -> TLSResult<AsyncConnection> {
+        let key = PoolKey::from_url(url, &self.connector);
+        if let Some(mut idle) = self.idle.remove(&key) {
+            if idle.since.elapsed() < DEFAULT_IDLE_TIMEOUT && !is_closed(&mut idle.conn) {
+                debug!("Reusing connection to {}", url.socket_addr());
+                idle.conn.get_mut().set_timeouts(timeouts);
+                return Ok(idle.conn);
+            }
+        }
+
+        info!("Opening a connection to {}", url.socket_addr());
+        let stream = self.connector.connect_async(url, timeouts).await?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn send_once(
+        &mut self,
+        url: &Url,
+        buf: &[u8],
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let mut conn = self
+            .checkout(url, timeouts)
+            .await
+            .map_err(HttpError::connect)?;
+        let res = async {
+            conn.get_mut().write_all(buf).await?;
+            conn.get_mut().flush().await?;
+            Response::read_from_async(&mut conn, method).await
+        }
+        .await;
+        if let Ok(resp) = &res {
+            if resp.keep_alive() {
+                self.idle.insert(
+                    PoolKey::from_url(url, &self.connector),
+                    IdleConnection {
+                        conn,
+                        since: Instant::now(),
+                    },
+                );
+            }
+        }
+        res
+    }
+
+    // Sends a serialized request and reads the response.
+    // When a reused connection turns out to be dead, idempotent requests
+    // are sent once more on a fresh connection.
+    async fn send(
+        &mut self,
+        url: &Url,
+        buf: &[u8],
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let reused = self
+            .idle
+            .contains_key(&PoolKey::from_url(url, &self.connector));
+        match self.send_once(url, buf, method, timeouts).await {
+            Err(ref e) if reused && method.is_idempotent() && e.is_dead_connection() => {
+                info!("Connection was dropped by the server, retrying on a new one");
+                self.send_once(url, buf, method, timeouts).await
+            }
+            res => res,
+        }
+    }
+
+    // Sends `req`, following redirects as the policy allows
+    async fn follow(
+        &mut self,
+        req: RequestBuilder<'_>,
+        redirect: &RedirectPolicy,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let mut req = req;
+        let mut hops = 0;
+        loop {
+            let buf = req.build()?;
+            let resp = self.send(req.url(), &buf, req.method(), timeouts).await?;
+            match redirect.next_request(&req, &resp, hops)? {
+                Some(next) => {
+                    req = next;
+                    hops += 1;
+                }
+                None => return Ok(resp),
+            }
+        }
+    }
+
+    async fn execute(
+        &mut self,
+        req: RequestBuilder<'_>,
+        redirect: &RedirectPolicy,
+        retry: &RetryPolicy,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let mut attempt = 1;
+        loop {
+            let res = self.follow(req.clone(), redirect, timeouts).await;
+            match retry.wait_before_retry(req.method(), &res, attempt, timeouts) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return res,
+            }
+            attempt += 1;
+        }
+    }
+}
+
+// HttpsClient for tokio
+pub struct AsyncHttpsClient {
+    headers: HeaderMap,
+    conns: Connections,
+    redirect: RedirectPolicy,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+}
+
+impl AsyncHttpsClient {
+    pub fn new(agent: &str, extra_headers: Option<&HeaderMap>) -> HttpResult<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert("User-Agent", agent)?;
+        if let Some(h) = extra_headers {
+            headers.extend(h);
+        }
+
+        Ok(Self {
+            headers,
+            conns: Connections {
+                idle: HashMap::new(),
+                connector: Connector::from_env(),
+            },
+            redirect: RedirectPolicy::new(),
+            retry: RetryPolicy::new(),
+            timeouts: Timeouts::new(),
+        })
+    }
+
+    pub fn default_headers(&mut self, headers: &HeaderMap) {
+        self.headers.extend(headers);
+    }
+
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.redirect = policy;
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    // Used for requests which don't set their own
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // Replaces the proxy settings taken from the environment.
+    // Idle connections are closed, they might not go through the new proxy.
+    pub fn set_connector(&mut self, connector: Connector) {
+        self.conns.idle.clear();
+        self.conns.connector = connector;
+    }
+
+    pub fn request<'a>(&self, m: Methods, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
+        Ok(RequestBuilder::new(Url::new(url)?).http_method(m))
+    }
+
+    // Sends a request with the client's headers, redirect policy,
+    // retry policy and timeouts unless the request sets its own.
+    // Streamed bodies (`body_reader`, or multipart with a `Part::reader`)
+    // can't be sent here, they fail with `RequestError::Body`.
+    pub async fn send(&mut self, req: RequestBuilder<'_>) -> HttpResult<Response> {
+        let req = req.default_headers(&self.headers);
+        let redirect = req.redirect_or(&self.redirect);
+        let retry = req.retry_or(&self.retry);
+        // the total timeout covers every hop and retry
+        let timeouts = req.timeouts_or(&self.timeouts).started();
+        self.conns.execute(req, &redirect, &retry, &timeouts).await
+    }
+
+    pub async fn get(
+        &mut self,
+        url: &str,
+        extra_headers: Option<&HeaderMap>,
+    ) -> HttpResult<Response> {
+        let mut req = self.request(Methods::GET, url)?;
+        if let Some(h) = extra_headers {
+            req = req.headers(h);
+        }
+        self.send(req).await
+    }
+
+    pub async fn post(
+        &mut self,
+        url: &str,
+        content: Vec<u8>,
+        extra_headers: Option<&HeaderMap>,
+    ) -> HttpResult<Response> {
+        let mut req = self.request(Methods::POST, url)?.content(&content);
+        if let Some(h) = extra_headers {
+            req = req.headers(h);
+        }
+        self.send(req).await
+    }
+}
+
+// PersistentClient for tokio, keeps a connection to one host open
+pub struct AsyncPersistentClient {
+    url: Url,
+    client: AsyncHttpsClient,
+}
+
+impl AsyncPersistentClient {
+    pub async fn new(a: &str, url: &str) -> HttpResult<Self> {
+        Self::with_connector(a, url, Connector::from_env()).await
+    }
+
+    // Connects with `connector` instead of the environment's proxy settings
+    pub async fn with_connector(a: &str, url: &str, connector: Connector) -> HttpResult<Self> {
+        let url = Url::new(url)?;
+        let mut client = AsyncHttpsClient::new(a, None)?;
+        client.set_connector(connector);
+
+        // connect right away, so a bad host shows up here
+        let timeouts = client.timeouts.started();
+        let conn = client
+            .conns
+            .checkout(&url, &timeouts)
+            .await
+            .map_err(HttpError::connect)?;
+        client.conns.idle.insert(
+            PoolKey::from_url(&url, &client.conns.connector),
+            IdleConnection {
+                conn,
+                since: Instant::now(),
+            },
+        );
+        Ok(Self { url, client })
+    }
+
+    pub fn default_headers(&mut self, headers: &HeaderMap) {
+        self.client.default_headers(headers);
+    }
+
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.client.set_redirect_policy(policy);
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.client.set_retry_policy(policy);
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.client.set_timeouts(timeouts);
+    }
+
+    pub fn set_connector(&mut self, connector: Connector) {
+        self.client.set_connector(connector);
+    }
+
+    // The host this client keeps a connection to
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn get<'a>(&mut self, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
+        self.client.request(Methods::GET, url)
+    }
+
+    pub fn post<'a>(&mut self, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
+        self.client.request(Methods::POST, url)
+    }
+
+    // Like `AsyncHttpsClient::send`, streamed bodies can't be sent
+    pub async fn send(&mut self, req: RequestBuilder<'_>) -> HttpResult<Response> {
+        self.client.send(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::error::RequestError;
+    use crate::https::multipart::{Multipart, Part};
+    use crate::https::request::read_request_head;
+    use crate::https::server::{Router, Server, ServerHandle, ServerResponse};
+    use std::io::{BufReader as StdBufReader, Cursor, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    // Answers with the client's port, so a reused connection shows up as the same port
+    fn server() -> (ServerHandle, String) {
+        let flaky = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .get("/peer", |req| {
+                ServerResponse::text(200, &req.peer.port().to_string())
+            })
+            .get("/redirect", |_| {
+                ServerResponse::new(302)
+                    .header("Location", "/peer")
+                    .unwrap()
+            })
+            .get("/flaky", move |_| {
+                match flaky.fetch_add(1, Ordering::SeqCst) {
+                    0 => ServerResponse::text(503, "busy"),
+                    _ => ServerResponse::text(200, "ok"),
+                }
+            })
+            .post("/echo", |req| ServerResponse::ok().body(req.body.clone()));
+        let server = Server::bind("127.0.0.1:0", router)
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let base = format!("http://{}", server.local_addr());
+        (server, base)
+    }
+
+    fn client() -> AsyncHttpsClient {
+        let mut client = AsyncHttpsClient::new("test", None).unwrap();
+        client.set_connector(Connector::new());
+        client.set_retry_policy(RetryPolicy::none());
+        client
+    }
+
+    fn text(resp: &Response) -> &str {
+        std::str::from_utf8(&resp.content).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reuses_connections() {
+        let (_server, base) = server();
+        let mut client = client();
+        let first = client.get(&format!("{}/peer", base), None).await.unwrap();
+        let second = client.get(&format!("{}/peer", base), None).await.unwrap();
+        assert_eq!(text(&first), text(&second));
+
+        let resp = client
+            .post(&format!("{}/echo", base), b"body".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(text(&resp), "body");
+        let third = client.get(&format!("{}/peer", base), None).await.unwrap();
+        assert_eq!(text(&first), text(&third));
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let (_server, base) = server();
+        let mut client = client();
+        let resp = client
+            .get(&format!("{}/redirect", base), None)
+            .await
+            .unwrap();
+        assert_eq!(resp.status_code, 200);
+        assert!(text(&resp).parse::<u16>().is_ok());
+
+        client.set_redirect_policy(RedirectPolicy::none());
+        let resp = client
+            .get(&format!("{}/redirect", base), None)
+            .await
+            .unwrap();
+        assert_eq!(resp.status_code, 302);
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let (_server, base) = server();
+        let mut client = client();
+        client.set_retry_policy(
+            RetryPolicy::new()
+                .max_attempts(2)
+                .backoff(Duration::from_millis(1), Duration::from_millis(10)),
+        );
+        let resp = client.get(&format!("{}/flaky", base), None).await.unwrap();
+        assert_eq!((resp.status_code, text(&resp)), (200, "ok"));
+    }
+
+    const HI: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+
+    // Answers one request, then drops the connection when the next one comes.
+    // A second connection gets an answer too.
+    fn dropping_server() -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (first, _) = listener.accept().unwrap();
+            let mut w = first.try_clone().unwrap();
+            let mut r = StdBufReader::new(first);
+            read_request_head(&mut r).unwrap();
+            w.write_all(HI).unwrap();
+            read_request_head(&mut r).unwrap();
+            drop((r, w));
+
+            let (mut second, _) = listener.accept().unwrap();
+            read_request_head(&mut StdBufReader::new(second.try_clone().unwrap())).unwrap();
+            second.write_all(HI).unwrap();
+        });
+        (base, handle)
+    }
+
+    #[tokio::test]
+    async fn resends_on_a_dead_connection() {
+        let (base, server) = dropping_server();
+        let mut client = client();
+        for _ in 0..2 {
+            let resp = client.get(&format!("{}/", base), None).await.unwrap();
+            assert_eq!(text(&resp), "hi");
+        }
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn posts_arent_resent() {
+        let (base, _server) = dropping_server();
+        let mut client = client();
+        client.get(&format!("{}/", base), None).await.unwrap();
+        let err = client
+            .post(&format!("{}/", base), b"once".to_vec(), None)
+            .await
+            .unwrap_err();
+        assert!(err.is_dead_connection());
+    }
+
+    #[tokio::test]
+    async fn persistent_client() {
+        let (_server, base) = server();
+        let mut client = AsyncPersistentClient::with_connector("test", &base, Connector::new())
+            .await
+            .unwrap();
+        assert_eq!(client.url().to_string(), format!("{}/", base));
+        let req = client.get(&format!("{}/peer", base)).unwrap();
+        let first = req.execute_async(&mut client).await.unwrap();
+        let req = client.get(&format!("{}/peer", base)).unwrap();
+        let second = client.send(req).await.unwrap();
+        assert_eq!(text(&first), text(&second));
+
+        // nothing listens there anymore
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let res = AsyncPersistentClient::with_connector(
+            "test",
+            &format!("http://{}", closed),
+            Connector::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(HttpError::Connect(_))));
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_refused() {
+        let (_server, base) = server();
+        let mut client = client();
+        let url = format!("{}/echo", base);
+        let req = client
+            .request(Methods::POST, &url)
+            .unwrap()
+            .body_reader(Cursor::new(b"streamed".to_vec()));
+        let err = client.send(req).await.unwrap_err();
+        assert!(matches!(err, HttpError::Request(RequestError::Body(_))));
+
+        let form = Multipart::new().part(Part::reader("file", Cursor::new(b"x".to_vec())));
+        let req = client
+            .request(Methods::POST, &url)
+            .unwrap()
+            .multipart(&form);
+        let err = client.send(req).await.unwrap_err();
+        assert!(matches!(err, HttpError::Request(RequestError::Body(_))));
+    }
+}
@@ -0,0 +1,258 @@
+use super::client::Methods;
+use super::encoding::{self, Decoder};
+use super::error::{HttpError, HttpResult, ProtocolError};
+use super::headers::HeaderMap;
+use super::pool::PooledConnection;
+use super::response::{read_chunk_length, read_line, Framing, Head, Response};
+use bytes::Bytes;
+use log::{debug, warn};
+use std::io;
+use std::io::{ErrorKind, Read};
+
+// Where in the body the connection is
+enum State {
+    // bytes left
+    Length(usize),
+    // bytes left in the current chunk, 0 when the next chunk size comes
+    Chunk(usize),
+    UntilClose,
+    Done,
+}
+
+// The body as it comes off the connection, with the framing removed.
+// When the end is reached the connection goes back to the pool,
+// if the server allows it, otherwise it's closed.
+struct Framed {
+    conn: Option<PooledConnection>,
+    state: State,
+    keep_alive: bool,
+}
+
+impl Framed {
+    fn finish(&mut self) {
+        self.state = State::Done;
+        if let Some(conn) = self.conn.take() {
+            if self.keep_alive {
+                debug!("Body fully read, connection goes back to the pool");
+                conn.release();
+            }
+        }
+    }
+
+    fn read_chunk_head(conn: &mut PooledConnection) -> HttpResult<usize> {
+        let len = read_chunk_length(&read_line(&mut **conn)?)?;
+        if len == 0 {
+            // trailers, which we don't use
+            while !read_line(&mut **conn)?.is_empty() {}
+        }
+        Ok(len)
+    }
+}
+
+impl Read for Framed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let conn = match &mut self.conn {
+                Some(c) => c,
+                None => return Ok(0),
+            };
+            match self.state {
+                State::Length(0) => {
+                    self.finish();
+                    return Ok(0);
+                }
+                State::Length(left) => {
+                    let max = buf.len().min(left);
+                    let n = conn.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::from(ErrorKind::UnexpectedEof));
+                    }
+                    self.state = State::Length(left - n);
+                    if left == n {
+                        self.finish();
+                    }
+                    return Ok(n);
+                }
+                State::Chunk(0) => match Self::read_chunk_head(conn)? {
+                    0 => {
+                        self.finish();
+                        return Ok(0);
+                    }
+                    len => self.state = State::Chunk(len),
+                },
+                State::Chunk(left) => {
+                    let max = buf.len().min(left);
+                    let n = conn.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::from(ErrorKind::UnexpectedEof));
+                    }
+                    // every chunk ends with CRLF
+                    if left == n && !read_line(&mut **conn)?.is_empty() {
+                        return Err(HttpError::from(ProtocolError::InvalidChunk).into());
+                    }
+                    self.state = State::Chunk(left - n);
+                    return Ok(n);
+                }
+                State::UntilClose => {
+                    let n = conn.read(buf)?;
+                    if n == 0 {
+                        self.finish();
+                    }
+                    return Ok(n);
+                }
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+// A response body that is decoded while it's read.
+// Dropping it before the end closes the connection instead of reusing it.
+pub struct BodyReader {
+    decoder: Decoder<Framed>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decoder.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // compressed data can end before its framing does,
+            // whatever is left is skipped to get to the end of the message
+            io::copy(self.decoder.get_mut(), &mut io::sink())?;
+        }
+        Ok(n)
+    }
+}
+
+// A response whose status and headers are read, but whose body is still
+// on the connection. Reading it undoes chunked and compressed encodings
+// on the fly, so large downloads don't have to fit in memory.
+pub struct StreamingResponse {
+    pub status_code: u16,
+    pub headers: HeaderMap,
+    body: BodyReader,
+    keep_alive: bool,
+}
+
+impl StreamingResponse {
+    // Reads the status line and headers from the connection, which is then
+    // owned by the body until it has been read to the end
+    pub fn read_from(mut conn: PooledConnection, method: &Methods) -> HttpResult<Self> {
+        let head = Response::read_final_head(&mut *conn)?;
+        Self::from_head(conn, method, head, true)
+    }
+
+    // A response whose head was already read from the connection.
+    // `reusable` is false when the connection can't be used again,
+    // whatever the server says.
+    pub(crate) fn from_head(
+        conn: PooledConnection,
+        method: &Methods,
+        (version, status_code, mut headers): Head,
+        reusable: bool,
+    ) -> HttpResult<Self> {
+        let keep_alive =
+            reusable && Response::wants_keep_alive(&version, method, status_code, &headers);
+        let (framing, codings) = Response::framing(method, status_code, &headers)?;
+        let empty = matches!(framing, Framing::Empty | Framing::Length(0));
+        let state = match framing {
+            Framing::Empty => State::Length(0),
+            Framing::Length(len) => State::Length(len),
+            Framing::Chunked => State::Chunk(0),
+            Framing::UntilClose => State::UntilClose,
+        };
+        let mut framed = Framed {
+            conn: Some(conn),
+            state,
+            keep_alive,
+        };
+        if empty {
+            framed.finish();
+        }
+
+        let mut decoder = Decoder::with_codings(framed, &codings).map_err(ProtocolError::Decode)?;
+
+        // same as for buffered responses, the headers describe the decoded content
+        let content_codings = headers.content_encoding();
+        if !content_codings.is_empty() && !empty {
+            match content_codings.iter().find(|c| !encoding::is_supported(c)) {
+                Some(c) => warn!(
+                    "Unsupported Content-Encoding {}, leaving the content as is",
+                    c
+                ),
+                None => {
+                    decoder = content_codings
+                        .iter()
+                        .rev()
+                        .try_fold(decoder, |d, c| d.wrap(c))
+                        .map_err(ProtocolError::Decode)?;
+                    headers.remove("Content-Encoding");
+                    headers.remove("Content-Length");
+                }
+            }
+        }
+
+        Ok(Self {
+            status_code,
+            headers,
+            body: BodyReader { decoder },
+            keep_alive,
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status_code)
+    }
+
+    // If the connection goes back to the pool once the body is read
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    // The status and headers, without any content
+    pub fn head(&self) -> Response {
+        Response::new(
+            self.status_code,
+            self.headers.clone(),
+            Bytes::new(),
+            self.keep_alive,
+        )
+    }
+
+    pub fn body(&mut self) -> &mut BodyReader {
+        &mut self.body
+    }
+
+    pub fn into_body(self) -> BodyReader {
+        self.body
+    }
+
+    // Reads the rest of the body into memory
+    pub fn into_response(mut self) -> HttpResult<Response> {
+        let mut content = Vec::new();
+        self.body.read_to_end(&mut content)?;
+        Ok(Response::new(
+            self.status_code,
+            self.headers,
+            Bytes::from(content),
+            self.keep_alive,
+        ))
+    }
+
+    // Skips the body, so the connection can be reused.
+    // A failure only means that it's closed instead.
+    pub fn discard(mut self) {
+        if let Err(e) = io::copy(&mut self.body, &mut io::sink()) {
+            debug!("Couldn't skip the body: {}", e);
+        }
+    }
+}
+
+impl Read for StreamingResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
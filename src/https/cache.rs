@@ -0,0 +1,859 @@
+use super::client::Methods;
+use super::date::parse_http_date;
+use super::error::HttpResult;
+use super::headers::HeaderMap;
+use super::middleware::{Middleware, Next};
+use super::request::RequestBuilder;
+use super::response::Response;
+use super::util::lock_unpoisoned;
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Statuses which may be stored without being told so (RFC 9110 15.1)
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+// heuristic freshness never lasts longer than this
+const MAX_HEURISTIC: Duration = Duration::from_secs(24 * 3600);
+// headers of a 304 which say nothing about the stored response
+const NOT_REFRESHED: [&str; 4] = [
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "set-cookie",
+];
+
+// A stored response, with what's needed to tell if it's still fresh
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    // the disk store keeps it next to the rest
+    #[serde(skip)]
+    pub body: Vec<u8>,
+    // when the response was received
+    pub stored_at: SystemTime,
+    // the request headers named by Vary, as they were sent
+    pub vary: Vec<(String, Option<String>)>,
+}
+
+impl CachedResponse {
+    fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (k, v) in &self.headers {
+            // they were valid when they were stored
+            let _ = headers.append(k, v);
+        }
+        headers
+    }
+
+    // Roughly what it takes in memory or on disk
+    pub fn size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+        self.body.len() + headers
+    }
+
+    fn age(&self, headers: &HeaderMap) -> Duration {
+        let initial = headers
+            .get("Age")
+            .and_then(|a| a.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let resident = SystemTime::now()
+            .duration_since(self.stored_at)
+            .unwrap_or_default();
+        // a huge Age is just very old
+        initial.saturating_add(resident)
+    }
+
+    // max-age, then Expires, then 10% of the time since Last-Modified
+    fn freshness_lifetime(&self, headers: &HeaderMap) -> Duration {
+        let cc = cache_control(headers);
+        if let Some(secs) = directive(&cc, "max-age").and_then(|v| v.parse().ok()) {
+            return Duration::from_secs(secs);
+        }
+        let date = headers
+            .get("Date")
+            .and_then(parse_http_date)
+            .unwrap_or(self.stored_at);
+        if let Some(expires) = headers.get("Expires") {
+            // invalid dates mean already expired
+            return parse_http_date(expires)
+                .and_then(|e| e.duration_since(date).ok())
+                .unwrap_or_default();
+        }
+        match headers.get("Last-Modified").and_then(parse_http_date) {
+            Some(modified) => {
+                (date.duration_since(modified).unwrap_or_default() / 10).min(MAX_HEURISTIC)
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        !has_directive(&cache_control(headers), "no-cache")
+            && self.age(headers) < self.freshness_lifetime(headers)
+    }
+
+    fn into_response(self) -> Response {
+        let mut headers = self.header_map();
+        let age = self.age(&headers).as_secs().to_string();
+        // the value only has digits
+        let _ = headers.insert("Age", &age);
+        Response::new(self.status, headers, Bytes::from(self.body), true)
+    }
+}
+
+// Where the cache keeps responses. Stores decide themselves what to evict.
+pub trait CacheStore: Debug + Send + Sync {
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+
+    fn put(&self, key: &str, entry: CachedResponse);
+
+    fn remove(&self, key: &str);
+}
+
+#[derive(Debug, Default)]
+struct MemoryEntries {
+    entries: HashMap<String, (CachedResponse, u64)>,
+    size: usize,
+    // increases with every use, the entry with the lowest was used longest ago
+    clock: u64,
+}
+
+// Keeps responses in memory, dropping the least recently used ones
+// when they take more than `max_bytes`. Clones share the entries.
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    max_bytes: usize,
+    inner: Arc<Mutex<MemoryEntries>>,
+}
+
+impl MemoryStore {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            inner: Arc::new(Mutex::new(MemoryEntries::default())),
+        }
+    }
+
+    // Bytes taken by the stored responses
+    pub fn size(&self) -> usize {
+        lock_unpoisoned(&self.inner).size
+    }
+
+    pub fn len(&self) -> usize {
+        lock_unpoisoned(&self.inner).entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut inner = lock_unpoisoned(&self.inner);
+        inner.clock += 1;
+        let clock = inner.clock;
+        let (entry, used) = inner.entries.get_mut(key)?;
+        *used = clock;
+        Some(entry.clone())
+    }
+
+    fn put(&self, key: &str, entry: CachedResponse) {
+        let size = entry.size();
+        if size > self.max_bytes {
+            debug!("Response for {} is too large to cache", key);
+            return;
+        }
+        let mut inner = lock_unpoisoned(&self.inner);
+        if let Some((old, _)) = inner.entries.remove(key) {
+            inner.size -= old.size();
+        }
+        while inner.size + size > self.max_bytes {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| k.clone());
+            match oldest.and_then(|k| inner.entries.remove(&k)) {
+                Some((old, _)) => inner.size -= old.size(),
+                None => break,
+            }
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        inner.size += size;
+        inner.entries.insert(key.to_string(), (entry, clock));
+    }
+
+    fn remove(&self, key: &str) {
+        let mut inner = lock_unpoisoned(&self.inner);
+        if let Some((old, _)) = inner.entries.remove(key) {
+            inner.size -= old.size();
+        }
+    }
+}
+
+// What a disk entry starts with, the body follows after a newline
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    key: String,
+    response: CachedResponse,
+}
+
+// Keeps responses in files under `dir`, one per request, removing the ones
+// used longest ago once they take more than `max_bytes` together.
+// Entries survive restarts, so several runs of the bot share them.
+#[derive(Debug, Clone)]
+pub struct DiskStore {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl DiskStore {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_bytes })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.entry", fnv1a(key)))
+    }
+
+    fn read(&self, key: &str) -> Result<CachedResponse, Error> {
+        let file = fs::File::open(self.path(key))?;
+        // reading it counts as using it
+        file.set_modified(SystemTime::now())?;
+        let mut r = BufReader::new(file);
+        let mut meta = String::new();
+        r.read_line(&mut meta)?;
+        let entry: DiskEntry =
+            serde_json::from_str(&meta).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        // another key with the same hash
+        if entry.key != key {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+        let mut response = entry.response;
+        r.read_to_end(&mut response.body)?;
+        Ok(response)
+    }
+
+    fn write(&self, key: &str, entry: &CachedResponse) -> Result<(), Error> {
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        let meta = DiskEntry {
+            key: key.to_string(),
+            response: entry.clone(),
+        };
+        let mut f = fs::File::create(&tmp)?;
+        serde_json::to_writer(&mut f, &meta).map_err(Error::other)?;
+        f.write_all(b"\n")?;
+        f.write_all(&entry.body)?;
+        // readers never see half an entry
+        fs::rename(tmp, path)
+    }
+
+    fn evict(&self) -> Result<(), Error> {
+        let mut files = Vec::new();
+        for e in fs::read_dir(&self.dir)? {
+            let e = e?;
+            if e.path().extension().is_some_and(|x| x == "entry") {
+                let meta = e.metadata()?;
+                files.push((meta.modified()?, meta.len(), e.path()));
+            }
+        }
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+impl CacheStore for DiskStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        match self.read(key) {
+            Ok(r) => Some(r),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Dropping unreadable cache entry for {}: {}", key, e);
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &str, entry: CachedResponse) {
+        if entry.size() as u64 > self.max_bytes {
+            debug!("Response for {} is too large to cache", key);
+            return;
+        }
+        if let Err(e) = self.write(key, &entry).and_then(|_| self.evict()) {
+            warn!("Couldn't write cache entry for {}: {}", key, e);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        if let Err(e) = fs::remove_file(self.path(key)) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Couldn't remove cache entry for {}: {}", key, e);
+            }
+        }
+    }
+}
+
+// Answers GET requests from its store while the responses are fresh
+// (Cache-Control max-age, Expires, or a guess from Last-Modified), and
+// revalidates stale ones with If-None-Match / If-Modified-Since, so an
+// unchanged resource only costs a 304. It's a private cache, responses to
+// requests with credentials are kept apart per Authorization header.
+// Successful POST, PUT, PATCH and DELETE requests drop the entry of their url.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    store: Arc<dyn CacheStore>,
+}
+
+impl Cache {
+    pub fn new(store: impl CacheStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+        }
+    }
+
+    pub fn memory(max_bytes: usize) -> Self {
+        Self::new(MemoryStore::new(max_bytes))
+    }
+
+    pub fn disk(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, Error> {
+        Ok(Self::new(DiskStore::new(dir, max_bytes)?))
+    }
+
+    fn key(req: &RequestBuilder) -> String {
+        let mut key = format!("{}", req.url());
+        if let Some(auth) = req.header_map().get("Authorization") {
+            // the credentials themselves aren't kept
+            key.push_str(&format!(" auth:{:016x}", fnv1a(auth)));
+        }
+        key
+    }
+
+    // If the request sends the same values for the headers the response varies on
+    fn vary_matches(entry: &CachedResponse, req: &RequestBuilder) -> bool {
+        entry
+            .vary
+            .iter()
+            .all(|(name, value)| req.header_map().get(name) == value.as_deref())
+    }
+
+    fn storable(req: &RequestBuilder, resp: &Response) -> bool {
+        let cc = cache_control(&resp.headers);
+        if has_directive(&cc, "no-store")
+            || has_directive(&cache_control(req.header_map()), "no-store")
+            || !CACHEABLE_STATUSES.contains(&resp.status_code)
+            || resp.headers.get("Vary") == Some("*")
+        {
+            return false;
+        }
+        // only worth keeping if it can be reused or revalidated
+        has_directive(&cc, "max-age")
+            || resp.headers.contains("Expires")
+            || resp.headers.contains("ETag")
+            || resp.headers.contains("Last-Modified")
+    }
+
+    fn store(&self, key: &str, req: &RequestBuilder, resp: &Response) {
+        if !Self::storable(req, resp) {
+            return;
+        }
+        let vary = resp
+            .headers
+            .get_all("Vary")
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let value = req.header_map().get(name).map(|v| v.to_string());
+                (name.to_string(), value)
+            })
+            .collect();
+        let headers = resp
+            .headers
+            .iter()
+            // cookies are for the one who got them
+            .filter(|(k, _)| !k.eq_ignore_ascii_case("Set-Cookie"))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        debug!("Caching {} {}", resp.status_code, key);
+        self.store.put(
+            key,
+            CachedResponse {
+                status: resp.status_code,
+                headers,
+                body: resp.content.to_vec(),
+                stored_at: SystemTime::now(),
+                vary,
+            },
+        );
+    }
+
+    // The 304's headers replace the stored ones, the body stays
+    fn refresh(&self, key: &str, mut entry: CachedResponse, not_modified: &Response) -> Response {
+        let mut headers = entry.header_map();
+        let updates: Vec<(&str, &str)> = not_modified
+            .headers
+            .iter()
+            .filter(|(k, _)| !NOT_REFRESHED.contains(&k.to_ascii_lowercase().as_str()))
+            .collect();
+        for (k, _) in &updates {
+            headers.remove(k);
+        }
+        for (k, v) in updates {
+            let _ = headers.append(k, v);
+        }
+        entry.headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        entry.stored_at = SystemTime::now();
+        self.store.put(key, entry.clone());
+        entry.into_response()
+    }
+}
+
+impl Middleware for Cache {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+        let key = Self::key(&req);
+        match req.method() {
+            Methods::GET => {}
+            Methods::POST | Methods::PUT | Methods::PATCH | Methods::DELETE => {
+                let resp = next.run(req)?;
+                if resp.is_success() || (300..400).contains(&resp.status_code) {
+                    self.store.remove(&key);
+                }
+                return Ok(resp);
+            }
+            _ => return next.run(req),
+        }
+
+        let req_cc = cache_control(req.header_map());
+        let entry = self.store.get(&key).filter(|e| Self::vary_matches(e, &req));
+        let entry = match entry {
+            Some(e) => e,
+            None => {
+                let resp = next.run(req.clone())?;
+                self.store(&key, &req, &resp);
+                return Ok(resp);
+            }
+        };
+
+        let headers = entry.header_map();
+        let forced = has_directive(&req_cc, "no-cache")
+            || directive(&req_cc, "max-age").is_some_and(|a| a == "0");
+        if !forced && entry.is_fresh(&headers) {
+            debug!("Cache hit for {}", key);
+            return Ok(entry.into_response());
+        }
+
+        // the caller's own validators are theirs to check, a 304 goes back to them
+        if req.header_map().contains("If-None-Match")
+            || req.header_map().contains("If-Modified-Since")
+        {
+            let resp = next.run(req.clone())?;
+            self.store(&key, &req, &resp);
+            return Ok(resp);
+        }
+
+        // stale, ask the server if it changed
+        let mut conditional = req.clone();
+        if let Some(etag) = headers.get("ETag") {
+            conditional = conditional.header(("If-None-Match", etag));
+        }
+        if let Some(modified) = headers.get("Last-Modified") {
+            conditional = conditional.header(("If-Modified-Since", modified));
+        }
+        let resp = next.run(conditional)?;
+        if resp.status_code == 304 {
+            debug!("{} wasn't modified, using the cached response", key);
+            return Ok(self.refresh(&key, entry, &resp));
+        }
+        self.store(&key, &req, &resp);
+        Ok(resp)
+    }
+}
+
+// Cache-Control directives with lowercase names, quotes removed from values
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all("Cache-Control")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((k, v)) => (
+                k.trim().to_ascii_lowercase(),
+                Some(v.trim().trim_matches('"').to_string()),
+            ),
+            None => (d.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn has_directive(cc: &[(String, Option<String>)], name: &str) -> bool {
+    cc.iter().any(|(k, _)| k == name)
+}
+
+fn directive<'a>(cc: &'a [(String, Option<String>)], name: &str) -> Option<&'a str> {
+    cc.iter()
+        .find(|(k, _)| k == name)
+        .and_then(|(_, v)| v.as_deref())
+}
+
+// FNV-1a, so disk entries keep their names between builds
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::date::format_http_date;
+    use crate::https::mock::{Mock, Route};
+    use crate::https::persistent_client::PersistentClient;
+    use crate::https::pool::ConnectionPool;
+    use crate::https::transport::Connector;
+
+    const URL: &str = "https://api.test/thing";
+
+    fn client(mock: &Mock, cache: Cache) -> PersistentClient {
+        let mut client = PersistentClient::with_connector(
+            "test",
+            "https://api.test",
+            ConnectionPool::new(),
+            Connector::new().dialer(mock.clone()),
+        )
+        .unwrap();
+        client.add_middleware(cache);
+        client
+    }
+
+    fn get(client: &mut PersistentClient) -> Response {
+        client.get(URL).unwrap().execute(client).unwrap()
+    }
+
+    fn http_date(offset_secs: i64) -> String {
+        let now = SystemTime::now();
+        let t = if offset_secs < 0 {
+            now - Duration::from_secs(offset_secs.unsigned_abs())
+        } else {
+            now + Duration::from_secs(offset_secs as u64)
+        };
+        format_http_date(t)
+    }
+
+    fn entry(body: &[u8], headers: &[(&str, &str)]) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.to_vec(),
+            stored_at: SystemTime::now(),
+            vary: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn max_age_is_served_from_the_cache() {
+        let mock = Mock::new().route(
+            Route::get(URL)
+                .unwrap()
+                .header("Cache-Control", "max-age=60")
+                .body("cached")
+                .times(1),
+        );
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        assert_eq!(&get(&mut client).content[..], b"cached");
+        let hit = get(&mut client);
+        assert_eq!(&hit.content[..], b"cached");
+        assert_eq!(hit.headers.get("Age"), Some("0"));
+        assert_eq!(mock.requests().len(), 1);
+        assert!(mock.is_done());
+    }
+
+    #[test]
+    fn expires_is_measured_from_date() {
+        let date = http_date(0);
+        let later = http_date(60);
+        let earlier = http_date(-60);
+        let mock = Mock::new()
+            .route(
+                Route::get(URL)
+                    .unwrap()
+                    .header("Date", &date)
+                    .header("Expires", &later)
+                    .times(1),
+            )
+            .route(
+                Route::get("https://api.test/old")
+                    .unwrap()
+                    .header("Date", &date)
+                    .header("Expires", &earlier),
+            );
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        get(&mut client);
+        get(&mut client);
+        assert_eq!(mock.requests().len(), 1);
+
+        // already expired, asked for every time
+        for _ in 0..2 {
+            client
+                .get("https://api.test/old")
+                .unwrap()
+                .execute(&mut client)
+                .unwrap();
+        }
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn last_modified_heuristic() {
+        let headers = HeaderMap::from_pairs(&[
+            ("Date", &http_date(0)),
+            ("Last-Modified", &http_date(-1000)),
+        ])
+        .unwrap();
+        let e = entry(b"", &[]);
+        let lifetime = e.freshness_lifetime(&headers).as_secs();
+        assert!((99..=100).contains(&lifetime), "{}", lifetime);
+
+        // capped at a day
+        let headers = HeaderMap::from_pairs(&[
+            ("Date", &http_date(0)),
+            ("Last-Modified", &http_date(-100 * 24 * 3600)),
+        ])
+        .unwrap();
+        assert_eq!(e.freshness_lifetime(&headers), MAX_HEURISTIC);
+        // max-age wins over everything
+        let headers = HeaderMap::from_pairs(&[
+            ("Cache-Control", "public, max-age=5"),
+            ("Expires", &http_date(600)),
+            ("Last-Modified", &http_date(-1000)),
+        ])
+        .unwrap();
+        assert_eq!(e.freshness_lifetime(&headers), Duration::from_secs(5));
+        // invalid Expires means already expired
+        let headers = HeaderMap::from_pairs(&[("Expires", "0")]).unwrap();
+        assert_eq!(e.freshness_lifetime(&headers), Duration::ZERO);
+    }
+
+    #[test]
+    fn huge_ages_saturate() {
+        let headers = HeaderMap::from_pairs(&[
+            ("Age", "18446744073709551615"),
+            ("Cache-Control", "max-age=60"),
+        ])
+        .unwrap();
+        let e = entry(b"", &[]);
+        assert_eq!(e.age(&headers).as_secs(), u64::MAX);
+        assert!(!e.is_fresh(&headers));
+    }
+
+    #[test]
+    fn revalidates_with_the_etag() {
+        let mock = Mock::new()
+            .route(
+                Route::get(URL)
+                    .unwrap()
+                    .header("ETag", "\"v1\"")
+                    .header("Cache-Control", "max-age=0")
+                    .header("X-Version", "1")
+                    .body("original")
+                    .times(1),
+            )
+            .route(
+                Route::get(URL)
+                    .unwrap()
+                    .status(304)
+                    .header("ETag", "\"v1\"")
+                    .header("Cache-Control", "max-age=60")
+                    .header("X-Version", "2")
+                    .header("Set-Cookie", "session=1")
+                    .times(1),
+            );
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        get(&mut client);
+        let revalidated = get(&mut client);
+        assert_eq!(revalidated.status_code, 200);
+        assert_eq!(&revalidated.content[..], b"original");
+        assert_eq!(revalidated.headers.get("X-Version"), Some("2"));
+        assert_eq!(revalidated.headers.get_all("X-Version").len(), 1);
+        // the 304's framing and cookies aren't taken over
+        assert_eq!(revalidated.headers.get("Content-Length"), Some("8"));
+        assert_eq!(revalidated.headers.get("Set-Cookie"), None);
+
+        let requests = mock.requests();
+        assert_eq!(requests[1].headers.get("If-None-Match"), Some("\"v1\""));
+        // fresh for a minute now
+        assert_eq!(&get(&mut client).content[..], b"original");
+        assert_eq!(mock.requests().len(), 2);
+        assert!(mock.is_done());
+    }
+
+    #[test]
+    fn revalidates_with_last_modified() {
+        let modified = http_date(-10);
+        let mock = Mock::new()
+            .route(
+                Route::get(URL)
+                    .unwrap()
+                    .header("Last-Modified", &modified)
+                    .header("Cache-Control", "no-cache")
+                    .body("original")
+                    .times(1),
+            )
+            .route(Route::get(URL).unwrap().status(304).times(1));
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        get(&mut client);
+        assert_eq!(&get(&mut client).content[..], b"original");
+        let requests = mock.requests();
+        assert_eq!(
+            requests[1].headers.get("If-Modified-Since"),
+            Some(modified.as_str())
+        );
+        assert_eq!(requests[1].headers.get("If-None-Match"), None);
+    }
+
+    #[test]
+    fn callers_conditionals_get_the_304() {
+        let mock = Mock::new()
+            .route(
+                Route::get(URL)
+                    .unwrap()
+                    .header("ETag", "\"v1\"")
+                    .header("Cache-Control", "max-age=0")
+                    .body("original")
+                    .times(1),
+            )
+            .route(Route::get(URL).unwrap().status(304).times(1));
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        get(&mut client);
+        let resp = client
+            .get(URL)
+            .unwrap()
+            .header(("If-None-Match", "\"mine\""))
+            .execute(&mut client)
+            .unwrap();
+        assert_eq!(resp.status_code, 304);
+        assert!(resp.content.is_empty());
+        assert_eq!(
+            mock.requests()[1].headers.get("If-None-Match"),
+            Some("\"mine\"")
+        );
+    }
+
+    #[test]
+    fn vary_keeps_variants_apart() {
+        let mock = Mock::new().route(
+            Route::get(URL)
+                .unwrap()
+                .header("Cache-Control", "max-age=60")
+                .header("Vary", "Accept-Language"),
+        );
+        let mut client = client(&mock, Cache::memory(1 << 20));
+        let mut get_in = |lang: &str| {
+            client
+                .get(URL)
+                .unwrap()
+                .header(("Accept-Language", lang))
+                .execute(&mut client)
+                .unwrap();
+        };
+        get_in("en");
+        get_in("en");
+        assert_eq!(mock.requests().len(), 1);
+        get_in("fr");
+        assert_eq!(mock.requests().len(), 2);
+        assert_eq!(
+            mock.requests()[1].headers.get("Accept-Language"),
+            Some("fr")
+        );
+    }
+
+    #[test]
+    fn memory_store_evicts_the_least_recently_used() {
+        let store = MemoryStore::new(100);
+        let e = entry(&[0; 40], &[]);
+        store.put("a", e.clone());
+        store.put("b", e.clone());
+        assert_eq!(store.size(), 80);
+        // a was used last, so b goes
+        store.get("a").unwrap();
+        store.put("c", e.clone());
+        assert!(store.get("b").is_none());
+        assert!(store.get("a").is_some());
+        assert_eq!((store.len(), store.size()), (2, 80));
+
+        // replacing an entry only counts the new one
+        store.put("a", entry(&[0; 10], &[("ETag", "x")]));
+        assert_eq!(store.size(), 40 + 10 + 5);
+        store.remove("a");
+        assert_eq!((store.len(), store.size()), (1, 40));
+        // too large for the whole store
+        store.put("d", entry(&[0; 101], &[]));
+        assert!(store.get("d").is_none());
+        assert_eq!(store.size(), 40);
+    }
+
+    #[test]
+    fn disk_store_evicts_the_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("bigeon-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let probe = DiskStore::new(&dir, u64::MAX).unwrap();
+        let e = entry(&[b'x'; 100], &[("ETag", "\"1\"")]);
+        probe.put("a", e.clone());
+        let len = fs::metadata(probe.path("a")).unwrap().len();
+
+        // room for two entries
+        let store = DiskStore::new(&dir, len * 5 / 2).unwrap();
+        store.put("b", e.clone());
+        let age = |key: &str, secs: u64| {
+            let f = fs::File::options()
+                .write(true)
+                .open(store.path(key))
+                .unwrap();
+            f.set_modified(SystemTime::now() - Duration::from_secs(secs))
+                .unwrap();
+        };
+        age("a", 20);
+        age("b", 10);
+        // reading a makes b the oldest
+        assert_eq!(store.get("a").unwrap().body, e.body);
+        store.put("c", e.clone());
+        assert!(store.get("b").is_none());
+        assert!(store.get("a").is_some());
+        assert!(store.get("c").is_some());
+
+        store.remove("a");
+        assert!(store.get("a").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,70 @@
-use super::request::RequestBuilder;
+use super::body::StreamingResponse;
+use super::error::HttpResult;
+use super::headers::HeaderMap;
+use super::middleware::Middleware;
+use super::pool::ConnectionPool;
+use super::redirect::RedirectPolicy;
+use super::request::{Message, RequestBuilder};
+use super::response::Response;
+use super::retry::RetryPolicy;
+use super::timeout::Timeouts;
 use crate::https::client::Methods;
-use crate::https::url::UrlError;
+use crate::https::url::{Url, UrlError};
+use std::sync::Arc;
+
 // Defines if a type can be a HTTPS client
-// which is accepted by the `RequestBuilder` as an executor of the `Request`
-pub trait CanBeClient<'a> {
-    fn request(&'a mut self, m: Methods, url: &'a str) -> Result<RequestBuilder<'a>, UrlError>;
+// which is accepted by the `RequestBuilder` as an executor of the `Request`.
+// `RequestBuilder::execute` does the rest: default headers, middleware,
+// redirects and retries. A client only has to send single messages,
+// and can leave the settings it doesn't have at their defaults.
+pub trait CanBeClient {
+    fn request<'a>(&mut self, m: Methods, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
+        Ok(RequestBuilder::new(Url::new(url)?).http_method(m))
+    }
+
+    // Pool the client takes its connections from
+    fn pool(&self) -> &ConnectionPool {
+        ConnectionPool::global()
+    }
+
+    // Added to every request that doesn't set them itself
+    fn headers(&self) -> Option<&HeaderMap> {
+        None
+    }
+
+    fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &[]
+    }
+
+    // Used for requests which don't set their own
+    fn redirect_policy(&self) -> RedirectPolicy {
+        RedirectPolicy::new()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new()
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Timeouts::new()
+    }
+
+    // Sends one request and reads the whole response, without following
+    // redirects or retrying. `url` may be another host than the client's.
+    fn send_message(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response>;
+
+    // Like `send_message`, but only the head of the response is read
+    fn send_message_streaming(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse>;
 }
@@ -0,0 +1,838 @@
+use super::client::Methods;
+use super::error::{HttpResult, ProtocolError};
+use super::headers::HeaderMap;
+use super::middleware::REDACTED;
+use super::mock::{is_incomplete, Mock, Route};
+use super::request::{read_request_body, read_request_head};
+use super::response::{read_chunk_length, Framing, Response, MAX_LINE_LEN};
+use super::timeout::Timeouts;
+use super::transport::{AsyncTransport, Connector, Dialer, Transport};
+use super::url::{encode_query_component, percent_decode, Url};
+use super::util::lock_unpoisoned;
+use super::util::{decode_base64, encode_base64};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Response headers that describe how the recorded body was sent
+const REPLAY_SKIPPED: [&str; 4] = [
+    "content-length",
+    "transfer-encoding",
+    "content-encoding",
+    "connection",
+];
+
+// Exchanges recorded from real servers, to be replayed offline.
+// Stored as JSON, with the bodies decoded and credentials replaced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedMessage,
+    pub response: RecordedMessage,
+}
+
+// Either side of an exchange. Requests have a method and url,
+// responses a status. Bodies that aren't UTF-8 are kept as base64.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub base64: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl RecordedMessage {
+    fn set_body(&mut self, body: Vec<u8>) {
+        match String::from_utf8(body) {
+            Ok(s) => self.body = s,
+            Err(e) => {
+                self.body = encode_base64(e.as_bytes());
+                self.base64 = true;
+            }
+        }
+    }
+
+    pub fn body_bytes(&self) -> Vec<u8> {
+        if !self.base64 {
+            return self.body.clone().into_bytes();
+        }
+        decode_base64(&self.body).unwrap_or_else(|| {
+            warn!("Recorded body isn't valid base64, replaying it as text");
+            self.body.clone().into_bytes()
+        })
+    }
+}
+
+impl Cassette {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let data = serde_json::to_vec_pretty(self).map_err(Error::other)?;
+        fs::write(path, data)
+    }
+
+    // A mock that answers the recorded requests, each one once and in order.
+    // Requests are matched by method, origin and path, the query is ignored
+    // since its secrets were redacted. Recorded bodies are already decoded,
+    // so the headers describing the original framing and coding are left out.
+    pub fn into_mock(self) -> Mock {
+        let mut mock = Mock::new();
+        for i in self.interactions {
+            let req = &i.request;
+            let method = req.method.as_deref().and_then(Methods::from_name);
+            let url = req.url.as_deref().and_then(|u| Url::new(u).ok());
+            let (method, url) = match (method, url) {
+                (Some(m), Some(u)) => (m, u),
+                _ => {
+                    warn!("Skipping a recorded request without a valid method or url");
+                    continue;
+                }
+            };
+            let target = format!("{}://{}{}", url.scheme(), url.host_header(), url.route());
+            let mut route = match Route::new(method, &target) {
+                Ok(r) => r.times(1),
+                Err(e) => {
+                    warn!("Skipping recorded request to {}: {}", target, e);
+                    continue;
+                }
+            };
+            let resp = &i.response;
+            route = route.status(resp.status.unwrap_or(200));
+            for (k, v) in &resp.headers {
+                if !REPLAY_SKIPPED.contains(&k.to_ascii_lowercase().as_str()) {
+                    route = route.header(k, v);
+                }
+            }
+            mock = mock.route(route.body(resp.body_bytes()));
+        }
+        mock
+    }
+}
+
+// Serves the exchanges recorded in the file at `path`.
+// With `Connector::new().dialer(mock)`, nothing goes to the network.
+pub fn replay(path: impl AsRef<Path>) -> Result<Mock, Error> {
+    let path = path.as_ref();
+    let cassette = Cassette::load(path)?;
+    info!(
+        "Replaying {} recorded exchanges from {}",
+        cassette.interactions.len(),
+        path.display()
+    );
+    Ok(cassette.into_mock())
+}
+
+// Opens real connections with its connector and records every exchange
+// made over them to a cassette file, which is rewritten after each one.
+// Only the blocking clients can be recorded.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    connector: Connector,
+    headers: Vec<String>,
+    fields: Vec<String>,
+    params: Vec<String>,
+    cassette: Arc<Mutex<Cassette>>,
+}
+
+impl Recorder {
+    // Hides the Authorization and cookie headers, JSON fields ending in
+    // "token" or named like a secret, and secret form and query parameters
+    pub fn new(path: impl Into<PathBuf>, connector: Connector) -> Self {
+        let headers = [
+            "Authorization",
+            "Proxy-Authorization",
+            "Cookie",
+            "Set-Cookie",
+        ];
+        let fields = ["client_secret", "password", "rpsticket"];
+        let params = ["client_secret", "code", "password"];
+        Self {
+            path: path.into(),
+            connector,
+            headers: headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+            cassette: Arc::new(Mutex::new(Cassette::default())),
+        }
+    }
+
+    // Also hide this header's value
+    pub fn redact_header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_ascii_lowercase());
+        self
+    }
+
+    // Also hide this JSON field's value, wherever it appears
+    pub fn redact_field(mut self, name: &str) -> Self {
+        self.fields.push(name.to_ascii_lowercase());
+        self
+    }
+
+    // Also hide this form or query parameter's value
+    pub fn redact_param(mut self, name: &str) -> Self {
+        self.params.push(name.to_ascii_lowercase());
+        self
+    }
+
+    // Everything recorded so far
+    pub fn cassette(&self) -> Cassette {
+        lock_unpoisoned(&self.cassette).clone()
+    }
+
+    fn add(&self, interaction: Interaction) {
+        let mut cassette = lock_unpoisoned(&self.cassette);
+        cassette.interactions.push(interaction);
+        if let Err(e) = cassette.save(&self.path) {
+            warn!("Couldn't write cassette {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn is_secret_field(&self, key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        key.ends_with("token") || key.ends_with("tokens") || self.fields.contains(&key)
+    }
+
+    fn is_secret_param(&self, key: &str) -> bool {
+        self.is_secret_field(key) || self.params.contains(&key.to_ascii_lowercase())
+    }
+
+    fn redact_headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(
+                |(k, v)| match self.headers.contains(&k.to_ascii_lowercase()) {
+                    true => (k.to_string(), REDACTED.to_string()),
+                    false => (k.to_string(), v.to_string()),
+                },
+            )
+            .collect()
+    }
+
+    // The placeholder is encoded, so the result still parses as a query
+    fn redact_query(&self, query: &str) -> String {
+        query
+            .split('&')
+            .map(|p| match p.split_once('=') {
+                Some((k, _)) if self.is_secret_param(&percent_decode(k).unwrap_or_default()) => {
+                    format!("{}={}", k, encode_query_component(REDACTED))
+                }
+                _ => p.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    fn redact_url(&self, url: &Url) -> String {
+        let s = format!("{}://{}{}", url.scheme(), url.host_header(), url.route());
+        match url.query() {
+            "" => s,
+            q => format!("{}?{}", s, self.redact_query(q)),
+        }
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map.iter_mut() {
+                    if self.is_secret_field(k) {
+                        hide(v);
+                    } else {
+                        self.redact_json(v);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_json(v)),
+            _ => {}
+        }
+    }
+
+    // JSON and form bodies have their secrets replaced, others are kept as they are
+    fn redact_body(&self, headers: &HeaderMap, body: Vec<u8>) -> Vec<u8> {
+        let content_type = headers.content_type().unwrap_or_default();
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            return match std::str::from_utf8(&body) {
+                Ok(form) => self.redact_query(form).into_bytes(),
+                Err(_) => body,
+            };
+        }
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(mut value) if value.is_object() || value.is_array() => {
+                self.redact_json(&mut value);
+                value.to_string().into_bytes()
+            }
+            _ => body,
+        }
+    }
+}
+
+// Replaces every string in the value, keeping its shape
+fn hide(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = REDACTED.to_string(),
+        Value::Array(values) => values.iter_mut().for_each(hide),
+        Value::Object(map) => map.values_mut().for_each(hide),
+        _ => {}
+    }
+}
+
+impl Dialer for Recorder {
+    fn dial(&self, url: &Url, timeouts: &Timeouts) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(RecordingStream {
+            inner: self.connector.connect(url, timeouts)?,
+            recorder: self.clone(),
+            url: url.clone(),
+            sent: Vec::new(),
+            received: Vec::new(),
+            progress: NOTHING_FRAMED,
+        }))
+    }
+
+    fn dial_async(&self, url: &Url) -> Result<Box<dyn AsyncTransport>, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("can't record async connections to {}", url.socket_addr()),
+        ))
+    }
+
+    fn name(&self) -> String {
+        format!("recorder:{}", self.path.display())
+    }
+}
+
+// How far the response being received has been framed. Offsets are into
+// `received`, so every read only looks at the bytes it added.
+#[derive(Debug, Clone, Copy)]
+enum Progress {
+    // looking for the end of a head that starts at `start`
+    Head { start: usize, scanned: usize },
+    // the response ends here
+    Until(usize),
+    // a chunk size line starts here
+    Chunk(usize),
+    // a trailer line, or the empty line ending the trailers, starts here
+    Trailer(usize),
+    // the response ends when the connection is closed
+    UntilClose,
+}
+
+const NOTHING_FRAMED: Progress = Progress::Head {
+    start: 0,
+    scanned: 0,
+};
+
+// The line starting at `pos` without its terminator, and where the next
+// one starts. None while it isn't complete.
+fn line_at(buf: &[u8], pos: usize) -> HttpResult<Option<(&[u8], usize)>> {
+    let rest = buf.get(pos..).unwrap_or_default();
+    match rest.iter().position(|b| *b == b'\n') {
+        Some(i) => {
+            let line = &rest[..i];
+            Ok(Some((
+                line.strip_suffix(b"\r").unwrap_or(line),
+                pos + i + 1,
+            )))
+        }
+        None if rest.len() > MAX_LINE_LEN => Err(ProtocolError::LineTooLong.into()),
+        None => Ok(None),
+    }
+}
+
+// A real connection that keeps a copy of what goes over it,
+// until the exchange is complete and can be recorded
+struct RecordingStream {
+    inner: Box<dyn Transport>,
+    recorder: Recorder,
+    url: Url,
+    sent: Vec<u8>,
+    received: Vec<u8>,
+    progress: Progress,
+}
+
+impl RecordingStream {
+    // If the response in `received` is complete, following its framing
+    // as far as the bytes go. Only the head is parsed here.
+    fn framed(&mut self) -> HttpResult<bool> {
+        loop {
+            self.progress = match self.progress {
+                Progress::Head { start, scanned } => match line_at(&self.received, scanned)? {
+                    // empty lines before the status line are skipped
+                    Some(([], next)) if scanned == start => Progress::Head {
+                        start: next,
+                        scanned: next,
+                    },
+                    Some(([], end)) => self.head_framing(start, end)?,
+                    Some((_, next)) => Progress::Head {
+                        start,
+                        scanned: next,
+                    },
+                    None => return Ok(false),
+                },
+                Progress::Until(end) => return Ok(self.received.len() >= end),
+                Progress::Chunk(pos) => match line_at(&self.received, pos)? {
+                    Some((line, next)) => match read_chunk_length(line)? {
+                        0 => Progress::Trailer(next),
+                        // the data and its CRLF
+                        len => match next.checked_add(len).and_then(|e| e.checked_add(2)) {
+                            Some(end) if self.received.len() >= end => Progress::Chunk(end),
+                            Some(_) => return Ok(false),
+                            None => return Err(ProtocolError::InvalidChunk.into()),
+                        },
+                    },
+                    None => return Ok(false),
+                },
+                Progress::Trailer(pos) => match line_at(&self.received, pos)? {
+                    Some(([], next)) => Progress::Until(next),
+                    Some((_, next)) => Progress::Trailer(next),
+                    None => return Ok(false),
+                },
+                Progress::UntilClose => return Ok(false),
+            };
+        }
+    }
+
+    // What follows the head in received[start..end]
+    fn head_framing(&self, start: usize, end: usize) -> HttpResult<Progress> {
+        let (_, status, headers) = Response::read_head(&mut &self.received[start..end])?;
+        // interim responses, the final one follows
+        if (100..200).contains(&status) && status != 101 {
+            return Ok(Progress::Head {
+                start: end,
+                scanned: end,
+            });
+        }
+        let (method, _, _) = read_request_head(&mut &self.sent[..])?;
+        Ok(match Response::framing(&method, status, &headers)?.0 {
+            Framing::Empty => Progress::Until(end),
+            Framing::Length(n) => Progress::Until(end.saturating_add(n)),
+            Framing::Chunked => Progress::Chunk(end),
+            Framing::UntilClose => Progress::UntilClose,
+        })
+    }
+
+    // Records every response that has been received completely
+    fn record_framed(&mut self) {
+        while !self.received.is_empty() {
+            match self.framed() {
+                Ok(true) => self.record(true),
+                Ok(false) => return,
+                Err(e) => return self.give_up(&e),
+            }
+        }
+    }
+
+    // Records the exchange at the start of the buffers.
+    // `complete` when nothing more of its response will be received.
+    fn record(&mut self, complete: bool) {
+        if self.received.is_empty() {
+            return;
+        }
+        let mut req = &self.sent[..];
+        let (method, target, headers) = match read_request_head(&mut req) {
+            Ok(head) => head,
+            Err(e) if is_incomplete(&e) && !complete => return,
+            Err(e) => return self.give_up(&e),
+        };
+        let body = match read_request_body(&mut req, &headers, usize::MAX) {
+            Ok(body) => body,
+            Err(e) if is_incomplete(&e) && !complete => return,
+            // the server answered before the whole body was sent
+            Err(_) => Vec::new(),
+        };
+        let mut resp = &self.received[..];
+        let response = match Response::read_from(&mut resp, &method) {
+            Ok(r) if r.keep_alive() || complete => r,
+            // the body only ends when the connection is closed
+            Ok(_) => return,
+            Err(e) if is_incomplete(&e) && !complete => return,
+            Err(e) => return self.give_up(&e),
+        };
+        let sent = self.sent.len() - req.len();
+        let received = self.received.len() - resp.len();
+        self.sent.drain(..sent);
+        self.received.drain(..received);
+        self.progress = NOTHING_FRAMED;
+        let host = match headers.get("Host") {
+            Some(h) => h.to_string(),
+            None => self.url.host_header(),
+        };
+        let url = match Url::new(&format!("{}://{}{}", self.url.scheme(), host, target)) {
+            Ok(u) => u,
+            Err(e) => return self.give_up(&e),
+        };
+        debug!("Recording {:?} {} -> {}", method, url, response.status_code);
+
+        let rec = &self.recorder;
+        let mut request = RecordedMessage {
+            method: Some(method.as_str().to_string()),
+            url: Some(rec.redact_url(&url)),
+            headers: rec.redact_headers(&headers),
+            ..Default::default()
+        };
+        request.set_body(rec.redact_body(&headers, body));
+        let mut recorded = RecordedMessage {
+            status: Some(response.status_code),
+            headers: rec.redact_headers(&response.headers),
+            ..Default::default()
+        };
+        recorded.set_body(rec.redact_body(&response.headers, response.content.to_vec()));
+        rec.add(Interaction {
+            request,
+            response: recorded,
+        });
+    }
+
+    fn give_up(&mut self, e: &dyn std::fmt::Display) {
+        warn!("Couldn't record an exchange with {}: {}", self.url, e);
+        self.sent.clear();
+        self.received.clear();
+        self.progress = NOTHING_FRAMED;
+    }
+}
+
+impl Read for RecordingStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.received.extend_from_slice(&buf[..n]);
+        if n == 0 && !buf.is_empty() {
+            self.record(true);
+        } else {
+            self.record_framed();
+        }
+        Ok(n)
+    }
+}
+
+impl Write for RecordingStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = self.inner.write(buf)?;
+        self.sent.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for RecordingStream {
+    fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.inner.set_timeouts(timeouts);
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+impl Drop for RecordingStream {
+    fn drop(&mut self) {
+        self.record(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::client::HttpsClient;
+    use crate::https::request::RequestBuilder;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "bigeon-cassette-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    // A server that has already written its answers, handed out a few bytes at a time
+    struct Canned(Cursor<Vec<u8>>);
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let n = buf.len().min(3);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Canned {
+        fn set_timeouts(&mut self, _: &Timeouts) {}
+
+        fn is_closed(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn canned(name: &str, answers: &str) -> (Recorder, RecordingStream) {
+        let recorder = Recorder::new(temp_path(name), Connector::new());
+        let stream = RecordingStream {
+            inner: Box::new(Canned(Cursor::new(answers.as_bytes().to_vec()))),
+            recorder: recorder.clone(),
+            url: Url::new("https://example.com").unwrap(),
+            sent: Vec::new(),
+            received: Vec::new(),
+            progress: NOTHING_FRAMED,
+        };
+        (recorder, stream)
+    }
+
+    fn read_exactly(stream: &mut RecordingStream, len: usize) {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+    }
+
+    #[test]
+    fn records_each_response_once_it_is_framed() {
+        let chunked = "HTTP/1.1 100 Continue\r\n\r\n\
+                       HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let empty = "\r\nHTTP/1.1 204 No Content\r\nX-A: b\r\n\r\n";
+        let length = "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\n";
+        let until_close = "HTTP/1.0 200 OK\r\n\r\nall of it";
+        let (recorder, mut stream) = canned(
+            "framing",
+            &format!("{}{}{}{}", chunked, empty, length, until_close),
+        );
+        stream
+            .write_all(b"POST /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        stream
+            .write_all(b"GET /b HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        stream
+            .write_all(b"HEAD /c HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        stream
+            .write_all(b"GET /d HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        read_exactly(&mut stream, chunked.len() - 1);
+        assert!(recorder.cassette().interactions.is_empty());
+        read_exactly(&mut stream, 1);
+        assert_eq!(recorder.cassette().interactions.len(), 1);
+        read_exactly(&mut stream, empty.len());
+        assert_eq!(recorder.cassette().interactions.len(), 2);
+        // a HEAD response has no body, whatever its Content-Length says
+        read_exactly(&mut stream, length.len());
+        assert_eq!(recorder.cassette().interactions.len(), 3);
+        read_exactly(&mut stream, until_close.len());
+        assert_eq!(recorder.cassette().interactions.len(), 3);
+        // the last one ends with the connection
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+
+        let interactions = recorder.cassette().interactions;
+        assert_eq!(interactions.len(), 4);
+        let first = &interactions[0];
+        assert_eq!(first.request.method.as_deref(), Some("POST"));
+        assert_eq!(first.request.url.as_deref(), Some("https://example.com/a"));
+        assert_eq!(first.request.body, "hi");
+        assert_eq!(first.response.status, Some(200));
+        assert_eq!(first.response.body, "hello world");
+        assert_eq!(interactions[1].response.status, Some(204));
+        assert_eq!(interactions[2].request.method.as_deref(), Some("HEAD"));
+        assert_eq!(interactions[2].response.body, "");
+        assert_eq!(interactions[3].response.body, "all of it");
+        fs::remove_file(temp_path("framing")).unwrap();
+    }
+
+    #[test]
+    fn gives_up_on_garbage() {
+        let (recorder, mut stream) = canned("garbage", "HTTP/1.1 200 OK\r\nBad header\r\n\r\n");
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert!(recorder.cassette().interactions.is_empty());
+        assert!(stream.received.is_empty());
+    }
+
+    const TOKEN_URL: &str = "https://login.live.com/oauth20_token.srf";
+    const CALLBACK_URL: &str = "https://api.example.com/cb?code=abc123&state=wersal";
+    const XBOX_URL: &str = "https://user.auth.xboxlive.com/user/authenticate";
+
+    fn server() -> Mock {
+        Mock::new()
+            .route(
+                Route::post(TOKEN_URL)
+                    .unwrap()
+                    .header("Set-Cookie", "session=cookie-secret")
+                    .json(&json!({
+                        "access_token": "access-secret",
+                        "refresh_token": "refresh-secret",
+                        "expires_in": 3600,
+                        "claims": {"UserTokens": ["user-secret"]}
+                    })),
+            )
+            .route(Route::get(CALLBACK_URL).unwrap().body("called back"))
+            .route(
+                Route::post(XBOX_URL).unwrap().json(
+                    &json!({"Token": "xbl-secret", "DisplayClaims": {"xui": [{"uhs": "hash"}]}}),
+                ),
+            )
+    }
+
+    fn exchanges(client: &mut HttpsClient) -> Vec<Response> {
+        let form = RequestBuilder::new(Url::new(TOKEN_URL).unwrap())
+            .http_method(Methods::POST)
+            .form(&[
+                ("client_id", "public-id"),
+                ("client_secret", "client-secret"),
+                ("code", "code-secret"),
+                ("refresh_token", "old-refresh-secret"),
+            ]);
+        let callback = RequestBuilder::new(Url::new(CALLBACK_URL).unwrap())
+            .header(("Authorization", "Bearer bearer-secret"));
+        let xbox = RequestBuilder::new(Url::new(XBOX_URL).unwrap())
+            .http_method(Methods::POST)
+            .json(&json!({
+                "Properties": {"RpsTicket": "d=ticket-secret", "password": "pw-secret"},
+                "RelyingParty": "http://auth.xboxlive.com"
+            }));
+        [form, callback, xbox]
+            .into_iter()
+            .map(|r| client.send(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn records_redacted_and_replays() {
+        let path = temp_path("redacted");
+        let backend = server();
+        let recorder = Recorder::new(&path, Connector::new().dialer(backend.clone()));
+        let mut client = HttpsClient::new("test", None).unwrap();
+        client.set_connector(Connector::new().dialer(recorder.clone()));
+        let live = exchanges(&mut client);
+        assert_eq!(backend.requests().len(), 3);
+        assert_eq!(&live[1].content[..], b"called back");
+
+        let saved = fs::read_to_string(&path).unwrap();
+        for secret in [
+            "client-secret",
+            "code-secret",
+            "old-refresh-secret",
+            "abc123",
+            "bearer-secret",
+            "ticket-secret",
+            "pw-secret",
+            "access-secret",
+            "refresh-secret",
+            "user-secret",
+            "xbl-secret",
+            "cookie-secret",
+        ] {
+            assert!(!saved.contains(secret), "{} was recorded", secret);
+        }
+        for kept in [
+            "public-id",
+            "state=wersal",
+            "3600",
+            "auth.xboxlive.com",
+            "hash",
+        ] {
+            assert!(saved.contains(kept), "{} is missing", kept);
+        }
+
+        let cassette = Cassette::load(&path).unwrap();
+        let token = &cassette.interactions[0];
+        assert!(token.request.body.contains("code=%3Credacted%3E"));
+        assert!(token.request.body.contains("client_id=public-id"));
+        let cookie = token
+            .response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Set-Cookie");
+        assert_eq!(cookie.unwrap().1, REDACTED);
+        let body: Value = serde_json::from_str(&token.response.body).unwrap();
+        assert_eq!(body["access_token"], REDACTED);
+        assert_eq!(body["claims"]["UserTokens"], json!([REDACTED]));
+        assert_eq!(body["expires_in"], 3600);
+        let callback = &cassette.interactions[1];
+        assert_eq!(
+            callback.request.url.as_deref(),
+            Some("https://api.example.com/cb?code=%3Credacted%3E&state=wersal")
+        );
+        let auth = callback
+            .request
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Authorization");
+        assert_eq!(auth.unwrap().1, REDACTED);
+
+        // offline, with the same requests
+        let replayed = replay(&path).unwrap();
+        let mut client = HttpsClient::new("test", None).unwrap();
+        client.set_connector(Connector::new().dialer(replayed.clone()));
+        let offline = exchanges(&mut client);
+        assert!(replayed.is_done());
+        for (live, offline) in live.iter().zip(&offline) {
+            assert_eq!(live.status_code, offline.status_code);
+        }
+        assert_eq!(&offline[1].content[..], b"called back");
+        let body: Value = offline[0].json().unwrap();
+        assert_eq!(body["access_token"], REDACTED);
+        assert_eq!(backend.requests().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn binary_bodies_are_base64() {
+        let path = temp_path("binary");
+        let backend = Mock::new().route(
+            Route::get("https://cdn.example.com/skin.png")
+                .unwrap()
+                .header("Content-Type", "image/png")
+                .body(vec![0x89, b'P', b'N', b'G', 0xff, 0x00]),
+        );
+        let recorder = Recorder::new(&path, Connector::new().dialer(backend));
+        let mut client = HttpsClient::new("test", None).unwrap();
+        client.set_connector(Connector::new().dialer(recorder.clone()));
+        client
+            .get("https://cdn.example.com/skin.png", None)
+            .unwrap();
+
+        let recorded = &recorder.cassette().interactions[0].response;
+        assert!(recorded.base64);
+        let replayed = replay(&path).unwrap();
+        client.set_connector(Connector::new().dialer(replayed));
+        let resp = client
+            .get("https://cdn.example.com/skin.png", None)
+            .unwrap();
+        assert_eq!(&resp.content[..], [0x89, b'P', b'N', b'G', 0xff, 0x00]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,12 +1,21 @@
-use super::request::RequestBuilder;
+use super::body::StreamingResponse;
+use super::canbeclient::CanBeClient;
+use super::error::HttpResult;
+use super::headers::HeaderMap;
+use super::middleware::Middleware;
+use super::pool::ConnectionPool;
+use super::redirect::RedirectPolicy;
+use super::request::{Message, RequestBuilder};
+use super::response::Response;
+use super::retry::RetryPolicy;
+use super::timeout::Timeouts;
+use super::transport::Connector;
 use crate::https::url::Url;
-use crate::tls::tls_stream::TlsStream;
-use std::collections::HashMap;
-use std::io;
-use std::io::{Read, Write};
+use std::sync::Arc;
 
 #[allow(clippy::upper_case_acronyms)]
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Methods {
     GET,
     POST,
@@ -18,59 +27,193 @@ pub enum Methods {
     OPTIONS,
 }
 
-type HeaderMap<'a> = HashMap<&'a str, &'a str>;
+impl Methods {
+    // Sending these twice has the same effect as sending them once (RFC 9110 9.2.2),
+    // so they can be retried on a new connection
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Methods::GET | Methods::HEAD | Methods::PUT | Methods::DELETE | Methods::OPTIONS
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Methods::GET => "GET",
+            Methods::POST => "POST",
+            Methods::PUT => "PUT",
+            Methods::PATCH => "PATCH",
+            Methods::DELETE => "DELETE",
+            Methods::HEAD => "HEAD",
+            Methods::CONNECT => "CONNECT",
+            Methods::OPTIONS => "OPTIONS",
+        }
+    }
+
+    // The method named in a request line, method names are case-sensitive
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "GET" => Some(Methods::GET),
+            "POST" => Some(Methods::POST),
+            "PUT" => Some(Methods::PUT),
+            "PATCH" => Some(Methods::PATCH),
+            "DELETE" => Some(Methods::DELETE),
+            "HEAD" => Some(Methods::HEAD),
+            "CONNECT" => Some(Methods::CONNECT),
+            "OPTIONS" => Some(Methods::OPTIONS),
+            _ => None,
+        }
+    }
+}
 
-pub struct HttpsClient<'b> {
-    headers: HashMap<&'b str, &'b str>,
+pub struct HttpsClient {
+    headers: HeaderMap,
+    pool: ConnectionPool,
+    connector: Connector,
+    middleware: Vec<Arc<dyn Middleware>>,
+    redirect: RedirectPolicy,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
 }
 
-impl<'b> HttpsClient<'b> {
-    pub fn new(agent: &'b str, extra_headers: Option<&HeaderMap<'b>>) -> Self {
-        let mut headers: HeaderMap<'b> = HashMap::new();
-        headers.insert("User-Agent", agent);
+impl HttpsClient {
+    pub fn new(agent: &str, extra_headers: Option<&HeaderMap>) -> HttpResult<Self> {
+        Self::with_pool(agent, extra_headers, ConnectionPool::global().clone())
+    }
+
+    pub fn with_pool(
+        agent: &str,
+        extra_headers: Option<&HeaderMap>,
+        pool: ConnectionPool,
+    ) -> HttpResult<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert("User-Agent", agent)?;
         if let Some(h) = extra_headers {
-            headers.extend(h.iter().map(|(k, v)| (*k, *v)));
+            headers.extend(h);
         }
 
-        Self { headers }
+        Ok(Self {
+            headers,
+            pool,
+            connector: Connector::from_env(),
+            middleware: Vec::new(),
+            redirect: RedirectPolicy::new(),
+            retry: RetryPolicy::new(),
+            timeouts: Timeouts::new(),
+        })
     }
-    fn request(&mut self, method: Methods, url: &str) -> RequestBuilder {
-        let url_parts = Url::new(url).unwrap();
 
-        let mut req = RequestBuilder::new();
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.redirect = policy;
+    }
 
-        req.http_method(method)
-            .headers(&self.headers)
-            .route(url_parts.route())
-            .host(url_parts.domain());
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
 
+    // Used for requests which don't set their own
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // Adds a layer after the ones added before
+    pub fn add_middleware(&mut self, layer: impl Middleware + 'static) {
+        self.middleware.push(Arc::new(layer));
+    }
+
+    // Replaces the proxy settings taken from the environment
+    pub fn set_connector(&mut self, connector: Connector) {
+        self.connector = connector;
+    }
+
+    // Sends a request through the client's middleware and the pool, with the client's
+    // headers, redirect policy, retry policy and timeouts unless the request sets its own
+    pub fn send(&mut self, req: RequestBuilder) -> HttpResult<Response> {
+        req.execute(self)
+    }
+
+    // Like `send`, but returns as soon as the response's headers are read,
+    // the body is read from the connection as it arrives.
+    // Redirects are followed, but middleware and retries are skipped,
+    // since they need the whole response.
+    pub fn send_streaming(&mut self, req: RequestBuilder) -> HttpResult<StreamingResponse> {
+        req.execute_streaming(self)
+    }
+
+    fn send_with(
+        &mut self,
+        method: Methods,
+        url: &str,
+        content: Option<&[u8]>,
+        headers: Option<&HeaderMap>,
+    ) -> HttpResult<Response> {
+        let mut req = self.request(method, url)?;
         if let Some(c) = content {
-            req.content(c);
+            req = req.content(c);
         }
         if let Some(h) = headers {
-            req.headers(&h);
+            req = req.headers(h);
         }
-
-        let bytes = req.build();
-
-        println!("{:?}", std::str::from_utf8(&bytes).unwrap());
-        let mut stream = TlsStream::new(None, url_parts.domain(), &url_parts.socket_addr())?;
-        let mut buf = vec![];
-        let _ = stream.write(&bytes)?;
-        let _ = stream.read_to_end(&mut buf)?;
-        Ok(buf)
+        self.send(req)
     }
 
-    pub fn get(&mut self, url: &str, extra_headers: Option<HeaderMap>) -> io::Result<Vec<u8>> {
-        self.request(Methods::GET, url, None, extra_headers)
+    pub fn get(&mut self, url: &str, extra_headers: Option<&HeaderMap>) -> HttpResult<Response> {
+        self.send_with(Methods::GET, url, None, extra_headers)
     }
 
     pub fn post(
         &mut self,
         url: &str,
         content: Vec<u8>,
-        extra_headers: Option<HeaderMap>,
-    ) -> io::Result<Vec<u8>> {
-        self.request(Methods::POST, url, Some(content), extra_headers)
+        extra_headers: Option<&HeaderMap>,
+    ) -> HttpResult<Response> {
+        self.send_with(Methods::POST, url, Some(&content), extra_headers)
+    }
+}
+
+impl CanBeClient for HttpsClient {
+    fn pool(&self) -> &ConnectionPool {
+        &self.pool
+    }
+
+    fn headers(&self) -> Option<&HeaderMap> {
+        Some(&self.headers)
+    }
+
+    fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    fn redirect_policy(&self) -> RedirectPolicy {
+        self.redirect.clone()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry.clone()
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    fn send_message(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        self.pool.send(url, msg, method, &self.connector, timeouts)
+    }
+
+    fn send_message_streaming(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        self.pool
+            .send_streaming(url, msg, method, &self.connector, timeouts)
     }
 }
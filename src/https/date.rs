@@ -0,0 +1,221 @@
+use std::ops::RangeInclusive;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// HTTP dates (RFC 9110 5.6.7), like "Sun, 06 Nov 1994 08:49:37 GMT".
+// The obsolete RFC 850 and asctime forms are understood too, dates are
+// always written as IMF-fixdate. Years go from 1 to 9999.
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// Days since 1970-01-01 of a date in the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// A number of `digits` ascii digits, without a sign
+fn number(s: &str, digits: RangeInclusive<usize>) -> Option<i64> {
+    if !digits.contains(&s.len()) || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn month(s: &str) -> Option<i64> {
+    Some(MONTHS.iter().position(|m| *m == s)? as i64 + 1)
+}
+
+// RFC 850 years have two digits, they're the latest year with them
+// that's at most 50 years ahead (RFC 9110 5.6.7)
+fn rfc850_year(yy: i64) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs() as i64;
+    let (current, _, _) = civil_from_days(now / 86400);
+    let year = current - current % 100 + yy;
+    if year > current + 50 {
+        year - 100
+    } else {
+        year
+    }
+}
+
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    let (year, month, day, time) = match parts[..] {
+        // "Sun," "06" "Nov" "1994" "08:49:37" "GMT"
+        [weekday, day, mon, year, time, "GMT"] if weekday.ends_with(',') => {
+            (number(year, 4..=4)?, month(mon)?, number(day, 2..=2)?, time)
+        }
+        // "Sunday," "06-Nov-94" "08:49:37" "GMT"
+        [weekday, date, time, "GMT"] if weekday.ends_with(',') => {
+            let mut date = date.split('-');
+            let (day, mon, year) = (date.next()?, date.next()?, date.next()?);
+            if date.next().is_some() {
+                return None;
+            }
+            (
+                rfc850_year(number(year, 2..=2)?),
+                month(mon)?,
+                number(day, 2..=2)?,
+                time,
+            )
+        }
+        // "Sun" "Nov" "6" "08:49:37" "1994"
+        [_, mon, day, time, year] => (number(year, 4..=4)?, month(mon)?, number(day, 1..=2)?, time),
+        _ => return None,
+    };
+
+    let mut time = time.split(':').map(|p| number(p, 2..=2));
+    let (h, m, sec) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() || year == 0 || h > 23 || m > 59 || sec > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    // days the month doesn't have, like Feb 30
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(h * 3600 + m * 60 + sec)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+pub fn format_http_date(t: SystemTime) -> String {
+    let secs = t
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs() as i64;
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const EXAMPLE: u64 = 784111777;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn all_three_forms() {
+        for s in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            let parsed = parse_http_date(s);
+            assert_eq!(parsed, Some(at(EXAMPLE)), "{}", s);
+            assert_eq!(
+                format_http_date(parsed.unwrap()),
+                "Sun, 06 Nov 1994 08:49:37 GMT"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for secs in [0, 951782400, 1709164800, EXAMPLE, 4102444799, 253402300799] {
+            let formatted = format_http_date(at(secs));
+            assert_eq!(parse_http_date(&formatted), Some(at(secs)), "{}", formatted);
+        }
+        assert_eq!(format_http_date(at(0)), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            format_http_date(at(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+        assert_eq!(
+            format_http_date(at(253402300799)),
+            "Fri, 31 Dec 9999 23:59:59 GMT"
+        );
+    }
+
+    #[test]
+    fn rfc850_two_digit_years() {
+        let this_year = format_http_date(SystemTime::now())[12..16]
+            .parse::<i64>()
+            .unwrap();
+        assert_eq!(rfc850_year(this_year % 100), this_year);
+        assert_eq!(rfc850_year(94), 1994);
+        let far = (this_year + 60) % 100;
+        assert_eq!(rfc850_year(far), this_year + 60 - 100);
+    }
+
+    #[test]
+    fn malformed() {
+        for s in [
+            "",
+            "garbage",
+            "Sun, 06 Nov 99999999999999 08:49:37 GMT",
+            "Sun, 06 Nov 19940 08:49:37 GMT",
+            "Sun, 06 Nov 0000 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 06 Nov 1994 -8:49:37 GMT",
+            "Sun, 06 Nov 1994 08:-9:37 GMT",
+            "Sun, 06 Nov 1994 08:49:-7 GMT",
+            "Sun, 06 Nov 1994 +8:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49:61 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun 06 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Thu, 29 Feb 2001 00:00:00 GMT",
+            "Sun, 06 Noo 1994 08:49:37 GMT",
+            "Sun, +6 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94-1 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 99999999999999",
+            "Sun Nov 123 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(s), None, "{:?}", s);
+        }
+        // the leap second is allowed
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:60 GMT"),
+            Some(at(EXAMPLE + 23))
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use brotli_decompressor::Decompressor;
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+
+// Content codings we can decode, sent as `Accept-Encoding`
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+pub fn is_supported(coding: &str) -> bool {
+    matches!(coding, "gzip" | "x-gzip" | "deflate" | "br" | "identity")
+}
+
+// Undoes a single coding
+pub fn decode(coding: &str, data: Bytes) -> Result<Bytes, Error> {
+    let mut out = Vec::with_capacity(data.len() * 4);
+    match coding {
+        "identity" => return Ok(data),
+        "gzip" | "x-gzip" => {
+            GzDecoder::new(&data[..]).read_to_end(&mut out)?;
+        }
+        // "deflate" is supposed to be zlib wrapped,
+        // but some servers send a raw deflate stream
+        "deflate" => {
+            if ZlibDecoder::new(&data[..]).read_to_end(&mut out).is_err() {
+                out.clear();
+                DeflateDecoder::new(&data[..]).read_to_end(&mut out)?;
+            }
+        }
+        "br" => {
+            Decompressor::new(&data[..], 4096).read_to_end(&mut out)?;
+        }
+        c => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported coding: {}", c),
+            ))
+        }
+    }
+    Ok(Bytes::from(out))
+}
+
+// Undoes codings in the reverse order they were applied in
+pub fn decode_all(codings: &[String], data: Bytes) -> Result<Bytes, Error> {
+    codings
+        .iter()
+        .rev()
+        .try_fold(data, |data, coding| decode(coding, data))
+}
+
+// Undoes codings while the data is read, for bodies that aren't kept in memory.
+// `get_mut` reaches the undecoded stream below every layer.
+pub struct Decoder<R: Read> {
+    layer: Layer<R>,
+}
+
+enum Layer<R: Read> {
+    Plain(R),
+    Gzip(Box<GzDecoder<Decoder<R>>>),
+    Zlib(Box<ZlibDecoder<BufReader<Decoder<R>>>>),
+    Deflate(Box<DeflateDecoder<BufReader<Decoder<R>>>>),
+    Brotli(Box<Decompressor<Decoder<R>>>),
+}
+
+impl<R: Read> Decoder<R> {
+    // Passes the data through as is
+    pub fn new(inner: R) -> Self {
+        Self {
+            layer: Layer::Plain(inner),
+        }
+    }
+
+    // Undoes `codings`, in the reverse order they were applied in
+    pub fn with_codings(inner: R, codings: &[String]) -> Result<Self, Error> {
+        codings
+            .iter()
+            .rev()
+            .try_fold(Self::new(inner), |d, coding| d.wrap(coding))
+    }
+
+    // Adds a layer undoing a single coding on top of the current ones
+    pub fn wrap(self, coding: &str) -> Result<Self, Error> {
+        let layer = match coding {
+            "identity" => return Ok(self),
+            "gzip" | "x-gzip" => Layer::Gzip(Box::new(GzDecoder::new(self))),
+            // zlib wrapped or raw, like in `decode`, told apart by the zlib header
+            "deflate" => {
+                let mut r = BufReader::new(self);
+                let head = r.fill_buf()?;
+                let zlib = head.len() >= 2
+                    && head[0] & 0x0f == 8
+                    && u16::from_be_bytes([head[0], head[1]]) % 31 == 0;
+                if zlib {
+                    Layer::Zlib(Box::new(ZlibDecoder::new(r)))
+                } else {
+                    Layer::Deflate(Box::new(DeflateDecoder::new(r)))
+                }
+            }
+            "br" => Layer::Brotli(Box::new(Decompressor::new(self, 4096))),
+            c => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported coding: {}", c),
+                ))
+            }
+        };
+        Ok(Self { layer })
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        match &mut self.layer {
+            Layer::Plain(r) => r,
+            Layer::Gzip(d) => d.get_mut().get_mut(),
+            Layer::Zlib(d) => d.get_mut().get_mut().get_mut(),
+            Layer::Deflate(d) => d.get_mut().get_mut().get_mut(),
+            Layer::Brotli(d) => d.get_mut().get_mut(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match &mut self.layer {
+            Layer::Plain(r) => r.read(buf),
+            Layer::Gzip(d) => d.read(buf),
+            Layer::Zlib(d) => d.read(buf),
+            Layer::Deflate(d) => d.read(buf),
+            Layer::Brotli(d) => d.read(buf),
+        }
+    }
+}
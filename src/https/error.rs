@@ -0,0 +1,326 @@
+use crate::https::headers::HeaderError;
+use crate::https::timeout;
+use crate::https::timeout::TimeoutKind;
+use crate::https::url::UrlError;
+use bytes::Bytes;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::num::ParseIntError;
+use std::str::Utf8Error;
+
+// Everything that can go wrong with a request, from building it
+// to decoding the response. Lower level errors are kept as the source.
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidUrl(UrlError),
+    Request(RequestError),
+    // the server couldn't be reached
+    Connect(Error),
+    Tls(Error),
+    Timeout(TimeoutKind),
+    // the server sent something that isn't valid HTTP
+    Protocol(ProtocolError),
+    Redirect(RedirectError),
+    // a response that isn't 2xx, with its body
+    Status(u16, Bytes),
+    // a 2xx response whose body isn't the expected json
+    Json(u16, Bytes, serde_json::Error),
+    Io(Error),
+}
+
+impl HttpError {
+    // An error from opening a connection.
+    // Plain io errors mean the server couldn't be reached.
+    pub fn connect(e: Error) -> Self {
+        match Self::from(e) {
+            HttpError::Io(e) => HttpError::Connect(e),
+            e => e,
+        }
+    }
+
+    // The response's status, for errors that come with one
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Status(s, _) | HttpError::Json(s, _, _) => Some(*s),
+            _ => None,
+        }
+    }
+
+    // The response's body, for errors that come with one
+    pub fn body(&self) -> Option<&Bytes> {
+        match self {
+            HttpError::Status(_, b) | HttpError::Json(_, b, _) => Some(b),
+            _ => None,
+        }
+    }
+
+    // If sending the same request again might work
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            HttpError::Timeout(k) => *k != TimeoutKind::Total,
+            HttpError::Connect(e) => matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+            ),
+            HttpError::Status(s, _) => is_retryable_status(*s),
+            e => e.is_dead_connection(),
+        }
+    }
+
+    // The server closed the connection before answering
+    pub fn is_dead_connection(&self) -> bool {
+        match self {
+            HttpError::Protocol(ProtocolError::Empty) => true,
+            HttpError::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::NotConnected
+            ),
+            _ => false,
+        }
+    }
+}
+
+// Statuses which usually go away when asked again later
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::InvalidUrl(e) => write!(f, "invalid url: {}", e),
+            HttpError::Request(e) => write!(f, "invalid request: {}", e),
+            HttpError::Connect(e) => write!(f, "couldn't connect: {}", e),
+            HttpError::Tls(e) => write!(f, "tls error: {}", e),
+            HttpError::Timeout(k) => write!(f, "timed out: {}", k),
+            HttpError::Protocol(e) => write!(f, "invalid response: {}", e),
+            HttpError::Redirect(e) => write!(f, "{}", e),
+            HttpError::Status(s, b) => {
+                write!(f, "server answered {}: {}", s, String::from_utf8_lossy(b))
+            }
+            HttpError::Json(s, _, e) => {
+                write!(f, "couldn't parse the {} response as json: {}", s, e)
+            }
+            HttpError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HttpError::InvalidUrl(e) => Some(e),
+            HttpError::Request(e) => Some(e),
+            HttpError::Connect(e) => Some(e),
+            HttpError::Tls(e) => Some(e),
+            HttpError::Timeout(k) => Some(k),
+            HttpError::Protocol(e) => Some(e),
+            HttpError::Redirect(e) => Some(e),
+            HttpError::Status(..) => None,
+            HttpError::Json(_, _, e) => Some(e),
+            HttpError::Io(e) => Some(e),
+        }
+    }
+}
+
+// Timeouts and TLS failures raised by the stream get their own variant,
+// and errors that were passed through a `Read` as io errors are unwrapped
+impl From<Error> for HttpError {
+    fn from(e: Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<HttpError>()) {
+            let kind = e.kind();
+            return match e.into_inner().map(|inner| inner.downcast::<HttpError>()) {
+                Some(Ok(inner)) => *inner,
+                _ => HttpError::Io(Error::from(kind)),
+            };
+        }
+        if let Some(k) = timeout::kind_of(&e) {
+            return HttpError::Timeout(k);
+        }
+        if is_tls_error(&e) {
+            return HttpError::Tls(e);
+        }
+        HttpError::Io(e)
+    }
+}
+
+// rustls errors travel inside io errors, both in TlsStream and tokio-rustls
+fn is_tls_error(e: &Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.downcast_ref::<rustls::Error>().is_some())
+}
+
+// For errors raised inside a `Read` implementation
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        match e {
+            HttpError::Io(e) => e,
+            HttpError::Timeout(k) => timeout::error(k),
+            e => Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl From<UrlError> for HttpError {
+    fn from(e: UrlError) -> Self {
+        HttpError::InvalidUrl(e)
+    }
+}
+
+impl From<HeaderError> for HttpError {
+    fn from(e: HeaderError) -> Self {
+        HttpError::Request(RequestError::Header(e))
+    }
+}
+
+impl From<RequestError> for HttpError {
+    fn from(e: RequestError) -> Self {
+        HttpError::Request(e)
+    }
+}
+
+impl From<ProtocolError> for HttpError {
+    fn from(e: ProtocolError) -> Self {
+        HttpError::Protocol(e)
+    }
+}
+
+impl From<RedirectError> for HttpError {
+    fn from(e: RedirectError) -> Self {
+        HttpError::Redirect(e)
+    }
+}
+
+pub type HttpResult<T> = Result<T, HttpError>;
+
+// A request that can't be sent
+#[derive(Debug, Clone)]
+pub enum RequestError {
+    Header(HeaderError),
+    Body(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Header(e) => write!(f, "invalid header: {}", e),
+            RequestError::Body(e) => write!(f, "couldn't encode the body: {}", e),
+        }
+    }
+}
+
+impl error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RequestError::Header(e) => Some(e),
+            RequestError::Body(..) => None,
+        }
+    }
+}
+
+// A response, or a request sent to us, that can't be parsed
+#[derive(Debug)]
+pub enum ProtocolError {
+    Empty,
+    ParseStrError(Utf8Error),
+    ParseError(ParseIntError),
+    NoHeaders,
+    InvalidHeader,
+    InvalidStatusLine,
+    InvalidRequestLine,
+    InvalidChunk,
+    LineTooLong,
+    UnsupportedTransferEncoding(String),
+    BodyTooLarge(usize),
+    Decode(Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::Empty => write!(f, "the given byte slice was empty"),
+            ProtocolError::ParseError(..) => {
+                write!(f, "there was a error during parsing a number")
+            }
+            ProtocolError::ParseStrError(..) => {
+                write!(f, "there was an error with parsing a string")
+            }
+            ProtocolError::NoHeaders => {
+                write!(f, "no headers were found")
+            }
+            ProtocolError::InvalidHeader => {
+                write!(f, "invalid header")
+            }
+            ProtocolError::InvalidStatusLine => {
+                write!(f, "invalid status line")
+            }
+            ProtocolError::InvalidRequestLine => {
+                write!(f, "invalid request line")
+            }
+            ProtocolError::InvalidChunk => {
+                write!(f, "a chunk wasn't terminated by CRLF")
+            }
+            ProtocolError::LineTooLong => {
+                write!(f, "a status or header line was too long")
+            }
+            ProtocolError::UnsupportedTransferEncoding(ref te) => {
+                write!(f, "unsupported transfer encoding: {}", te)
+            }
+            ProtocolError::BodyTooLarge(limit) => {
+                write!(f, "the body is larger than {} bytes", limit)
+            }
+            ProtocolError::Decode(ref e) => {
+                write!(f, "couldn't decode the content: {}", e)
+            }
+        }
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ProtocolError::ParseError(ref e) => Some(e),
+            ProtocolError::ParseStrError(ref e) => Some(e),
+            ProtocolError::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A redirect that isn't followed
+#[derive(Debug)]
+pub enum RedirectError {
+    TooMany(usize),
+    InvalidLocation(UrlError),
+    NotAllowed(String),
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedirectError::TooMany(n) => write!(f, "stopped after {} redirects", n),
+            RedirectError::InvalidLocation(e) => {
+                write!(f, "the redirect location is not a valid url: {}", e)
+            }
+            RedirectError::NotAllowed(u) => {
+                write!(f, "redirect to another host is not allowed: {}", u)
+            }
+        }
+    }
+}
+
+impl error::Error for RedirectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RedirectError::InvalidLocation(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::https::date::parse_http_date;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    InvalidName(String),
+    InvalidValue(String),
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::InvalidName(n) => write!(f, "invalid header name: {:?}", n),
+            HeaderError::InvalidValue(n) => write!(f, "invalid value for header {}", n),
+        }
+    }
+}
+impl Error for HeaderError {}
+
+// tchar = "!" / "#" / "$" / "%" / "&" / "'" / "*" / "+" / "-" / "." /
+//         "^" / "_" / "`" / "|" / "~" / DIGIT / ALPHA
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+// Anything but control characters (tabs are fine),
+// most importantly no CR or LF which would end the header
+fn is_valid_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f))
+}
+
+// Splits a comma separated header into lowercase tokens, "gzip, Chunked" -> ["gzip", "chunked"]
+fn split_tokens<'a, I: Iterator<Item = &'a str>>(values: I) -> Vec<String> {
+    values
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// Headers of a request or response.
+// Names are matched case-insensitively but sent the way they were given,
+// a name can have several values, and the order of insertion is kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Result<Self, HeaderError> {
+        let mut map = Self::new();
+        for (k, v) in pairs {
+            map.insert(k, v)?;
+        }
+        Ok(map)
+    }
+
+    fn validate(name: &str, value: &str) -> Result<(), HeaderError> {
+        if !is_valid_name(name) {
+            return Err(HeaderError::InvalidName(name.to_string()));
+        }
+        if !is_valid_value(value) {
+            return Err(HeaderError::InvalidValue(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // Sets the header, replacing every value it had
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        Self::validate(name, value)?;
+        self.remove(name);
+        self.entries
+            .push((name.to_string(), value.trim().to_string()));
+        Ok(())
+    }
+
+    // Adds another value for the header
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), HeaderError> {
+        Self::validate(name, value)?;
+        self.entries
+            .push((name.to_string(), value.trim().to_string()));
+        Ok(())
+    }
+
+    // Removes every value of the header, returns if there was any
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        len != self.entries.len()
+    }
+
+    // The first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Copies every header of `other`, replacing the ones with the same name
+    pub fn extend(&mut self, other: &HeaderMap) {
+        for (k, _) in &other.entries {
+            self.remove(k);
+        }
+        self.entries.extend(other.entries.iter().cloned());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Typed accessors
+
+    // None if missing or not a number
+    pub fn content_length(&self) -> Option<usize> {
+        self.get("Content-Length")?.parse().ok()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+    }
+
+    pub fn location(&self) -> Option<&str> {
+        self.get("Location")
+    }
+
+    // Codings from every Transfer-Encoding header, in order
+    pub fn transfer_encoding(&self) -> Vec<String> {
+        split_tokens(self.get_all("Transfer-Encoding").into_iter())
+    }
+
+    // Codings from every Content-Encoding header, in order
+    pub fn content_encoding(&self) -> Vec<String> {
+        split_tokens(self.get_all("Content-Encoding").into_iter())
+    }
+
+    // Options of the Connection header, like "close" or "keep-alive"
+    pub fn connection(&self) -> Vec<String> {
+        split_tokens(self.get_all("Connection").into_iter())
+    }
+
+    // Either a number of seconds or a date
+    pub fn retry_after(&self) -> Option<Duration> {
+        let v = self.get("Retry-After")?;
+        match v.parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                let at = parse_http_date(v)?;
+                Some(
+                    at.duration_since(SystemTime::now())
+                        .unwrap_or(Duration::ZERO),
+                )
+            }
+        }
+    }
+
+    pub fn set_cookies(&self) -> Vec<&str> {
+        self.get_all("Set-Cookie")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut map = HeaderMap::from_pairs(&[("Content-Type", "text/plain")]).unwrap();
+        assert_eq!(map.get("content-type"), Some("text/plain"));
+        assert!(map.contains("CONTENT-TYPE"));
+
+        map.insert("content-TYPE", "text/html").unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.iter().next(), Some(("content-TYPE", "text/html")));
+
+        assert!(map.remove("Content-Type"));
+        assert!(!map.remove("content-type"));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn append_keeps_every_value() {
+        let mut map = HeaderMap::new();
+        map.append("Set-Cookie", "a=1").unwrap();
+        map.append("Host", "example.com").unwrap();
+        map.append("set-cookie", "b=2").unwrap();
+        map.append("SET-COOKIE", "c=3").unwrap();
+        assert_eq!(map.set_cookies(), ["a=1", "b=2", "c=3"]);
+        assert_eq!(map.get("Set-Cookie"), Some("a=1"));
+
+        map.insert("Set-Cookie", "d=4").unwrap();
+        assert_eq!(map.get_all("set-cookie"), ["d=4"]);
+        // the other headers keep their place
+        assert_eq!(map.iter().next(), Some(("Host", "example.com")));
+    }
+
+    #[test]
+    fn extend_replaces_by_name() {
+        let mut map = HeaderMap::from_pairs(&[("Accept", "*/*"), ("X-A", "1")]).unwrap();
+        let mut other = HeaderMap::new();
+        other.append("x-a", "2").unwrap();
+        other.append("x-a", "3").unwrap();
+        map.extend(&other);
+        assert_eq!(map.get_all("X-A"), ["2", "3"]);
+        assert_eq!(map.get("Accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn rejects_line_breaks() {
+        let mut map = HeaderMap::new();
+        for value in ["a\r\nX-Injected: 1", "a\nb", "a\rb", "a\0b"] {
+            assert_eq!(
+                map.insert("X-Test", value),
+                Err(HeaderError::InvalidValue("X-Test".to_string()))
+            );
+            assert!(map.append("X-Test", value).is_err());
+        }
+        for name in ["X-Test\r\nX-Injected", "X Test", "X:Test", ""] {
+            assert_eq!(
+                map.insert(name, "a"),
+                Err(HeaderError::InvalidName(name.to_string()))
+            );
+        }
+        assert!(map.is_empty());
+
+        map.insert("X-Test", "\ta b  ").unwrap();
+        assert_eq!(map.get("X-Test"), Some("a b"));
+    }
+}
@@ -0,0 +1,475 @@
+use super::error::HttpResult;
+use super::headers::HeaderMap;
+use super::request::RequestBuilder;
+use super::response::Response;
+use super::url::{percent_decode, Url};
+use super::util::basic_auth;
+use log::{debug, info, log_enabled, warn, Level};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Middleware wraps every request a client sends, redirects and retries included.
+// Each layer gets the request and the rest of the chain, so it can change the
+// request, look at or change the response, or answer without calling `next`.
+// Layers run in the order they were added.
+pub trait Middleware: Send + Sync {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response>;
+}
+
+// The layers after the current one, ending with the client sending the request
+pub struct Next<'a, 'r> {
+    rest: &'a [Arc<dyn Middleware>],
+    send: &'a mut dyn FnMut(RequestBuilder<'r>) -> HttpResult<Response>,
+}
+
+impl<'r> Next<'_, 'r> {
+    pub fn run(self, req: RequestBuilder<'r>) -> HttpResult<Response> {
+        match self.rest.split_first() {
+            Some((layer, rest)) => layer.handle(
+                req,
+                Next {
+                    rest,
+                    send: self.send,
+                },
+            ),
+            None => (self.send)(req),
+        }
+    }
+}
+
+// Passes `req` through the layers, `send` is called by the last one
+pub fn run<'r, F>(
+    layers: &[Arc<dyn Middleware>],
+    req: RequestBuilder<'r>,
+    mut send: F,
+) -> HttpResult<Response>
+where
+    F: FnMut(RequestBuilder<'r>) -> HttpResult<Response>,
+{
+    Next {
+        rest: layers,
+        send: &mut send,
+    }
+    .run(req)
+}
+
+pub(crate) const REDACTED: &str = "<redacted>";
+
+// Logs every request and its outcome at info, and their headers at debug.
+// Credentials in headers and query parameters are replaced before logging.
+#[derive(Debug, Clone)]
+pub struct Logger {
+    headers: Vec<String>,
+    params: Vec<String>,
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        let headers = [
+            "Authorization",
+            "Proxy-Authorization",
+            "Cookie",
+            "Set-Cookie",
+        ];
+        let params = [
+            "access_token",
+            "refresh_token",
+            "client_secret",
+            "code",
+            "password",
+            "token",
+        ];
+        Self {
+            headers: headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    // Also hide this header's value
+    pub fn redact_header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_ascii_lowercase());
+        self
+    }
+
+    // Also hide this query parameter's value
+    pub fn redact_param(mut self, name: &str) -> Self {
+        self.params.push(name.to_ascii_lowercase());
+        self
+    }
+
+    // The url without the password and secret parameters
+    pub fn redact_url(&self, url: &Url) -> String {
+        let mut s = format!("{}://{}{}", url.scheme(), url.host_header(), url.route());
+        if url.query().is_empty() {
+            return s;
+        }
+        let pairs: Vec<String> = url
+            .query()
+            .split('&')
+            .map(|p| match p.split_once('=') {
+                Some((k, _)) if self.is_secret_param(k) => format!("{}={}", k, REDACTED),
+                _ => p.to_string(),
+            })
+            .collect();
+        s.push('?');
+        s.push_str(&pairs.join("&"));
+        s
+    }
+
+    fn is_secret_param(&self, key: &str) -> bool {
+        let key = percent_decode(key).unwrap_or_else(|_| key.to_string());
+        self.params.contains(&key.to_ascii_lowercase())
+    }
+
+    // The header's value as it's logged
+    fn shown_value<'v>(&self, name: &str, value: &'v str) -> &'v str {
+        if self.headers.contains(&name.to_ascii_lowercase()) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    fn log_headers(&self, prefix: &str, headers: &HeaderMap) {
+        for (k, v) in headers.iter() {
+            debug!("{} {}: {}", prefix, k, self.shown_value(k, v));
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Logger {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+        let line = format!("{:?} {}", req.method(), self.redact_url(req.url()));
+        info!("{}", line);
+        if log_enabled!(Level::Debug) {
+            self.log_headers(">", req.header_map());
+        }
+
+        let start = Instant::now();
+        let res = next.run(req);
+        match &res {
+            Ok(resp) => {
+                info!(
+                    "{} -> {} ({} bytes in {:?})",
+                    line,
+                    resp.status_code,
+                    resp.content.len(),
+                    start.elapsed()
+                );
+                if log_enabled!(Level::Debug) {
+                    self.log_headers("<", &resp.headers);
+                }
+            }
+            Err(e) => warn!("{} failed after {:?}: {}", line, start.elapsed(), e),
+        }
+        res
+    }
+}
+
+// Adds an Authorization header to requests which don't have one
+#[derive(Clone)]
+pub struct Auth {
+    value: String,
+}
+
+impl Auth {
+    // The header's whole value, like "Bot <token>"
+    pub fn new(value: &str) -> Self {
+        Self {
+            value: value.to_string(),
+        }
+    }
+
+    pub fn bearer(token: &str) -> Self {
+        Self::new(&format!("Bearer {}", token))
+    }
+
+    pub fn basic(user: &str, password: &str) -> Self {
+        Self::new(&basic_auth(user, password))
+    }
+}
+
+// never print the credentials
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Auth({})", REDACTED)
+    }
+}
+
+impl Middleware for Auth {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+        if req.header_map().contains("Authorization") {
+            return next.run(req);
+        }
+        next.run(req.header(("Authorization", &self.value)))
+    }
+}
+
+// Adds headers to requests which don't set them themselves
+#[derive(Debug, Clone)]
+pub struct DefaultHeaders {
+    headers: HeaderMap,
+}
+
+impl DefaultHeaders {
+    pub fn new(headers: HeaderMap) -> Self {
+        Self { headers }
+    }
+}
+
+impl Middleware for DefaultHeaders {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+        next.run(req.default_headers(&self.headers))
+    }
+}
+
+// Counts requests and how they ended.
+// Clones share the counters, so keep one to read them after adding it to a client.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    success: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    failures: AtomicU64,
+    total_micros: AtomicU64,
+}
+
+// The counters at one point in time.
+// `failures` are requests which got no response at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub requests: u64,
+    pub success: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub failures: u64,
+    pub total_time: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let c = &self.inner;
+        MetricsSnapshot {
+            requests: c.requests.load(Ordering::Relaxed),
+            success: c.success.load(Ordering::Relaxed),
+            client_errors: c.client_errors.load(Ordering::Relaxed),
+            server_errors: c.server_errors.load(Ordering::Relaxed),
+            failures: c.failures.load(Ordering::Relaxed),
+            total_time: Duration::from_micros(c.total_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+        let c = &self.inner;
+        c.requests.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let res = next.run(req);
+        let counter = match &res {
+            Ok(resp) if resp.is_success() => &c.success,
+            Ok(resp) if (400..500).contains(&resp.status_code) => &c.client_errors,
+            Ok(resp) if resp.status_code >= 500 => &c.server_errors,
+            Ok(_) => &c.success,
+            Err(_) => &c.failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        c.total_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::client::HttpsClient;
+    use crate::https::mock::{Mock, Route};
+    use crate::https::pool::ConnectionPool;
+    use crate::https::retry::RetryPolicy;
+    use crate::https::transport::Connector;
+    use crate::https::util::lock_unpoisoned;
+    use std::sync::Mutex;
+
+    const URL: &str = "https://mw.test/";
+
+    fn client(mock: &Mock) -> HttpsClient {
+        let mut client = HttpsClient::with_pool("test", None, ConnectionPool::new()).unwrap();
+        client.set_connector(Connector::new().dialer(mock.clone()));
+        client.set_retry_policy(RetryPolicy::none());
+        client
+    }
+
+    fn get(url: &str) -> RequestBuilder<'static> {
+        RequestBuilder::new(Url::new(url).unwrap())
+    }
+
+    #[test]
+    fn logger_redacts_credentials() {
+        let logger = Logger::new().redact_header("X-Api-Key").redact_param("sig");
+        assert_eq!(logger.shown_value("authorization", "Bot secret"), REDACTED);
+        assert_eq!(
+            logger.shown_value("Proxy-Authorization", "Basic x"),
+            REDACTED
+        );
+        assert_eq!(logger.shown_value("x-api-key", "secret"), REDACTED);
+        assert_eq!(logger.shown_value("Accept", "*/*"), "*/*");
+
+        let url = Url::new("https://mw.test/cb?code=abc&state=1&SIG=x&access%5Ftoken=t").unwrap();
+        assert_eq!(
+            logger.redact_url(&url),
+            "https://mw.test/cb?code=<redacted>&state=1&SIG=<redacted>&access%5Ftoken=<redacted>"
+        );
+
+        // only the log is redacted, not the request
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let mut client = client(&mock);
+        client.add_middleware(logger);
+        client
+            .send(get(URL).header(("Authorization", "Bot secret")))
+            .unwrap();
+        assert_eq!(
+            mock.requests()[0].headers.get("Authorization"),
+            Some("Bot secret")
+        );
+    }
+
+    #[test]
+    fn auth_fills_in_authorization() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let mut client = client(&mock);
+        client.add_middleware(Auth::basic("user", "pass"));
+        client.send(get(URL)).unwrap();
+        client
+            .send(get(URL).header(("authorization", "Bearer mine")))
+            .unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0].headers.get("Authorization"),
+            Some("Basic dXNlcjpwYXNz")
+        );
+        assert_eq!(
+            requests[1].headers.get_all("Authorization"),
+            ["Bearer mine"]
+        );
+        assert_eq!(format!("{:?}", Auth::bearer("t")), "Auth(<redacted>)");
+    }
+
+    #[test]
+    fn default_headers_dont_overwrite() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let mut client = client(&mock);
+        let defaults =
+            HeaderMap::from_pairs(&[("Accept", "application/json"), ("X-Trace", "1")]).unwrap();
+        client.add_middleware(DefaultHeaders::new(defaults));
+        client
+            .send(get(URL).header(("accept", "text/plain")))
+            .unwrap();
+
+        let headers = &mock.requests()[0].headers;
+        assert_eq!(headers.get_all("Accept"), ["text/plain"]);
+        assert_eq!(headers.get("X-Trace"), Some("1"));
+    }
+
+    #[test]
+    fn metrics_count_outcomes() {
+        let mock = Mock::new()
+            .route(Route::get("https://mw.test/ok").unwrap())
+            .route(Route::get("https://mw.test/missing").unwrap().status(404))
+            .route(Route::get("https://mw.test/broken").unwrap().status(500))
+            .route(Route::get("https://mw.test/gone").unwrap().hang_up());
+        let mut client = client(&mock);
+        let metrics = Metrics::new();
+        client.add_middleware(metrics.clone());
+
+        for path in ["ok", "missing", "broken", "unrouted"] {
+            client
+                .send(get(&format!("https://mw.test/{}", path)))
+                .unwrap();
+        }
+        assert!(client.send(get("https://mw.test/gone")).is_err());
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests, 5);
+        assert_eq!(snapshot.success, 1);
+        assert_eq!(snapshot.client_errors, 1);
+        // the mock answers 501 without a route
+        assert_eq!(snapshot.server_errors, 2);
+        assert_eq!(snapshot.failures, 1);
+    }
+
+    // Records when the request passes it on the way in and the response on the way out
+    struct Trace {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Trace {
+        fn handle<'r>(&self, req: RequestBuilder<'r>, next: Next<'_, 'r>) -> HttpResult<Response> {
+            lock_unpoisoned(&self.log).push(format!("{} in", self.name));
+            let res = next.run(req.header(("X-Trace", self.name)));
+            lock_unpoisoned(&self.log).push(format!("{} out", self.name));
+            res
+        }
+    }
+
+    // Answers every request itself
+    struct Teapot;
+
+    impl Middleware for Teapot {
+        fn handle<'r>(&self, _: RequestBuilder<'r>, _: Next<'_, 'r>) -> HttpResult<Response> {
+            Response::from_slice(b"HTTP/1.1 418 I'm a teapot\r\nContent-Length: 0\r\n\r\n")
+        }
+    }
+
+    #[test]
+    fn layers_run_in_order() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let mut client = client(&mock);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["first", "second"] {
+            client.add_middleware(Trace {
+                name,
+                log: log.clone(),
+            });
+        }
+        client.send(get(URL)).unwrap();
+
+        assert_eq!(
+            *lock_unpoisoned(&log),
+            ["first in", "second in", "second out", "first out"]
+        );
+        // the last layer to set a header wins
+        assert_eq!(mock.requests()[0].headers.get_all("X-Trace"), ["second"]);
+
+        // a layer that answers keeps the rest of the chain from running
+        client.add_middleware(Teapot);
+        client.add_middleware(Trace {
+            name: "third",
+            log: log.clone(),
+        });
+        lock_unpoisoned(&log).clear();
+        let resp = client.send(get(URL)).unwrap();
+        assert_eq!(resp.status_code, 418);
+        assert_eq!(mock.requests().len(), 1);
+        assert!(!lock_unpoisoned(&log).iter().any(|l| l.starts_with("third")));
+    }
+}
@@ -0,0 +1,475 @@
+use super::body::StreamingResponse;
+use super::canbeclient::CanBeClient;
+use super::client::Methods;
+use super::error::{HttpError, HttpResult, ProtocolError};
+use super::headers::HeaderMap;
+use super::redirect::same_origin;
+use super::request::{read_request_body, read_request_head, Message};
+use super::response::Response;
+use super::retry::RetryPolicy;
+use super::timeout::Timeouts;
+use super::transport::{AsyncTransport, Connector, Dialer, Transport};
+use super::url::{Url, UrlError};
+use super::util::lock_unpoisoned;
+use log::{debug, warn};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A canned response, and the requests it answers
+#[derive(Debug, Clone)]
+pub struct Route {
+    method: Methods,
+    url: Url,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // how often it may still match, None for always
+    times: Option<usize>,
+    hang_up: bool,
+}
+
+impl Route {
+    // Matches requests with this method to the url's origin and path.
+    // The query only has to match if the url has one.
+    // Answers 200 with an empty body until told otherwise.
+    pub fn new(method: Methods, url: &str) -> Result<Self, UrlError> {
+        Ok(Self {
+            method,
+            url: Url::new(url)?,
+            status: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            times: None,
+            hang_up: false,
+        })
+    }
+
+    pub fn get(url: &str) -> Result<Self, UrlError> {
+        Self::new(Methods::GET, url)
+    }
+
+    pub fn post(url: &str) -> Result<Self, UrlError> {
+        Self::new(Methods::POST, url)
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn json(self, value: &serde_json::Value) -> Self {
+        self.header("Content-Type", "application/json")
+            .body(value.to_string())
+    }
+
+    // Only answer this many requests, later ones go to the next matching route.
+    // Routes for the same request answer in the order they were added.
+    pub fn times(mut self, n: usize) -> Self {
+        self.times = Some(n);
+        self
+    }
+
+    // Close the connection instead of answering,
+    // like a server that dropped it while it was idle
+    pub fn hang_up(mut self) -> Self {
+        self.hang_up = true;
+        self
+    }
+
+    fn matches(&self, req: &RecordedRequest) -> bool {
+        self.times != Some(0)
+            && self.method == req.method
+            && same_origin(&self.url, &req.url)
+            && self.url.route() == req.url.route()
+            && (self.url.query().is_empty() || self.url.query() == req.url.query())
+    }
+
+    fn to_bytes(&self, method: &Methods) -> Vec<u8> {
+        if self.hang_up {
+            return Vec::new();
+        }
+        let mut buf = format!("HTTP/1.1 {} Mock\r\n", self.status).into_bytes();
+        for (k, v) in &self.headers {
+            buf.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
+        }
+        buf.extend_from_slice(format!("Content-Length: {}\r\n\r\n", self.body.len()).as_bytes());
+        if !matches!(method, Methods::HEAD) {
+            buf.extend_from_slice(&self.body);
+        }
+        buf
+    }
+}
+
+// A request the mock received
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Methods,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    routes: Vec<Route>,
+    requests: Vec<RecordedRequest>,
+    connections: usize,
+}
+
+// An in-memory server for tests. With `Connector::new().dialer(mock.clone())`
+// a client's connections all end up here instead of the network.
+// Requests are answered by the first route that matches and recorded,
+// ones without a route get 501. Clones share the routes and the recording.
+#[derive(Debug, Clone)]
+pub struct Mock {
+    id: usize,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    pub fn route(self, route: Route) -> Self {
+        lock_unpoisoned(&self.state).routes.push(route);
+        self
+    }
+
+    // Every request received so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        lock_unpoisoned(&self.state).requests.clone()
+    }
+
+    // Number of connections opened to the mock
+    pub fn connections(&self) -> usize {
+        lock_unpoisoned(&self.state).connections
+    }
+
+    // If every route limited with `times` has answered as often as it may
+    pub fn is_done(&self) -> bool {
+        lock_unpoisoned(&self.state)
+            .routes
+            .iter()
+            .all(|r| r.times.is_none_or(|n| n == 0))
+    }
+
+    fn answer(&self, req: RecordedRequest) -> Vec<u8> {
+        let mut state = lock_unpoisoned(&self.state);
+        let resp = match state.routes.iter_mut().find(|r| r.matches(&req)) {
+            Some(route) => {
+                if let Some(n) = &mut route.times {
+                    *n -= 1;
+                }
+                debug!(
+                    "Mock answers {:?} {} with {}",
+                    req.method, req.url, route.status
+                );
+                route.to_bytes(&req.method)
+            }
+            None => {
+                warn!("No mock route for {:?} {}", req.method, req.url);
+                let body = format!("no mock route for {:?} {}", req.method, req.url);
+                format!(
+                    "HTTP/1.1 501 Not Implemented\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .into_bytes()
+            }
+        };
+        state.requests.push(req);
+        resp
+    }
+
+    fn stream(&self, url: &Url) -> MockStream {
+        lock_unpoisoned(&self.state).connections += 1;
+        MockStream {
+            mock: self.clone(),
+            url: url.clone(),
+            input: Vec::new(),
+            output: Vec::new(),
+            continued: false,
+        }
+    }
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dialer for Mock {
+    fn dial(&self, url: &Url, _: &Timeouts) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(self.stream(url)))
+    }
+
+    fn dial_async(&self, url: &Url) -> Result<Box<dyn AsyncTransport>, Error> {
+        Ok(Box::new(self.stream(url)))
+    }
+
+    fn name(&self) -> String {
+        format!("mock#{}", self.id)
+    }
+}
+
+// Requests can be executed on the mock itself, they go through the global pool.
+// Nothing is retried, so every answer shows up in the test.
+impl CanBeClient for Mock {
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::none()
+    }
+
+    fn send_message(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let connector = Connector::new().dialer(self.clone());
+        self.pool().send(url, msg, method, &connector, timeouts)
+    }
+
+    fn send_message_streaming(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        let connector = Connector::new().dialer(self.clone());
+        self.pool()
+            .send_streaming(url, msg, method, &connector, timeouts)
+    }
+}
+
+// One connection to the mock. Requests are answered as soon as
+// they're completely written, the answers wait to be read.
+struct MockStream {
+    mock: Mock,
+    // the url the connection was opened for
+    url: Url,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // if 100 Continue was sent for the request being written
+    continued: bool,
+}
+
+// A message that isn't completely written yet
+pub(crate) fn is_incomplete(e: &HttpError) -> bool {
+    match e {
+        HttpError::Protocol(ProtocolError::Empty) => true,
+        HttpError::Io(e) => e.kind() == ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+impl MockStream {
+    // Answers every complete request in the input
+    fn process(&mut self) -> Result<(), Error> {
+        loop {
+            let mut r = &self.input[..];
+            let (method, target, headers) = match read_request_head(&mut r) {
+                Ok(head) => head,
+                Err(e) if is_incomplete(&e) => return Ok(()),
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+            };
+            let expects = headers
+                .get("Expect")
+                .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"));
+            if expects && !self.continued {
+                self.continued = true;
+                self.output
+                    .extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+            }
+            let body = match read_request_body(&mut r, &headers, usize::MAX) {
+                Ok(body) => body,
+                Err(e) if is_incomplete(&e) => return Ok(()),
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+            };
+
+            let host = match headers.get("Host") {
+                Some(h) => h.to_string(),
+                None => self.url.host_header(),
+            };
+            let url = Url::new(&format!("{}://{}{}", self.url.scheme(), host, target))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let consumed = self.input.len() - r.len();
+            self.input.drain(..consumed);
+            self.continued = false;
+
+            let resp = self.mock.answer(RecordedRequest {
+                method,
+                url,
+                headers,
+                body,
+            });
+            self.output.extend_from_slice(&resp);
+        }
+    }
+
+    // Nothing left to read means the server closed the connection
+    fn read_output(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.output.len());
+        buf[..n].copy_from_slice(&self.output[..n]);
+        self.output.drain(..n);
+        n
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.read_output(buf))
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.input.extend_from_slice(buf);
+        self.process()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for MockStream {
+    fn set_timeouts(&mut self, _: &Timeouts) {}
+
+    // idle connections stay open, unless there's an answer nobody asked for
+    fn is_closed(&mut self) -> bool {
+        !self.output.is_empty()
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let n = this.read_output(buf.initialize_unfilled());
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Poll::Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncTransport for MockStream {
+    fn set_timeouts(&mut self, _: &Timeouts) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::request::RequestBuilder;
+
+    fn get(mock: &mut Mock, url: &str) -> Response {
+        RequestBuilder::new(Url::new(url).unwrap())
+            .execute(mock)
+            .unwrap()
+    }
+
+    #[test]
+    fn routes_answer_in_order() {
+        let mut mock = Mock::new()
+            .route(Route::get("https://a/x").unwrap().body("first").times(1))
+            .route(Route::get("https://a/x").unwrap().status(404).body("later"));
+        assert!(!mock.is_done());
+        assert_eq!(&get(&mut mock, "https://a/x").content[..], b"first");
+        assert!(mock.is_done());
+        for _ in 0..2 {
+            let resp = get(&mut mock, "https://a/x");
+            assert_eq!(resp.status_code, 404);
+            assert_eq!(&resp.content[..], b"later");
+        }
+        assert_eq!(mock.requests().len(), 3);
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[test]
+    fn unmatched_requests_get_501() {
+        let mut mock = Mock::new()
+            .route(Route::post("https://a/x").unwrap())
+            .route(Route::get("https://a/q?k=v").unwrap());
+        assert_eq!(get(&mut mock, "https://a/x").status_code, 501);
+        assert_eq!(get(&mut mock, "https://b/x").status_code, 501);
+        assert_eq!(get(&mut mock, "http://a/x").status_code, 501);
+        assert_eq!(get(&mut mock, "https://a/q?k=w").status_code, 501);
+        assert_eq!(get(&mut mock, "https://a/q?k=v").status_code, 200);
+        // a route without a query takes any
+        let mut mock = Mock::new().route(Route::get("https://a/q").unwrap());
+        assert_eq!(get(&mut mock, "https://a/q?k=w").status_code, 200);
+        assert_eq!(mock.requests()[0].url.query(), "k=w");
+    }
+
+    #[test]
+    fn records_requests() {
+        let mut mock = Mock::new().route(
+            Route::post("https://a/x")
+                .unwrap()
+                .json(&serde_json::json!({"ok": true})),
+        );
+        let resp = RequestBuilder::new(Url::new("https://a/x").unwrap())
+            .http_method(Methods::POST)
+            .header(("X-Test", "1"))
+            .json(&serde_json::json!({"a": 1}))
+            .execute(&mut mock)
+            .unwrap();
+        assert_eq!(resp.headers.content_type(), Some("application/json"));
+        assert_eq!(resp.json::<serde_json::Value>().unwrap()["ok"], true);
+
+        let req = &mock.requests()[0];
+        assert_eq!(req.method, Methods::POST);
+        assert_eq!(req.headers.get("X-Test"), Some("1"));
+        assert_eq!(req.headers.get("Host"), Some("a"));
+        assert_eq!(req.body_str(), r#"{"a":1}"#);
+    }
+}
@@ -1,6 +1,26 @@
+pub mod async_client;
+pub mod body;
+pub mod cache;
 pub mod canbeclient;
+pub mod cassette;
 pub mod client;
+pub mod date;
+pub mod encoding;
+pub mod error;
+pub mod headers;
+pub mod middleware;
+pub mod mock;
+pub mod multipart;
 pub mod persistent_client;
+pub mod pool;
+pub mod proxy;
+pub mod redirect;
 pub mod request;
+pub mod resolver;
 pub mod response;
+pub mod retry;
+pub mod server;
+pub mod timeout;
+pub mod transport;
 pub mod url;
+mod util;
@@ -0,0 +1,299 @@
+use super::util::{lock_unpoisoned, random};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Cursor, Error, Read};
+use std::sync::{Arc, Mutex};
+
+const CRLF: &[u8] = "\r\n".as_bytes();
+
+type PartStream = Arc<Mutex<Option<Box<dyn Read + Send>>>>;
+
+#[derive(Clone)]
+enum PartBody {
+    Bytes(Vec<u8>),
+    // clones of the part share it, whichever request is sent first takes it
+    Stream(PartStream),
+}
+
+impl Debug for PartBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PartBody::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            PartBody::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+// One field of a multipart/form-data body (RFC 7578)
+#[derive(Debug, Clone)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    body: PartBody,
+}
+
+impl Part {
+    pub fn text(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            filename: None,
+            content_type: None,
+            body: PartBody::Bytes(value.as_bytes().to_vec()),
+        }
+    }
+
+    // Sent as application/octet-stream unless another type is set
+    pub fn bytes(name: &str, body: Vec<u8>) -> Self {
+        Self::with_body(name, PartBody::Bytes(body))
+    }
+
+    // Read while the request is sent, so the form goes out with chunked
+    // transfer encoding and the part doesn't have to fit in memory.
+    // Like `RequestBuilder::body_reader` it can only be sent once.
+    pub fn reader(name: &str, r: impl Read + Send + 'static) -> Self {
+        Self::with_body(
+            name,
+            PartBody::Stream(Arc::new(Mutex::new(Some(Box::new(r))))),
+        )
+    }
+
+    fn with_body(name: &str, body: PartBody) -> Self {
+        Self {
+            name: name.to_string(),
+            filename: None,
+            content_type: Some("application/octet-stream".to_string()),
+            body,
+        }
+    }
+
+    pub fn file_name(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    fn write_head(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice("Content-Disposition: form-data; name=\"".as_bytes());
+        buf.extend_from_slice(escape(&self.name).as_bytes());
+        buf.push(b'"');
+        if let Some(f) = &self.filename {
+            buf.extend_from_slice("; filename=\"".as_bytes());
+            buf.extend_from_slice(escape(f).as_bytes());
+            buf.push(b'"');
+        }
+        buf.extend_from_slice(CRLF);
+        if let Some(t) = &self.content_type {
+            buf.extend_from_slice("Content-Type: ".as_bytes());
+            buf.extend_from_slice(escape(t).as_bytes());
+            buf.extend_from_slice(CRLF);
+        }
+        buf.extend_from_slice(CRLF);
+    }
+}
+
+// Quotes and line breaks would end the header early,
+// they're percent-encoded like browsers do
+fn escape(s: &str) -> String {
+    s.replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+// A multipart/form-data body, for file uploads.
+// Give it to `RequestBuilder::multipart`, which also sets the Content-Type.
+#[derive(Debug, Clone, Default)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(self, name: &str, value: &str) -> Self {
+        self.part(Part::text(name, value))
+    }
+
+    // A file part, with the type guessed by the receiver
+    pub fn file(self, name: &str, filename: &str, body: Vec<u8>) -> Self {
+        self.part(Part::bytes(name, body).file_name(filename))
+    }
+
+    pub fn part(mut self, part: Part) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    // A boundary which isn't found in any part. Streamed parts can't be
+    // checked, but 128 random bits won't turn up in them by chance.
+    fn boundary(&self) -> String {
+        loop {
+            let b = format!("------------------------{:016x}{:016x}", random(), random());
+            let bytes = b.as_bytes();
+            let unused = self.parts.iter().all(|p| match &p.body {
+                PartBody::Bytes(body) => !body.windows(bytes.len()).any(|w| w == bytes),
+                PartBody::Stream(_) => true,
+            });
+            if unused {
+                return b;
+            }
+        }
+    }
+
+    fn take(stream: &PartStream) -> Option<Box<dyn Read + Send>> {
+        lock_unpoisoned(stream).take()
+    }
+
+    // The value for the Content-Type header and the encoded body,
+    // which streams if any of the parts does
+    pub fn encode(&self) -> Result<(String, EncodedBody), Error> {
+        let boundary = self.boundary();
+        let mut readers: Vec<Box<dyn Read + Send>> = vec![];
+        let mut buf = vec![];
+        for part in &self.parts {
+            buf.extend_from_slice("--".as_bytes());
+            buf.extend_from_slice(boundary.as_bytes());
+            buf.extend_from_slice(CRLF);
+            part.write_head(&mut buf);
+            match &part.body {
+                PartBody::Bytes(body) => buf.extend_from_slice(body),
+                PartBody::Stream(stream) => match Self::take(stream) {
+                    Some(r) => {
+                        readers.push(Box::new(Cursor::new(std::mem::take(&mut buf))));
+                        readers.push(r);
+                    }
+                    None => {
+                        return Err(Error::other(format!(
+                            "the part {:?} was already sent",
+                            part.name
+                        )))
+                    }
+                },
+            }
+            buf.extend_from_slice(CRLF);
+        }
+        buf.extend_from_slice("--".as_bytes());
+        buf.extend_from_slice(boundary.as_bytes());
+        buf.extend_from_slice("--".as_bytes());
+        buf.extend_from_slice(CRLF);
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        if readers.is_empty() {
+            return Ok((content_type, EncodedBody::Bytes(buf)));
+        }
+        readers.push(Box::new(Cursor::new(buf)));
+        let body = readers
+            .into_iter()
+            .reduce(|a, b| Box::new(a.chain(b)))
+            .expect("the closing boundary is always there");
+        Ok((content_type, EncodedBody::Stream(body)))
+    }
+}
+
+// A form as it's sent, see `Multipart::encode`
+pub enum EncodedBody {
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::client::Methods;
+    use crate::https::mock::{Mock, Route};
+    use crate::https::request::RequestBuilder;
+    use crate::https::url::Url;
+
+    const URL: &str = "https://discord.com/api/v10/channels/1/messages";
+
+    fn expected(boundary: &str) -> String {
+        format!(
+            "--{b}\r\n\
+             Content-Disposition: form-data; name=\"payload_json\"\r\n\r\n\
+             {{}}\r\n\
+             --{b}\r\n\
+             Content-Disposition: form-data; name=\"files[0]\"; filename=\"a%22b.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             hello\r\n\
+             --{b}--\r\n",
+            b = boundary
+        )
+    }
+
+    fn send(form: &Multipart) -> Mock {
+        let mut mock = Mock::new().route(Route::post(URL).unwrap());
+        let resp = RequestBuilder::new(Url::new(URL).unwrap())
+            .http_method(Methods::POST)
+            .multipart(form)
+            .execute(&mut mock)
+            .unwrap();
+        assert_eq!(resp.status_code, 200);
+        mock
+    }
+
+    fn boundary(content_type: &str) -> &str {
+        content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap()
+    }
+
+    #[test]
+    fn bytes_parts_are_sent_with_a_length() {
+        let form = Multipart::new().text("payload_json", "{}").part(
+            Part::bytes("files[0]", b"hello".to_vec())
+                .file_name("a\"b.txt")
+                .content_type("text/plain"),
+        );
+        let req = &send(&form).requests()[0];
+        let content_type = req.headers.get("Content-Type").unwrap();
+        assert_eq!(req.body_str(), expected(boundary(content_type)));
+        let length = req.body.len().to_string();
+        assert_eq!(req.headers.get("Content-Length"), Some(length.as_str()));
+        assert!(!req.headers.contains("Transfer-Encoding"));
+        // bytes can be sent again
+        send(&form);
+    }
+
+    #[test]
+    fn reader_parts_are_streamed() {
+        let form = Multipart::new().text("payload_json", "{}").part(
+            Part::reader("files[0]", Cursor::new(b"hello".to_vec()))
+                .file_name("a\"b.txt")
+                .content_type("text/plain"),
+        );
+        let req = &send(&form).requests()[0];
+        let content_type = req.headers.get("Content-Type").unwrap();
+        assert_eq!(req.body_str(), expected(boundary(content_type)));
+        assert_eq!(req.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert!(!req.headers.contains("Content-Length"));
+
+        // the reader was used up by the first request
+        let err = form.encode().err().unwrap();
+        assert!(err.to_string().contains("files[0]"));
+        let mut mock = Mock::new();
+        let res = RequestBuilder::new(Url::new(URL).unwrap())
+            .http_method(Methods::POST)
+            .multipart(&form)
+            .execute(&mut mock);
+        assert!(res.is_err());
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn boundaries_differ_and_avoid_the_parts() {
+        let form = Multipart::new().text("a", "b");
+        let (first, _) = form.encode().unwrap();
+        let (second, _) = form.encode().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(boundary(&first).len(), 24 + 32);
+    }
+}
@@ -1,58 +1,343 @@
+use super::body::StreamingResponse;
 use super::client::Methods;
-use super::request::RequestBuilder;
+use super::error::{HttpError, HttpResult};
+use super::headers::HeaderMap;
+use super::middleware::Middleware;
+use super::pool::{ConnectionPool, PooledConnection};
+use super::redirect::{same_origin, RedirectPolicy};
+use super::request::{Message, RequestBuilder};
+use super::response::Response;
+use super::retry::RetryPolicy;
+use super::timeout::Timeouts;
+use super::transport::Connector;
 use super::url::{Url, UrlError};
 use crate::https::canbeclient::CanBeClient;
-use crate::tls::tls_stream::TlsStream;
-use std::collections::HashMap;
-use std::io::{Error, Read, Write};
-
-type TLSResult<T> = Result<T, Error>;
-type HeaderMap<'a> = HashMap<&'a str, String>;
-pub struct PersistentClient<'p> {
-    io: TlsStream,
-    head: HeaderMap<'p>,
+use log::{debug, info};
+use std::io::{Error, ErrorKind, Write};
+use std::sync::Arc;
+
+// A client that keeps one connection to a single host checked out of the pool
+// and reuses it for every request, re-dialing when it's gone.
+// The connection goes back to the pool when the client is dropped.
+// Until then it takes one of the host's slots in the pool (4 by default),
+// so creating more clients than that for a host blocks until one is dropped.
+pub struct PersistentClient {
+    url: Url,
+    pool: ConnectionPool,
+    connector: Connector,
+    middleware: Vec<Arc<dyn Middleware>>,
+    io: Option<PooledConnection>,
+    head: HeaderMap,
+    redirect: RedirectPolicy,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
 }
 
-impl<'p> PersistentClient<'p> {
-    pub fn new(a: &'p str, url: &'p str) -> TLSResult<Self> {
-        let p_url = Url::new(url).unwrap();
+impl PersistentClient {
+    pub fn new(a: &str, url: &str) -> HttpResult<Self> {
+        Self::with_pool(a, url, ConnectionPool::global().clone())
+    }
 
-        Ok(Self {
-            io: TlsStream::new(None, p_url.domain(), &p_url.socket_addr())?,
-            head: HashMap::from_iter(vec![("User-Agent", a.to_string())]),
-        })
+    pub fn with_pool(a: &str, url: &str, pool: ConnectionPool) -> HttpResult<Self> {
+        Self::with_connector(a, url, pool, Connector::from_env())
     }
 
-    pub fn default_headers(&mut self, headers: HashMap<&'p str, String>) {
-        for (k, v) in headers.into_iter() {
-            self.head.insert(k, v);
+    // Connects through the connector's proxy instead of the environment's
+    pub fn with_connector(
+        a: &str,
+        url: &str,
+        pool: ConnectionPool,
+        connector: Connector,
+    ) -> HttpResult<Self> {
+        let p_url = Url::new(url)?;
+        let mut head = HeaderMap::new();
+        head.insert("User-Agent", a)?;
+
+        let mut client = Self {
+            url: p_url,
+            pool,
+            connector,
+            middleware: Vec::new(),
+            io: None,
+            head,
+            redirect: RedirectPolicy::new(),
+            retry: RetryPolicy::new(),
+            timeouts: Timeouts::new(),
+        };
+        let timeouts = client.timeouts.started();
+        client.connect(&timeouts)?;
+        Ok(client)
+    }
+
+    pub fn default_headers(&mut self, headers: &HeaderMap) {
+        self.head.extend(headers);
+    }
+
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.redirect = policy;
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    // Used for requests which don't set their own
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // Replaces the proxy settings, the connection is re-dialed on the next request
+    pub fn set_connector(&mut self, connector: Connector) {
+        self.io = None;
+        self.connector = connector;
+    }
+
+    // Adds a layer after the ones added before
+    pub fn add_middleware(&mut self, layer: impl Middleware + 'static) {
+        self.middleware.push(Arc::new(layer));
+    }
+
+    pub fn connector(&self) -> &Connector {
+        &self.connector
+    }
+
+    // The host this client keeps a connection to
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn connect(&mut self, timeouts: &Timeouts) -> HttpResult<()> {
+        self.io = None;
+        let io = self
+            .pool
+            .checkout(&self.url, &self.connector, timeouts)
+            .map_err(HttpError::connect)?;
+        self.io = Some(io);
+        Ok(())
+    }
+
+    // Re-dials if the server has closed the connection meanwhile
+    fn ensure_connected(&mut self, timeouts: &Timeouts) -> HttpResult<()> {
+        let alive = match &mut self.io {
+            // leftover bytes mean the last response wasn't framed properly
+            Some(io) => io.buffer().is_empty() && !io.get_mut().is_closed(),
+            None => false,
+        };
+        match &mut self.io {
+            Some(io) if alive => io.set_timeouts(timeouts),
+            _ => {
+                info!("Connecting to {}", self.url.socket_addr());
+                self.connect(timeouts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> HttpResult<()> {
+        match &mut self.io {
+            Some(io) => Ok(io.get_mut().write_all(buf)?),
+            None => Err(HttpError::Io(Error::from(ErrorKind::NotConnected))),
         }
     }
 
-    pub fn io_write(&mut self, buf: &[u8]) -> TLSResult<usize> {
-        self.io.write(buf)
+    pub fn is_connected(&self) -> bool {
+        self.io.is_some()
+    }
+
+    pub fn io_write(&mut self, buf: &[u8]) -> HttpResult<()> {
+        let timeouts = self.timeouts.started();
+        self.ensure_connected(&timeouts)?;
+        self.write_all(buf)
     }
 
-    pub fn io_read(&mut self) -> TLSResult<Vec<u8>> {
-        let mut buf = vec![];
-        self.io.read_to_end(&mut buf)?;
-        Ok(buf)
+    // Reads one response, leaving the connection ready for the next one
+    pub fn read_response(&mut self, method: &Methods) -> HttpResult<Response> {
+        let io = match &mut self.io {
+            Some(io) => io,
+            None => return Err(HttpError::Io(Error::from(ErrorKind::NotConnected))),
+        };
+        let res = Response::read_from(&mut **io, method);
+        match &res {
+            Ok(resp) if resp.keep_alive() => {}
+            _ => {
+                debug!("Connection can't be reused, closing it");
+                self.io = None;
+            }
+        }
+        res
+    }
+
+    // Sends a request and reads the response.
+    // When a reused connection turns out to be dead, idempotent requests
+    // are sent once more on a fresh connection, unless their body was streamed.
+    pub fn send(
+        &mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let reused = self.prepare(timeouts)?;
+        match self.exchange(msg, method, timeouts) {
+            Err(ref e) if Self::resend(reused, msg, method, e) => {
+                info!("Connection was dropped by the server, retrying on a new one");
+                self.connect(timeouts)?;
+                self.exchange(msg, method, timeouts)
+            }
+            res => res,
+        }
     }
 
-    pub fn get(&'p mut self, url: &'p str) -> Result<RequestBuilder<'p>, UrlError> {
+    // Like `send`, but only the head of the response is read.
+    // The body takes the connection with it and gives it back to the pool
+    // once it's read, the next request checks it out again.
+    pub fn send_streaming(
+        &mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        let reused = self.prepare(timeouts)?;
+        match self.exchange_streaming(msg, method, timeouts) {
+            Err(ref e) if Self::resend(reused, msg, method, e) => {
+                info!("Connection was dropped by the server, retrying on a new one");
+                self.connect(timeouts)?;
+                self.exchange_streaming(msg, method, timeouts)
+            }
+            res => res,
+        }
+    }
+
+    // Connects if needed, and tells if the connection was used before
+    fn prepare(&mut self, timeouts: &Timeouts) -> HttpResult<bool> {
+        let had_connection = self.io.is_some();
+        self.ensure_connected(timeouts)?;
+        Ok(had_connection || self.io.as_ref().is_some_and(|io| io.is_reused()))
+    }
+
+    fn resend(reused: bool, msg: &Message, method: &Methods, e: &HttpError) -> bool {
+        reused && method.is_idempotent() && msg.is_replayable() && e.is_dead_connection()
+    }
+
+    // Writes the request and reads the whole response.
+    // The connection is dropped when it can't be used for another request.
+    fn exchange(
+        &mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let io = match &mut self.io {
+            Some(io) => io,
+            None => return Err(HttpError::Io(Error::from(ErrorKind::NotConnected))),
+        };
+        let res = msg.write_to(io, timeouts).and_then(|early| {
+            // a server that answered before getting the body won't read it anymore
+            let reusable = early.is_none();
+            let head = match early {
+                Some(head) => head,
+                None => Response::read_final_head(&mut **io)?,
+            };
+            Ok((Response::read_rest(&mut **io, method, head)?, reusable))
+        });
+        match res {
+            Ok((resp, true)) if resp.keep_alive() => Ok(resp),
+            res => {
+                debug!("Connection can't be reused, closing it");
+                self.io = None;
+                res.map(|(resp, _)| resp)
+            }
+        }
+    }
+
+    fn exchange_streaming(
+        &mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        let mut io = match self.io.take() {
+            Some(io) => io,
+            None => return Err(HttpError::Io(Error::from(ErrorKind::NotConnected))),
+        };
+        let early = msg.write_to(&mut io, timeouts)?;
+        let reusable = early.is_none();
+        let head = match early {
+            Some(head) => head,
+            None => Response::read_final_head(&mut *io)?,
+        };
+        StreamingResponse::from_head(io, method, head, reusable)
+    }
+
+    pub fn get<'a>(&mut self, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
         self.request(Methods::GET, url)
     }
 
-    pub fn post(&'p mut self, url: &'p str) -> Result<RequestBuilder<'p>, UrlError> {
+    pub fn post<'a>(&mut self, url: &str) -> Result<RequestBuilder<'a>, UrlError> {
         self.request(Methods::POST, url)
     }
 }
 
-impl CanBeClient<'_> for PersistentClient<'_> {
-    fn request<'a>(&'a mut self, m: Methods, url: &'a str) -> Result<RequestBuilder<'a>, UrlError> {
-        let p_url = Url::new(url)?;
-        let req = RequestBuilder::new(p_url);
+impl Drop for PersistentClient {
+    fn drop(&mut self) {
+        if let Some(io) = self.io.take() {
+            io.release();
+        }
+    }
+}
 
-        Ok(req.http_method(m).headers(&self.head))
+// Requests to the client's host use its connection,
+// hops to other hosts go through the pool
+impl CanBeClient for PersistentClient {
+    fn pool(&self) -> &ConnectionPool {
+        &self.pool
+    }
+
+    fn headers(&self) -> Option<&HeaderMap> {
+        Some(&self.head)
+    }
+
+    fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    fn redirect_policy(&self) -> RedirectPolicy {
+        self.redirect.clone()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry.clone()
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    fn send_message(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        if same_origin(url, &self.url) {
+            self.send(msg, method, timeouts)
+        } else {
+            self.pool.send(url, msg, method, &self.connector, timeouts)
+        }
+    }
+
+    fn send_message_streaming(
+        &mut self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        if same_origin(url, &self.url) {
+            self.send_streaming(msg, method, timeouts)
+        } else {
+            self.pool
+                .send_streaming(url, msg, method, &self.connector, timeouts)
+        }
     }
 }
@@ -0,0 +1,546 @@
+use super::body::StreamingResponse;
+use super::client::Methods;
+use super::error::{HttpError, HttpResult};
+use super::request::Message;
+use super::response::Response;
+use super::timeout;
+use super::timeout::{TimeoutKind, Timeouts};
+use super::transport::{Connector, Transport};
+use super::url::Url;
+use super::util::lock_unpoisoned;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::io::{BufReader, Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Connections are pooled per (scheme, host, port),
+// so that every client talking to the same server shares them.
+
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_MAX_PER_HOST: usize = 4;
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type Connection = BufReader<Box<dyn Transport>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    scheme: String,
+    host: String,
+    port: u16,
+    // the proxy or dialer the connection goes through
+    via: Option<String>,
+}
+
+impl PoolKey {
+    pub fn from_url(url: &Url, connector: &Connector) -> Self {
+        Self {
+            scheme: url.scheme().to_string(),
+            host: url.domain().to_string(),
+            port: url.port(),
+            via: connector.route_for(url.scheme(), url.domain()),
+        }
+    }
+
+    fn same_origin(&self, url: &Url) -> bool {
+        self.scheme == url.scheme() && self.host == url.domain() && self.port == url.port()
+    }
+}
+
+struct IdleConnection {
+    conn: Connection,
+    since: Instant,
+}
+
+// `open` counts both idle and checked out connections
+#[derive(Default)]
+struct HostState {
+    idle: Vec<IdleConnection>,
+    open: usize,
+}
+
+struct PoolInner {
+    hosts: Mutex<HashMap<PoolKey, HostState>>,
+    available: Condvar,
+    idle_timeout: Duration,
+    max_per_host: usize,
+    checkout_timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<PoolInner>,
+}
+
+static GLOBAL_POOL: OnceLock<ConnectionPool> = OnceLock::new();
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_PER_HOST)
+    }
+
+    pub fn with_limits(idle_timeout: Duration, max_per_host: usize) -> Self {
+        Self::with_checkout_timeout(idle_timeout, max_per_host, DEFAULT_CHECKOUT_TIMEOUT)
+    }
+
+    // `checkout_timeout` is how long a checkout waits for a host at its limit
+    pub fn with_checkout_timeout(
+        idle_timeout: Duration,
+        max_per_host: usize,
+        checkout_timeout: Duration,
+    ) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                hosts: Mutex::new(HashMap::new()),
+                available: Condvar::new(),
+                idle_timeout,
+                max_per_host: max_per_host.max(1),
+                checkout_timeout,
+            }),
+        }
+    }
+
+    // The pool used by clients which weren't given one
+    pub fn global() -> &'static ConnectionPool {
+        GLOBAL_POOL.get_or_init(ConnectionPool::new)
+    }
+
+    // Takes an idle connection to the url's host, or opens a new one.
+    // Blocks while the host is at its connection limit.
+    // The connection is set up to read and write with the given timeouts.
+    pub fn checkout(
+        &self,
+        url: &Url,
+        connector: &Connector,
+        timeouts: &Timeouts,
+    ) -> Result<PooledConnection, Error> {
+        let key = PoolKey::from_url(url, connector);
+        let mut deadline = Instant::now() + self.inner.checkout_timeout;
+        if let Some(d) = timeouts.deadline() {
+            deadline = deadline.min(d);
+        }
+        let mut hosts = lock_unpoisoned(&self.inner.hosts);
+
+        loop {
+            self.remove_expired(&mut hosts);
+            let state = hosts.entry(key.clone()).or_default();
+
+            while let Some(mut idle) = state.idle.pop() {
+                if idle.conn.buffer().is_empty() && !idle.conn.get_mut().is_closed() {
+                    debug!("Reusing pooled connection to {}", url.socket_addr());
+                    idle.conn.get_mut().set_timeouts(timeouts);
+                    return Ok(PooledConnection::new(self, key, idle.conn, true));
+                }
+                state.open -= 1;
+            }
+
+            if state.open < self.inner.max_per_host {
+                state.open += 1;
+                drop(hosts);
+                return match self.dial(url, connector, timeouts) {
+                    Ok(conn) => Ok(PooledConnection::new(self, key, conn, false)),
+                    Err(e) => {
+                        self.forget(&key);
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if timeouts.is_expired() {
+                return Err(timeout::error(TimeoutKind::Total));
+            }
+            if now >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "no pooled connection became available",
+                ));
+            }
+            debug!(
+                "Connection limit reached for {}, waiting",
+                url.socket_addr()
+            );
+            hosts = match self.inner.available.wait_timeout(hosts, deadline - now) {
+                Ok((g, _)) => g,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    fn dial(
+        &self,
+        url: &Url,
+        connector: &Connector,
+        timeouts: &Timeouts,
+    ) -> Result<Connection, Error> {
+        info!("Opening a connection to {}", url.socket_addr());
+        Ok(BufReader::new(connector.connect(url, timeouts)?))
+    }
+
+    fn remove_expired(&self, hosts: &mut HashMap<PoolKey, HostState>) {
+        let timeout = self.inner.idle_timeout;
+        for state in hosts.values_mut() {
+            let before = state.idle.len();
+            state.idle.retain(|i| i.since.elapsed() < timeout);
+            state.open -= before - state.idle.len();
+        }
+        hosts.retain(|_, state| state.open > 0);
+    }
+
+    fn release(&self, key: PoolKey, conn: Connection) {
+        let mut hosts = lock_unpoisoned(&self.inner.hosts);
+        hosts.entry(key).or_default().idle.push(IdleConnection {
+            conn,
+            since: Instant::now(),
+        });
+        self.inner.available.notify_one();
+    }
+
+    fn forget(&self, key: &PoolKey) {
+        let mut hosts = lock_unpoisoned(&self.inner.hosts);
+        if let Some(state) = hosts.get_mut(key) {
+            state.open = state.open.saturating_sub(1);
+        }
+        self.inner.available.notify_one();
+    }
+
+    // Number of idle connections kept for the url's host, proxied or not
+    pub fn idle_count(&self, url: &Url) -> usize {
+        lock_unpoisoned(&self.inner.hosts)
+            .iter()
+            .filter(|(k, _)| k.same_origin(url))
+            .map(|(_, s)| s.idle.len())
+            .sum()
+    }
+
+    // Sends a request over a pooled connection and reads the response.
+    // The connection goes back to the pool if the server allows it.
+    // When a reused connection turns out to be dead, idempotent requests
+    // are sent once more on a fresh connection, unless their body was streamed.
+    pub fn send(
+        &self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        connector: &Connector,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        self.send_with(url, msg, method, connector, timeouts, |conn, msg| {
+            conn.send(msg, method, timeouts)
+        })
+    }
+
+    // Like `send`, but only the head of the response is read.
+    // The connection goes back to the pool once the body has been read.
+    pub fn send_streaming(
+        &self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        connector: &Connector,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        self.send_with(url, msg, method, connector, timeouts, |conn, msg| {
+            conn.send_streaming(msg, method, timeouts)
+        })
+    }
+
+    fn send_with<T, F>(
+        &self,
+        url: &Url,
+        msg: &mut Message,
+        method: &Methods,
+        connector: &Connector,
+        timeouts: &Timeouts,
+        send: F,
+    ) -> HttpResult<T>
+    where
+        F: Fn(PooledConnection, &mut Message) -> HttpResult<T>,
+    {
+        let conn = self
+            .checkout(url, connector, timeouts)
+            .map_err(HttpError::connect)?;
+        let reused = conn.is_reused();
+        match send(conn, msg) {
+            Err(ref e)
+                if reused
+                    && method.is_idempotent()
+                    && msg.is_replayable()
+                    && e.is_dead_connection() =>
+            {
+                info!("Pooled connection was dropped by the server, retrying on a new one");
+                let conn = self
+                    .checkout(url, connector, timeouts)
+                    .map_err(HttpError::connect)?;
+                send(conn, msg)
+            }
+            res => res,
+        }
+    }
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A connection taken out of the pool.
+// It's only given back by `release`, dropping it closes the connection.
+pub struct PooledConnection {
+    pool: ConnectionPool,
+    key: PoolKey,
+    conn: Option<Connection>,
+    reused: bool,
+}
+
+impl PooledConnection {
+    fn new(pool: &ConnectionPool, key: PoolKey, conn: Connection, reused: bool) -> Self {
+        Self {
+            pool: pool.clone(),
+            key,
+            conn: Some(conn),
+            reused,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.get_mut().set_timeouts(timeouts);
+    }
+
+    // If the connection was used for an earlier request
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    pub fn release(mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.release(self.key.clone(), conn);
+        }
+    }
+
+    // Writes the request and reads the response,
+    // giving the connection back to the pool when it's reusable
+    pub fn send(
+        mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Response> {
+        let early = msg.write_to(&mut self, timeouts)?;
+        // a server that answered before getting the body won't read it anymore
+        let reusable = early.is_none();
+        let head = match early {
+            Some(head) => head,
+            None => Response::read_final_head(&mut *self)?,
+        };
+        let resp = Response::read_rest(&mut *self, method, head)?;
+        if resp.keep_alive() && reusable {
+            self.release();
+        }
+        Ok(resp)
+    }
+
+    // Writes the request and reads the head of the response,
+    // the body keeps the connection until it has been read
+    pub fn send_streaming(
+        mut self,
+        msg: &mut Message,
+        method: &Methods,
+        timeouts: &Timeouts,
+    ) -> HttpResult<StreamingResponse> {
+        let early = msg.write_to(&mut self, timeouts)?;
+        let reusable = early.is_none();
+        let head = match early {
+            Some(head) => head,
+            None => Response::read_final_head(&mut *self)?,
+        };
+        StreamingResponse::from_head(self, method, head, reusable)
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match &self.conn {
+            Some(c) => c,
+            None => unreachable!("connection used after being released"),
+        }
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match &mut self.conn {
+            Some(c) => c,
+            None => unreachable!("connection used after being released"),
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if self.conn.take().is_some() {
+            debug!("Closing connection to {}", self.key.host);
+            self.pool.forget(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::client::HttpsClient;
+    use crate::https::mock::{Mock, Route};
+    use crate::https::persistent_client::PersistentClient;
+    use crate::https::request::RequestBuilder;
+    use crate::https::retry::RetryPolicy;
+    use std::thread;
+
+    const URL: &str = "https://pool.test/";
+
+    fn get(pool: &ConnectionPool, mock: &Mock, method: Methods) -> HttpResult<Response> {
+        let url = Url::new(URL).unwrap();
+        let req = RequestBuilder::new(url.clone()).http_method(method);
+        let mut msg = req.message()?;
+        let connector = Connector::new().dialer(mock.clone());
+        pool.send(&url, &mut msg, &method, &connector, &Timeouts::new())
+    }
+
+    fn checkout(pool: &ConnectionPool, mock: &Mock) -> Result<PooledConnection, Error> {
+        let url = Url::new(URL).unwrap();
+        let connector = Connector::new().dialer(mock.clone());
+        pool.checkout(&url, &connector, &Timeouts::new())
+    }
+
+    #[test]
+    fn reuses_idle_connections() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let pool = ConnectionPool::new();
+        for _ in 0..3 {
+            get(&pool, &mock, Methods::GET).unwrap();
+        }
+        assert_eq!(mock.connections(), 1);
+        assert_eq!(pool.idle_count(&Url::new(URL).unwrap()), 1);
+    }
+
+    #[test]
+    fn idle_connections_expire() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let pool = ConnectionPool::with_limits(Duration::from_millis(50), 4);
+        get(&pool, &mock, Methods::GET).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        get(&pool, &mock, Methods::GET).unwrap();
+        assert_eq!(mock.connections(), 2);
+        assert_eq!(pool.idle_count(&Url::new(URL).unwrap()), 1);
+    }
+
+    #[test]
+    fn waits_for_a_free_slot() {
+        let mock = Mock::new();
+        let pool =
+            ConnectionPool::with_checkout_timeout(DEFAULT_IDLE_TIMEOUT, 1, Duration::from_secs(5));
+        let held = checkout(&pool, &mock).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            held.release();
+        });
+
+        let start = Instant::now();
+        let conn = checkout(&pool, &mock).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(conn.is_reused());
+        assert_eq!(mock.connections(), 1);
+        releaser.join().unwrap();
+
+        // a dropped connection frees its slot too
+        drop(conn);
+        assert!(!checkout(&pool, &mock).unwrap().is_reused());
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[test]
+    fn gives_up_waiting_for_a_slot() {
+        let mock = Mock::new();
+        let pool = ConnectionPool::with_checkout_timeout(
+            DEFAULT_IDLE_TIMEOUT,
+            1,
+            Duration::from_millis(100),
+        );
+        let _held = checkout(&pool, &mock).unwrap();
+        let err = checkout(&pool, &mock).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "no pooled connection became available");
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[test]
+    fn persistent_clients_hold_a_slot() {
+        let mock = Mock::new();
+        let pool = ConnectionPool::with_checkout_timeout(
+            DEFAULT_IDLE_TIMEOUT,
+            1,
+            Duration::from_millis(100),
+        );
+        let connector = Connector::new().dialer(mock.clone());
+        let client = PersistentClient::with_connector("test", URL, pool.clone(), connector);
+        let client = client.unwrap();
+        assert!(checkout(&pool, &mock).is_err());
+        drop(client);
+        assert!(checkout(&pool, &mock).unwrap().is_reused());
+    }
+
+    #[test]
+    fn clients_share_a_pool() {
+        let mock = Mock::new().route(Route::get(URL).unwrap());
+        let pool = ConnectionPool::new();
+        let mut clients: Vec<_> = (0..2)
+            .map(|_| {
+                let mut c = HttpsClient::with_pool("test", None, pool.clone()).unwrap();
+                c.set_connector(Connector::new().dialer(mock.clone()));
+                c.set_retry_policy(RetryPolicy::none());
+                c
+            })
+            .collect();
+        for c in &mut clients {
+            c.get(URL, None).unwrap();
+        }
+        assert_eq!(mock.connections(), 1);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn resends_on_a_dead_connection_once() {
+        let mock = Mock::new()
+            .route(Route::get(URL).unwrap().times(1))
+            .route(Route::get(URL).unwrap().hang_up().times(1))
+            .route(Route::get(URL).unwrap().body("fresh"));
+        let pool = ConnectionPool::new();
+        get(&pool, &mock, Methods::GET).unwrap();
+        let resp = get(&pool, &mock, Methods::GET).unwrap();
+        assert_eq!(&resp.content[..], b"fresh");
+        assert_eq!(mock.connections(), 2);
+        assert_eq!(mock.requests().len(), 3);
+
+        // a fresh connection that dies isn't resent
+        let mock = Mock::new().route(Route::get(URL).unwrap().hang_up());
+        let err = get(&ConnectionPool::new(), &mock, Methods::GET).unwrap_err();
+        assert!(err.is_dead_connection());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn posts_arent_resent() {
+        let mock = Mock::new()
+            .route(Route::get(URL).unwrap())
+            .route(Route::post(URL).unwrap().hang_up());
+        let pool = ConnectionPool::new();
+        get(&pool, &mock, Methods::GET).unwrap();
+        let err = get(&pool, &mock, Methods::POST).unwrap_err();
+        assert!(err.is_dead_connection());
+        assert_eq!(mock.connections(), 1);
+        assert_eq!(mock.requests().len(), 2);
+    }
+}
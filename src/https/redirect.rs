@@ -0,0 +1,333 @@
+use super::body::StreamingResponse;
+use super::client::Methods;
+use super::error::{HttpResult, RedirectError};
+use super::request::RequestBuilder;
+use super::response::Response;
+use super::url::Url;
+use log::{debug, info};
+
+const DEFAULT_MAX_HOPS: usize = 10;
+
+// Decides which 3xx responses are followed and how
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+    max_hops: usize,
+    same_host_only: bool,
+}
+
+impl RedirectPolicy {
+    pub fn new() -> Self {
+        Self {
+            max_hops: DEFAULT_MAX_HOPS,
+            same_host_only: false,
+        }
+    }
+
+    // Never follow redirects, the 3xx response is returned as is
+    pub fn none() -> Self {
+        Self {
+            max_hops: 0,
+            same_host_only: false,
+        }
+    }
+
+    pub fn max_hops(mut self, n: usize) -> Self {
+        self.max_hops = n;
+        self
+    }
+
+    pub fn same_host_only(mut self, b: bool) -> Self {
+        self.same_host_only = b;
+        self
+    }
+
+    // The request to send next, if `resp` is a redirect that should be followed.
+    // `hops` is the number of redirects followed so far.
+    pub fn next_request<'a>(
+        &self,
+        req: &RequestBuilder<'a>,
+        resp: &Response,
+        hops: usize,
+    ) -> HttpResult<Option<RequestBuilder<'a>>> {
+        if !matches!(resp.status_code, 301 | 302 | 303 | 307 | 308) || self.max_hops == 0 {
+            return Ok(None);
+        }
+        let location = match resp.headers.location() {
+            Some(l) => l,
+            None => {
+                debug!("{} without a Location header", resp.status_code);
+                return Ok(None);
+            }
+        };
+        if hops >= self.max_hops {
+            return Err(RedirectError::TooMany(hops).into());
+        }
+
+        // a streamed body is gone, only redirects that drop it can be followed
+        let keeps_body = match resp.status_code {
+            303 => false,
+            301 | 302 => !matches!(req.method(), Methods::POST),
+            _ => true,
+        };
+        if keeps_body && !req.is_replayable() {
+            info!(
+                "Not following {}, the streamed body can't be sent again",
+                resp.status_code
+            );
+            return Ok(None);
+        }
+
+        let target = req
+            .url()
+            .join(location)
+            .map_err(RedirectError::InvalidLocation)?;
+        let cross_host = !same_origin(req.url(), &target);
+        if cross_host && self.same_host_only {
+            return Err(RedirectError::NotAllowed(target.to_string()).into());
+        }
+        info!("Following {} redirect to {}", resp.status_code, target);
+
+        let mut next = req.clone().redirect_to(target);
+        if cross_host {
+            next = next.remove_header("Authorization");
+        }
+
+        // 303 always turns into a GET, and so do POSTs redirected with 301/302,
+        // like every browser does. 307 and 308 keep the method and body.
+        let to_get = match resp.status_code {
+            303 => !matches!(req.method(), Methods::HEAD),
+            301 | 302 => matches!(req.method(), Methods::POST),
+            _ => false,
+        };
+        if to_get {
+            next = next
+                .http_method(Methods::GET)
+                .without_content()
+                .remove_header("Content-Type");
+        }
+
+        Ok(Some(next))
+    }
+
+    // Sends `req` using `send`, following redirects as the policy allows
+    pub fn follow<'a, F>(&self, req: RequestBuilder<'a>, mut send: F) -> HttpResult<Response>
+    where
+        F: FnMut(&RequestBuilder<'a>) -> HttpResult<Response>,
+    {
+        let mut req = req;
+        let mut hops = 0;
+        loop {
+            let resp = send(&req)?;
+            match self.next_request(&req, &resp, hops)? {
+                Some(next) => {
+                    req = next;
+                    hops += 1;
+                }
+                None => return Ok(resp),
+            }
+        }
+    }
+
+    // Like `follow`, for responses whose body is read as it arrives.
+    // The bodies of redirects are skipped, so their connections can be reused.
+    pub fn follow_streaming<'a, F>(
+        &self,
+        req: RequestBuilder<'a>,
+        mut send: F,
+    ) -> HttpResult<StreamingResponse>
+    where
+        F: FnMut(&RequestBuilder<'a>) -> HttpResult<StreamingResponse>,
+    {
+        let mut req = req;
+        let mut hops = 0;
+        loop {
+            let resp = send(&req)?;
+            match self.next_request(&req, &resp.head(), hops)? {
+                Some(next) => {
+                    resp.discard();
+                    req = next;
+                    hops += 1;
+                }
+                None => return Ok(resp),
+            }
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Same scheme, host and port
+pub fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme() && a.domain() == b.domain() && a.port() == b.port()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::error::HttpError;
+    use crate::https::mock::{Mock, Route};
+
+    fn redirect(from: &str, status: u16, location: &str) -> Route {
+        Route::new(Methods::GET, from)
+            .unwrap()
+            .status(status)
+            .header("Location", location)
+    }
+
+    fn request(method: Methods, url: &str) -> RequestBuilder<'static> {
+        RequestBuilder::new(Url::new(url).unwrap()).http_method(method)
+    }
+
+    fn post(url: &str) -> RequestBuilder<'static> {
+        request(Methods::POST, url)
+            .header(("Content-Type", "text/plain"))
+            .content(b"payload")
+    }
+
+    #[test]
+    fn gives_up_after_max_hops() {
+        let mut mock = Mock::new().route(redirect("https://a/loop", 302, "/loop"));
+        let err = request(Methods::GET, "https://a/loop")
+            .redirect(RedirectPolicy::new().max_hops(3))
+            .execute(&mut mock)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HttpError::Redirect(RedirectError::TooMany(3))
+        ));
+        assert_eq!(mock.requests().len(), 4);
+
+        // without hops the redirect itself comes back
+        let resp = request(Methods::GET, "https://a/loop")
+            .redirect(RedirectPolicy::none())
+            .execute(&mut mock)
+            .unwrap();
+        assert_eq!(resp.status_code, 302);
+    }
+
+    #[test]
+    fn same_host_only() {
+        let mut mock = Mock::new()
+            .route(redirect("https://a/x", 302, "/y"))
+            .route(Route::get("https://a/y").unwrap().body("y"))
+            .route(redirect("https://a/away", 302, "https://b/z"));
+        let policy = RedirectPolicy::new().same_host_only(true);
+        let resp = request(Methods::GET, "https://a/x")
+            .redirect(policy.clone())
+            .execute(&mut mock)
+            .unwrap();
+        assert_eq!(&resp.content[..], b"y");
+
+        let err = request(Methods::GET, "https://a/away")
+            .redirect(policy)
+            .execute(&mut mock)
+            .unwrap_err();
+        assert!(
+            matches!(err, HttpError::Redirect(RedirectError::NotAllowed(ref u)) if u == "https://b/z")
+        );
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn authorization_stays_on_its_host() {
+        let mut mock = Mock::new()
+            .route(redirect("https://a/x", 302, "/y"))
+            .route(redirect("https://a/y", 307, "https://b/z"))
+            .route(Route::get("https://b/z").unwrap());
+        request(Methods::GET, "https://a/x")
+            .header(("Authorization", "Bot secret"))
+            .execute(&mut mock)
+            .unwrap();
+        let auth: Vec<_> = mock
+            .requests()
+            .iter()
+            .map(|r| r.headers.get("Authorization").map(str::to_string))
+            .collect();
+        assert_eq!(
+            auth,
+            [
+                Some("Bot secret".to_string()),
+                Some("Bot secret".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn see_other_becomes_a_get() {
+        for method in [Methods::POST, Methods::PUT, Methods::GET] {
+            let mut mock = Mock::new()
+                .route(
+                    Route::new(method, "https://a/form")
+                        .unwrap()
+                        .status(303)
+                        .header("Location", "/done"),
+                )
+                .route(Route::get("https://a/done").unwrap().body("done"));
+            let req = post("https://a/form").http_method(method);
+            assert_eq!(&req.execute(&mut mock).unwrap().content[..], b"done");
+            let done = &mock.requests()[1];
+            assert_eq!(done.method, Methods::GET);
+            assert!(done.body.is_empty());
+            assert_eq!(done.headers.get("Content-Type"), None);
+        }
+    }
+
+    #[test]
+    fn moved_posts_become_gets() {
+        for status in [301, 302] {
+            let mut mock = Mock::new()
+                .route(
+                    Route::post("https://a/old")
+                        .unwrap()
+                        .status(status)
+                        .header("Location", "/new"),
+                )
+                .route(Route::get("https://a/new").unwrap());
+            post("https://a/old").execute(&mut mock).unwrap();
+            let new = &mock.requests()[1];
+            assert_eq!(new.method, Methods::GET);
+            assert!(new.body.is_empty());
+        }
+    }
+
+    #[test]
+    fn temporary_and_permanent_keep_the_method() {
+        for status in [307, 308] {
+            let mut mock = Mock::new()
+                .route(
+                    Route::post("https://a/old")
+                        .unwrap()
+                        .status(status)
+                        .header("Location", "/new"),
+                )
+                .route(Route::post("https://a/new").unwrap().body("posted"));
+            let resp = post("https://a/old").execute(&mut mock).unwrap();
+            assert_eq!(&resp.content[..], b"posted");
+            let new = &mock.requests()[1];
+            assert_eq!(new.method, Methods::POST);
+            assert_eq!(new.body_str(), "payload");
+            assert_eq!(new.headers.get("Content-Type"), Some("text/plain"));
+        }
+    }
+
+    #[test]
+    fn relative_locations() {
+        let mut mock = Mock::new()
+            .route(redirect("https://a/x/y", 302, "../c?d=1"))
+            .route(redirect("https://a/c", 302, "//b/e"))
+            .route(Route::get("https://b/e").unwrap());
+        request(Methods::GET, "https://a/x/y?old=1")
+            .execute(&mut mock)
+            .unwrap();
+        let urls: Vec<String> = mock.requests().iter().map(|r| r.url.to_string()).collect();
+        assert_eq!(
+            urls,
+            ["https://a/x/y?old=1", "https://a/c?d=1", "https://b/e"]
+        );
+    }
+}
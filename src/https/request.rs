@@ -1,93 +1,697 @@
+use crate::https::async_client::AsyncPersistentClient;
+use crate::https::body::StreamingResponse;
+use crate::https::canbeclient::CanBeClient;
 use crate::https::client::Methods;
-use crate::https::persistent_client::PersistentClient;
+use crate::https::encoding::ACCEPT_ENCODING;
+use crate::https::error::{HttpResult, ProtocolError, RequestError};
+use crate::https::headers::HeaderMap;
+use crate::https::middleware;
+use crate::https::multipart::{EncodedBody, Multipart};
+use crate::https::pool::Connection;
+use crate::https::redirect::RedirectPolicy;
+use crate::https::response::{read_line, Head, Response};
+use crate::https::retry::RetryPolicy;
+use crate::https::timeout;
+use crate::https::timeout::{TimeoutKind, Timeouts};
 use crate::https::url::Url;
-use std::collections::HashMap;
-use std::error::Error;
+use crate::https::util::lock_unpoisoned;
+use log::debug;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 const CRLF: &[u8] = "\r\n".as_bytes();
 
+// size of the chunks a streamed body is sent in
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// how long to wait for 100 Continue before sending the body anyway
+const CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A body read while it's sent. Clones of the request share it,
+// whichever is sent first takes it.
+type BodyStream<'a> = Arc<Mutex<Option<Box<dyn Read + Send + 'a>>>>;
+
+#[derive(Clone)]
 pub struct RequestBuilder<'a> {
     method: Methods,
-    route: &'a str,
-    headers: HashMap<&'a str, &'a str>,
-    content: Option<&'a [u8]>,
-    content_len: usize,
-    host: &'a str,
+    url: Url,
+    headers: HeaderMap,
+    content: Option<Cow<'a, [u8]>>,
+    stream: Option<BodyStream<'a>>,
+    expect_continue: bool,
+    redirect: Option<RedirectPolicy>,
+    retry: Option<RetryPolicy>,
+    timeouts: Option<Timeouts>,
+    // first invalid header or body, reported when the request is built
+    error: Option<RequestError>,
 }
 
 impl<'a> RequestBuilder<'a> {
-    pub fn new(url: Url<'a>) -> Self {
+    pub fn new(url: Url) -> Self {
         Self {
             method: Methods::GET,
-            route: url.route(),
-            headers: HashMap::new(),
+            url,
+            headers: HeaderMap::new(),
             content: None,
-            content_len: 0,
-            host: url.domain(),
+            stream: None,
+            expect_continue: false,
+            redirect: None,
+            retry: None,
+            timeouts: None,
+            error: None,
         }
     }
     pub fn http_method(mut self, m: Methods) -> Self {
         self.method = m;
         self
     }
-    pub fn header(mut self, h: (&'a str, &'a str)) -> Self {
-        self.headers.insert(h.0, h.1);
+    pub fn header(mut self, h: (&str, &str)) -> Self {
+        if let Err(e) = self.headers.insert(h.0, h.1) {
+            self.error.get_or_insert(RequestError::Header(e));
+        }
         self
     }
-    pub fn headers(mut self, h: &'a HashMap<&str, String>) -> Self {
-        for (key, val) in h {
-            self.headers.insert(key, &val);
-        }
+    pub fn headers(mut self, h: &HeaderMap) -> Self {
+        self.headers.extend(h);
         self
     }
     pub fn content(mut self, c: &'a [u8]) -> Self {
-        self.content_len = c.len();
-        self.content = Some(c);
+        self.stream = None;
+        self.content = Some(Cow::Borrowed(c));
         self
     }
 
-    pub fn execute(self, exec: &'a mut PersistentClient<'a>) -> Result<Vec<u8>, std::io::Error> {
-        let mut buf = vec![];
+    // Sends the body as it's read from `r`, with chunked transfer encoding,
+    // so it doesn't have to fit in memory. It can only be sent once,
+    // so the request isn't retried and 307/308 redirects aren't followed.
+    pub fn body_reader(mut self, r: impl Read + Send + 'a) -> Self {
+        self.content = None;
+        self.stream = Some(Arc::new(Mutex::new(Some(Box::new(r)))));
+        self
+    }
+
+    // Asks the server with `Expect: 100-continue` if it wants the body,
+    // before sending it. Servers that don't answer within a second
+    // get the body anyway.
+    pub fn expect_continue(mut self, b: bool) -> Self {
+        self.expect_continue = b;
+        self
+    }
+
+    // Sends `form` as an application/x-www-form-urlencoded body,
+    // like &[("client_id", id), ("code", code)] or a struct
+    pub fn form(self, form: &impl Serialize) -> Self {
+        match serde_urlencoded::to_string(form) {
+            Ok(body) => self
+                .owned_content(body.into_bytes())
+                .header(("Content-Type", "application/x-www-form-urlencoded")),
+            Err(e) => self.body_error(e.to_string()),
+        }
+    }
+
+    // Sends `value` as a JSON body
+    pub fn json(self, value: &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => self
+                .owned_content(body)
+                .header(("Content-Type", "application/json")),
+            Err(e) => self.body_error(e.to_string()),
+        }
+    }
+
+    fn owned_content(mut self, c: Vec<u8>) -> Self {
+        self.stream = None;
+        self.content = Some(Cow::Owned(c));
+        self
+    }
+
+    fn body_error(mut self, e: String) -> Self {
+        self.error.get_or_insert(RequestError::Body(e));
+        self
+    }
+
+    // Sends the form as the body, with its Content-Type and boundary.
+    // Forms with `Part::reader` parts are streamed like `body_reader` bodies.
+    pub fn multipart(self, form: &Multipart) -> Self {
+        match form.encode() {
+            Ok((content_type, EncodedBody::Bytes(body))) => self
+                .owned_content(body)
+                .header(("Content-Type", &content_type)),
+            Ok((content_type, EncodedBody::Stream(body))) => self
+                .body_reader(body)
+                .header(("Content-Type", &content_type)),
+            Err(e) => self.body_error(e.to_string()),
+        }
+    }
+
+    // Overrides the client's redirect policy for this request
+    pub fn redirect(mut self, policy: RedirectPolicy) -> Self {
+        self.redirect = Some(policy);
+        self
+    }
+
+    // Overrides the client's retry policy for this request
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    // Overrides the client's timeouts for this request
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    pub(crate) fn redirect_to(mut self, url: Url) -> Self {
+        self.url = url;
+        self
+    }
+    pub(crate) fn without_content(mut self) -> Self {
+        self.content = None;
+        self.stream = None;
+        self
+    }
+    // Adds the headers the request doesn't set itself
+    pub(crate) fn default_headers(mut self, h: &HeaderMap) -> Self {
+        for (k, v) in h.iter() {
+            if !self.headers.contains(k) {
+                // already validated
+                let _ = self.headers.append(k, v);
+            }
+        }
+        self
+    }
+    pub(crate) fn remove_header(mut self, name: &str) -> Self {
+        self.headers.remove(name);
+        self
+    }
+
+    pub fn method(&self) -> &Methods {
+        &self.method
+    }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    pub fn header_map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    // If the request can be sent more than once, which a streamed body can't
+    pub fn is_replayable(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    // The request's own settings, or the client's
+    pub(crate) fn redirect_or(&self, default: &RedirectPolicy) -> RedirectPolicy {
+        self.redirect.clone().unwrap_or_else(|| default.clone())
+    }
+    // Requests with a streamed body are only sent once
+    pub(crate) fn retry_or(&self, default: &RetryPolicy) -> RetryPolicy {
+        if !self.is_replayable() {
+            return RetryPolicy::none();
+        }
+        self.retry.clone().unwrap_or_else(|| default.clone())
+    }
+    pub(crate) fn timeouts_or(&self, default: &Timeouts) -> Timeouts {
+        self.timeouts.unwrap_or(*default)
+    }
 
-        let b_method = match self.method {
-            Methods::GET => "GET".as_bytes(),
-            Methods::POST => "POST".as_bytes(),
-            Methods::PATCH => "PATCH".as_bytes(),
-            Methods::OPTIONS => "OPTIONS".as_bytes(),
-            Methods::CONNECT => "CONNECT".as_bytes(),
-            Methods::HEAD => "HEAD".as_bytes(),
-            Methods::PUT => "PUT".as_bytes(),
-            Methods::DELETE => "DELETE".as_bytes(),
+    // Serializes the request. Streamed bodies can't be serialized up front,
+    // they're only sent by the blocking clients.
+    pub fn build(&self) -> HttpResult<Vec<u8>> {
+        if let Some(e) = &self.error {
+            return Err(e.clone().into());
+        }
+        if self.stream.is_some() {
+            return Err(RequestError::Body(
+                "a streamed body can only be sent by the blocking clients".to_string(),
+            )
+            .into());
+        }
+        let mut buf = self.head();
+        if let Some(c) = &self.content {
+            buf.extend_from_slice(c);
+        }
+        Ok(buf)
+    }
+
+    // The request as it's written to a connection, taking the body stream if it has one
+    pub fn message(&self) -> HttpResult<Message<'_>> {
+        if let Some(e) = &self.error {
+            return Err(e.clone().into());
+        }
+        let body = match (&self.stream, &self.content) {
+            (Some(stream), _) => {
+                let mut stream = lock_unpoisoned(stream);
+                match stream.take() {
+                    Some(r) => MessageBody::Chunked(Some(r)),
+                    None => {
+                        return Err(RequestError::Body(
+                            "the body stream was already sent".to_string(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            (None, Some(c)) => MessageBody::Fixed(c),
+            (None, None) => MessageBody::Empty,
         };
+        let expect_continue = self.expect_continue && !matches!(body, MessageBody::Empty);
+        Ok(Message {
+            head: self.head(),
+            body,
+            expect_continue,
+        })
+    }
 
-        buf.extend_from_slice(b_method);
+    // Request line and headers, up to and including the empty line
+    fn head(&self) -> Vec<u8> {
+        let mut buf = vec![];
+
+        buf.extend_from_slice(self.method.as_str().as_bytes());
         buf.extend_from_slice(&[32]);
 
         // route
-        buf.extend_from_slice(self.route.as_bytes());
+        buf.extend_from_slice(self.url.request_target().as_bytes());
         buf.push(32);
 
         buf.extend_from_slice("HTTP/1.1".as_bytes());
         buf.extend_from_slice(CRLF);
 
-        for (k, v) in &self.headers {
+        // Host is mandatory in HTTP/1.1, one set by the caller replaces the url's
+        let host = match self.headers.get("Host") {
+            Some(h) => h.to_string(),
+            None => self.url.host_header(),
+        };
+        buf.extend_from_slice("Host: ".as_bytes());
+        buf.extend_from_slice(host.as_bytes());
+        buf.extend_from_slice(CRLF);
+
+        // we can decode these, unless the caller asked for something else
+        if !self.headers.contains("Accept-Encoding") {
+            buf.extend_from_slice("Accept-Encoding: ".as_bytes());
+            buf.extend_from_slice(ACCEPT_ENCODING.as_bytes());
+            buf.extend_from_slice(CRLF);
+        }
+
+        for (k, v) in self.headers.iter() {
+            // the framing follows from the body, whatever the caller set
+            if k.eq_ignore_ascii_case("Host")
+                || k.eq_ignore_ascii_case("Content-Length")
+                || k.eq_ignore_ascii_case("Transfer-Encoding")
+                || k.eq_ignore_ascii_case("Expect")
+            {
+                continue;
+            }
             buf.extend_from_slice(k.as_bytes());
             buf.extend_from_slice(": ".as_bytes());
             buf.extend_from_slice(v.as_bytes());
             buf.extend_from_slice(CRLF);
         }
+
+        // Content-Length or chunked, POST, PUT and PATCH always say
+        // how long their body is, even if there is none
+        let has_body = self.stream.is_some() || self.content.is_some();
+        if self.stream.is_some() {
+            buf.extend_from_slice("Transfer-Encoding: chunked".as_bytes());
+            buf.extend_from_slice(CRLF);
+        } else if has_body || matches!(self.method, Methods::POST | Methods::PUT | Methods::PATCH) {
+            let len = self.content.as_ref().map_or(0, |c| c.len());
+            buf.extend_from_slice(format!("Content-Length: {}", len).as_bytes());
+            buf.extend_from_slice(CRLF);
+        }
+        if self.expect_continue && has_body {
+            buf.extend_from_slice("Expect: 100-continue".as_bytes());
+            buf.extend_from_slice(CRLF);
+        }
+
         buf.extend_from_slice(CRLF);
+        buf
+    }
 
-        // Content-Length and Content
-        if self.content_len > 0 {
-            buf.extend_from_slice("Content-Length: ".as_bytes());
-            buf.extend_from_slice(format!("{}\r\n", self.content_len).as_bytes());
-            if let Some(c) = self.content {
-                buf.extend_from_slice(&c);
-            };
+    // Sends the request on any client, following redirects and retrying
+    // transient failures. The client's default headers are added first,
+    // then the request goes through its middleware, and the last layer
+    // hands it on to the redirects and retries, which all happen inside it.
+    pub fn execute<C: CanBeClient + ?Sized>(self, exec: &mut C) -> HttpResult<Response> {
+        let req = match exec.headers() {
+            Some(h) => self.default_headers(h),
+            None => self,
+        };
+        let layers = exec.middleware().to_vec();
+        middleware::run(&layers, req, |req| {
+            let policy = req.redirect_or(&exec.redirect_policy());
+            let retry = req.retry_or(&exec.retry_policy());
+            // the total timeout covers every hop and retry
+            let timeouts = req.timeouts_or(&exec.timeouts()).started();
+            retry.run(req.method(), &timeouts, || {
+                policy.follow(req.clone(), |req| {
+                    let mut msg = req.message()?;
+                    exec.send_message(req.url(), &mut msg, req.method(), &timeouts)
+                })
+            })
+        })
+    }
+
+    // Like `execute`, but returns once the response's headers are read
+    // and leaves the body on the connection. Redirects are followed,
+    // middleware and retries are skipped since they need the whole response.
+    pub fn execute_streaming<C: CanBeClient + ?Sized>(
+        self,
+        exec: &mut C,
+    ) -> HttpResult<StreamingResponse> {
+        let req = match exec.headers() {
+            Some(h) => self.default_headers(h),
+            None => self,
         };
+        let policy = req.redirect_or(&exec.redirect_policy());
+        let timeouts = req.timeouts_or(&exec.timeouts()).started();
+        policy.follow_streaming(req, |req| {
+            let mut msg = req.message()?;
+            exec.send_message_streaming(req.url(), &mut msg, req.method(), &timeouts)
+        })
+    }
+
+    // Async version of `execute`
+    pub async fn execute_async(self, exec: &mut AsyncPersistentClient) -> HttpResult<Response> {
+        exec.send(self).await
+    }
+}
+
+// Reads the request line and headers of a request sent to us
+pub(crate) fn read_request_head<R: BufRead>(r: &mut R) -> HttpResult<(Methods, String, HeaderMap)> {
+    // like status lines, request lines may come after empty lines
+    let mut line = read_line(r)?;
+    while line.is_empty() {
+        line = read_line(r)?;
+    }
+    let line = String::from_utf8(line).map_err(|e| ProtocolError::ParseStrError(e.utf8_error()))?;
+
+    // GET /path?query HTTP/1.1
+    let mut parts = line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/") && !t.is_empty() => (m, t),
+        _ => return Err(ProtocolError::InvalidRequestLine.into()),
+    };
+    let method = Methods::from_name(method).ok_or(ProtocolError::InvalidRequestLine)?;
+
+    let mut headers = HeaderMap::new();
+    loop {
+        let line = read_line(r)?;
+        if line.is_empty() {
+            break;
+        }
+        let line =
+            String::from_utf8(line).map_err(|e| ProtocolError::ParseStrError(e.utf8_error()))?;
+        match line.split_once(':') {
+            Some((k, v)) if headers.append(k, v).is_ok() => {}
+            _ => return Err(ProtocolError::InvalidHeader.into()),
+        }
+    }
+    Ok((method, target.to_string(), headers))
+}
+
+// Reads the body of a request whose head was read with `read_request_head`.
+// Requests without Content-Length or Transfer-Encoding have none, and ones
+// with a body larger than `max` bytes are refused before it's read.
+pub(crate) fn read_request_body<R: BufRead>(
+    r: &mut R,
+    headers: &HeaderMap,
+    max: usize,
+) -> HttpResult<Vec<u8>> {
+    let codings = headers.transfer_encoding();
+    if !codings.is_empty() {
+        if codings.len() != 1 || codings[0] != "chunked" {
+            return Err(ProtocolError::UnsupportedTransferEncoding(codings.join(", ")).into());
+        }
+        return Ok(Response::read_chunked_max(r, max)?.to_vec());
+    }
+    let len = match headers.get("Content-Length") {
+        Some(l) => l.parse::<u64>().map_err(ProtocolError::ParseError)?,
+        None => 0,
+    };
+    if len > max as u64 {
+        return Err(ProtocolError::BodyTooLarge(max).into());
+    }
+    // the length is the client's word, so the buffer grows with what's sent
+    let mut body = Vec::new();
+    r.by_ref().take(len).read_to_end(&mut body)?;
+    if (body.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(body)
+}
+
+enum MessageBody<'m> {
+    Empty,
+    Fixed(&'m [u8]),
+    // taken when it's sent
+    Chunked(Option<Box<dyn Read + Send + 'm>>),
+}
+
+// A request ready to be written to a connection, see `RequestBuilder::message`
+pub struct Message<'m> {
+    head: Vec<u8>,
+    body: MessageBody<'m>,
+    expect_continue: bool,
+}
+
+impl Message<'_> {
+    // If it can be written again, on a new connection
+    pub fn is_replayable(&self) -> bool {
+        !matches!(self.body, MessageBody::Chunked(_))
+    }
+
+    // Writes the request. With `Expect: 100-continue` the body is only sent
+    // once the server asks for it, or hasn't answered in time.
+    // A final response the server sent instead is returned, its body still
+    // has to be read, and the connection can't be reused afterwards.
+    pub(crate) fn write_to(
+        &mut self,
+        conn: &mut Connection,
+        timeouts: &Timeouts,
+    ) -> HttpResult<Option<Head>> {
+        conn.get_mut().write_all(&self.head)?;
+        if self.expect_continue {
+            if let Some(head) = Self::wait_for_continue(conn, timeouts)? {
+                debug!("Server answered {} before the body was sent", head.1);
+                return Ok(Some(head));
+            }
+        }
+
+        match &mut self.body {
+            MessageBody::Empty => {}
+            MessageBody::Fixed(c) => conn.get_mut().write_all(c)?,
+            MessageBody::Chunked(stream) => match stream.take() {
+                Some(r) => Self::write_chunked(conn, r)?,
+                None => {
+                    return Err(
+                        RequestError::Body("the body stream was already sent".to_string()).into(),
+                    )
+                }
+            },
+        }
+        Ok(None)
+    }
+
+    // None once the server sent 100 Continue or stayed silent,
+    // otherwise the head of its final response
+    fn wait_for_continue(conn: &mut Connection, timeouts: &Timeouts) -> HttpResult<Option<Head>> {
+        conn.get_mut()
+            .set_timeouts(&timeouts.read(CONTINUE_TIMEOUT));
+        let answered = conn.fill_buf().map(|b| !b.is_empty());
+        conn.get_mut().set_timeouts(timeouts);
+        match answered {
+            Ok(true) => {}
+            Ok(false) => return Err(ProtocolError::Empty.into()),
+            Err(e) if timeout::kind_of(&e) == Some(TimeoutKind::Read) => {
+                debug!("No 100 Continue, sending the body anyway");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        loop {
+            let head = Response::read_head(conn)?;
+            match head.1 {
+                100 => return Ok(None),
+                // other interim responses, like 103 Early Hints
+                102..=199 => continue,
+                _ => return Ok(Some(head)),
+            }
+        }
+    }
+
+    fn write_chunked(conn: &mut Connection, mut r: Box<dyn Read + Send + '_>) -> HttpResult<()> {
+        let mut data = vec![0; CHUNK_SIZE];
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE + 16);
+        loop {
+            let n = match r.read(&mut data) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(RequestError::Body(e.to_string()).into()),
+            };
+            chunk.clear();
+            chunk.extend_from_slice(format!("{:x}", n).as_bytes());
+            chunk.extend_from_slice(CRLF);
+            if n == 0 {
+                // the last chunk, without trailers
+                chunk.extend_from_slice(CRLF);
+                conn.get_mut().write_all(&chunk)?;
+                return Ok(());
+            }
+            chunk.extend_from_slice(&data[..n]);
+            chunk.extend_from_slice(CRLF);
+            conn.get_mut().write_all(&chunk)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::pool::ConnectionPool;
+    use crate::https::transport::Connector;
+    use std::io::{BufReader, ErrorKind};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    fn head_of(req: &RequestBuilder) -> String {
+        String::from_utf8(req.message().unwrap().head).unwrap()
+    }
+
+    #[test]
+    fn one_host_header() {
+        let req = RequestBuilder::new(Url::new("https://a:8443/x").unwrap());
+        assert!(head_of(&req).contains("\r\nHost: a:8443\r\n"));
+
+        let req = req.header(("host", "b.example"));
+        let head = head_of(&req);
+        assert!(head.contains("\r\nHost: b.example\r\n"));
+        assert_eq!(head.to_ascii_lowercase().matches("host:").count(), 1);
+    }
+
+    // A server for one connection, `answer` gets it right after the request's head.
+    // The thread returns the body the client sent.
+    fn serve_once<F>(answer: F) -> (Url, JoinHandle<Vec<u8>>)
+    where
+        F: FnOnce(&HeaderMap, &mut BufReader<TcpStream>, &mut TcpStream) -> Vec<u8>
+            + Send
+            + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::new(&format!("http://{}/upload", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let (_, _, headers) = read_request_head(&mut reader).unwrap();
+            answer(&headers, &mut reader, &mut writer)
+        });
+        (url, handle)
+    }
+
+    const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+    fn send(url: &Url, req: RequestBuilder, pool: &ConnectionPool) -> Response {
+        let mut msg = req.message().unwrap();
+        pool.send(
+            url,
+            &mut msg,
+            &Methods::POST,
+            &Connector::new(),
+            &Timeouts::new(),
+        )
+        .unwrap()
+    }
+
+    fn post(url: &Url) -> RequestBuilder<'static> {
+        RequestBuilder::new(url.clone()).http_method(Methods::POST)
+    }
+
+    #[test]
+    fn streamed_bodies_are_chunked() {
+        let (url, server) = serve_once(|headers, r, w| {
+            assert_eq!(headers.get("Transfer-Encoding"), Some("chunked"));
+            assert_eq!(headers.get("Content-Length"), None);
+            // the raw framing, read by hand
+            let mut raw = Vec::new();
+            while !raw.ends_with(b"0\r\n\r\n") {
+                let mut line = Vec::new();
+                r.read_until(b'\n', &mut line).unwrap();
+                raw.extend_from_slice(&line);
+            }
+            w.write_all(OK).unwrap();
+            raw
+        });
+        let body = vec![b'x'; CHUNK_SIZE + 10];
+        let req = post(&url).body_reader(io::Cursor::new(body));
+        send(&url, req, &ConnectionPool::new());
+
+        let mut expected = format!("{:x}\r\n", CHUNK_SIZE).into_bytes();
+        expected.extend_from_slice(&[b'x'; CHUNK_SIZE]);
+        expected.extend_from_slice(b"\r\na\r\nxxxxxxxxxx\r\n0\r\n\r\n");
+        assert_eq!(server.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn body_follows_100_continue() {
+        let (url, server) = serve_once(|headers, r, w| {
+            assert_eq!(headers.get("Expect"), Some("100-continue"));
+            // nothing comes before the server asks
+            r.get_ref()
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let early = r.fill_buf().map(|b| b.len());
+            assert!(
+                matches!(early, Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+            );
+            r.get_ref().set_read_timeout(None).unwrap();
+
+            w.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+            let body = read_request_body(r, headers, usize::MAX).unwrap();
+            w.write_all(OK).unwrap();
+            body
+        });
+        let pool = ConnectionPool::new();
+        let req = post(&url).content(b"hello").expect_continue(true);
+        assert_eq!(send(&url, req, &pool).status_code, 200);
+        assert_eq!(server.join().unwrap(), b"hello");
+        assert_eq!(pool.idle_count(&url), 1);
+    }
+
+    #[test]
+    fn final_response_before_100_skips_the_body() {
+        let (url, server) = serve_once(|_, r, w| {
+            w.write_all(b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            // the client closes without sending anything more
+            let mut rest = Vec::new();
+            r.read_to_end(&mut rest).unwrap();
+            rest
+        });
+        let pool = ConnectionPool::new();
+        let req = post(&url).content(b"hello").expect_continue(true);
+        assert_eq!(send(&url, req, &pool).status_code, 417);
+        assert_eq!(pool.idle_count(&url), 0);
+        assert_eq!(server.join().unwrap(), b"");
+    }
 
-        exec.io_write(&buf);
-        exec.io_read()
+    #[test]
+    fn body_goes_out_when_the_server_is_silent() {
+        let (url, server) = serve_once(|headers, r, w| {
+            let body = read_request_body(r, headers, usize::MAX).unwrap();
+            w.write_all(OK).unwrap();
+            body
+        });
+        let started = Instant::now();
+        let req = post(&url).content(b"hello").expect_continue(true);
+        assert_eq!(send(&url, req, &ConnectionPool::new()).status_code, 200);
+        assert!(started.elapsed() >= CONTINUE_TIMEOUT);
+        assert_eq!(server.join().unwrap(), b"hello");
     }
 }
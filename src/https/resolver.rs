@@ -0,0 +1,507 @@
+use super::timeout;
+use super::timeout::TimeoutKind;
+use super::util::lock_unpoisoned;
+use log::{debug, info};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+// How long to wait for a connection attempt before starting the next one,
+// the default of RFC 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// std doesn't tell how long a name is valid, so answers are kept this long
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+// Turns a host name into addresses, in the order they should be tried
+pub trait Lookup: Debug + Send + Sync {
+    fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+}
+
+// The system's resolver, getaddrinfo on most platforms
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+#[derive(Debug)]
+struct Cached {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+// Resolves host names for the clients and connects to them.
+// Static overrides answer first, then the cache, then the lookup.
+// Connecting tries IPv6 and IPv4 addresses side by side (RFC 8305),
+// and the first one to answer is used. Clones share the cache.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    lookup: Arc<dyn Lookup>,
+    overrides: Arc<HashMap<String, Vec<SocketAddr>>>,
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<(String, u16), Cached>>>,
+}
+
+static GLOBAL_RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            lookup: Arc::new(SystemLookup),
+            overrides: Arc::new(HashMap::new()),
+            ttl: DEFAULT_TTL,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // The resolver used by connectors which weren't given one
+    pub fn global() -> &'static Resolver {
+        GLOBAL_RESOLVER.get_or_init(Resolver::new)
+    }
+
+    // Looks names up with `lookup` instead of the system, starting with an empty cache
+    pub fn lookup(mut self, lookup: impl Lookup + 'static) -> Self {
+        self.lookup = Arc::new(lookup);
+        self.cache = Arc::new(Mutex::new(HashMap::new()));
+        self
+    }
+
+    // Sends connections for `host` to `addr`, whatever port the url has.
+    // Can be given several times for the same host.
+    pub fn override_host(mut self, host: &str, addr: SocketAddr) -> Self {
+        Arc::make_mut(&mut self.overrides)
+            .entry(normalize(host))
+            .or_default()
+            .push(addr);
+        self
+    }
+
+    // How long looked up addresses are reused, zero turns the cache off
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // The addresses `host` is overridden with, if any
+    pub fn override_for(&self, host: &str) -> Option<&[SocketAddr]> {
+        self.overrides.get(&normalize(host)).map(|a| a.as_slice())
+    }
+
+    pub fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        let host = normalize(host);
+        if let Some(addrs) = self.overrides.get(&host) {
+            debug!("{} is overridden with {:?}", host, addrs);
+            return Ok(addrs.clone());
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let key = (host, port);
+        let now = Instant::now();
+        if !self.ttl.is_zero() {
+            let mut cache = lock_unpoisoned(&self.cache);
+            match cache.get(&key) {
+                Some(c) if c.expires > now => return Ok(c.addrs.clone()),
+                Some(_) => {
+                    cache.remove(&key);
+                }
+                None => {}
+            }
+        }
+
+        // not holding the lock, lookups can take a while
+        let addrs = self.lookup.lookup(&key.0, port)?;
+        if addrs.is_empty() {
+            return Err(no_addresses(&key.0));
+        }
+        debug!("Resolved {} to {:?}", key.0, addrs);
+        if !self.ttl.is_zero() {
+            lock_unpoisoned(&self.cache).insert(
+                key,
+                Cached {
+                    addrs: addrs.clone(),
+                    expires: now + self.ttl,
+                },
+            );
+        }
+        Ok(addrs)
+    }
+
+    // Forgets every looked up address
+    pub fn clear_cache(&self) {
+        lock_unpoisoned(&self.cache).clear();
+    }
+
+    // Connects to one of the host's addresses. Another attempt starts
+    // whenever the last one failed or hasn't finished within 250ms,
+    // alternating between IPv6 and IPv4. `timeout` limits the whole race.
+    pub fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<TcpStream, Error> {
+        if timeout.is_some_and(|t| t.is_zero()) {
+            return Err(timeout::error(TimeoutKind::Connect));
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let addrs = self.resolve(host, port)?;
+        if let [addr] = addrs[..] {
+            let sock = match timeout {
+                Some(t) => TcpStream::connect_timeout(&addr, t),
+                None => TcpStream::connect(addr),
+            }
+            .map_err(connect_error)?;
+            info!("Connected to {} at {}", host, addr);
+            return Ok(sock);
+        }
+        let mut queue = interleave(addrs).into_iter();
+        let (tx, rx) = mpsc::channel();
+        let mut pending = 0;
+        let mut start_next = true;
+        let mut last_err = no_addresses(host);
+
+        loop {
+            let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if left.is_some_and(|l| l.is_zero()) {
+                return Err(timeout::error(TimeoutKind::Connect));
+            }
+            if start_next {
+                match queue.next() {
+                    Some(addr) => {
+                        debug!("Trying {} for {}", addr, host);
+                        let tx = tx.clone();
+                        thread::spawn(move || {
+                            let res = match left {
+                                Some(t) => TcpStream::connect_timeout(&addr, t),
+                                None => TcpStream::connect(addr),
+                            };
+                            // nobody listens anymore if another attempt won
+                            let _ = tx.send((addr, res));
+                        });
+                        pending += 1;
+                    }
+                    None if pending == 0 => return Err(last_err),
+                    None => {}
+                }
+            }
+
+            let more = queue.len() > 0;
+            let wait = match (more, left) {
+                (true, Some(l)) => l.min(ATTEMPT_DELAY),
+                (true, None) => ATTEMPT_DELAY,
+                (false, Some(l)) => l,
+                (false, None) => Duration::MAX,
+            };
+            match rx.recv_timeout(wait) {
+                Ok((addr, Ok(sock))) => {
+                    info!("Connected to {} at {}", host, addr);
+                    return Ok(sock);
+                }
+                Ok((addr, Err(e))) => {
+                    debug!("Connecting to {} at {} failed: {}", host, addr, e);
+                    pending -= 1;
+                    last_err = connect_error(e);
+                    start_next = true;
+                }
+                Err(RecvTimeoutError::Timeout) => start_next = more,
+                Err(RecvTimeoutError::Disconnected) => return Err(last_err),
+            }
+        }
+    }
+
+    // Async version of `connect`
+    pub async fn connect_async(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<tokio::net::TcpStream, Error> {
+        // the lookup blocks, so it's done off the runtime
+        let resolver = self.clone();
+        let name = host.to_string();
+        let addrs = match tokio::task::spawn_blocking(move || resolver.resolve(&name, port)).await {
+            Ok(res) => res?,
+            Err(e) => return Err(Error::other(e)),
+        };
+        let race = race_async(host, interleave(addrs));
+        match timeout {
+            Some(t) => match tokio::time::timeout(t, race).await {
+                Ok(res) => res,
+                Err(_) => Err(timeout::error(TimeoutKind::Connect)),
+            },
+            None => race.await,
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Dropping the set when one attempt wins cancels the others
+async fn race_async(host: &str, addrs: Vec<SocketAddr>) -> Result<tokio::net::TcpStream, Error> {
+    let mut queue = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut start_next = true;
+    let mut last_err = no_addresses(host);
+
+    loop {
+        if start_next {
+            if let Some(addr) = queue.next() {
+                debug!("Trying {} for {}", addr, host);
+                attempts.spawn(async move { (addr, tokio::net::TcpStream::connect(addr).await) });
+            }
+        }
+        let more = queue.len() > 0;
+        let next = match more {
+            true => tokio::time::timeout(ATTEMPT_DELAY, attempts.join_next()).await,
+            false => Ok(attempts.join_next().await),
+        };
+        match next {
+            Ok(Some(Ok((addr, Ok(sock))))) => {
+                info!("Connected to {} at {}", host, addr);
+                return Ok(sock);
+            }
+            Ok(Some(Ok((addr, Err(e))))) => {
+                debug!("Connecting to {} at {} failed: {}", host, addr, e);
+                last_err = e;
+                start_next = true;
+            }
+            Ok(Some(Err(e))) => {
+                last_err = Error::other(e);
+                start_next = true;
+            }
+            // every attempt failed
+            Ok(None) => return Err(last_err),
+            Err(_) => start_next = more,
+        }
+    }
+}
+
+// Names are case insensitive, IPv6 addresses may come in brackets
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase()
+}
+
+fn no_addresses(host: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{} resolved to no addresses", host),
+    )
+}
+
+fn connect_error(e: Error) -> Error {
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => timeout::error(TimeoutKind::Connect),
+        _ => e,
+    }
+}
+
+// Alternates between the address families, starting with the first
+// address's, as the lookup already sorted them by preference (RFC 8305 section 4)
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        out.extend(preferred.pop_front());
+        out.extend(other.pop_front());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::client::Methods;
+    use crate::https::pool::ConnectionPool;
+    use crate::https::request::RequestBuilder;
+    use crate::https::server::{Router, Server, ServerResponse};
+    use crate::https::timeout::Timeouts;
+    use crate::https::transport::Connector;
+    use crate::https::url::Url;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Answers every name with the same addresses, counting the lookups
+    #[derive(Debug, Clone, Default)]
+    struct FakeLookup {
+        addrs: Vec<SocketAddr>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Lookup for FakeLookup {
+        fn lookup(&self, _: &str, _: u16) -> Result<Vec<SocketAddr>, Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.addrs.clone())
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // An address nothing listens on
+    fn refusing() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn override_keeps_the_url() {
+        let router = Router::new().get("/api", |req| {
+            ServerResponse::text(200, req.headers.get("Host").unwrap_or_default())
+        });
+        let server = Server::bind("127.0.0.1:0", router)
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let resolver = Resolver::new().override_host("discord.com", server.local_addr());
+        let connector = Connector::new().resolver(resolver);
+
+        let url = Url::new("http://discord.com:8443/api").unwrap();
+        let req = RequestBuilder::new(url.clone());
+        let mut msg = req.message().unwrap();
+        let resp = ConnectionPool::new()
+            .send(&url, &mut msg, &Methods::GET, &connector, &Timeouts::new())
+            .unwrap();
+        assert_eq!(&resp.content[..], b"discord.com:8443");
+    }
+
+    #[test]
+    fn overrides_are_normalized() {
+        let lookup = FakeLookup::default();
+        let resolver = Resolver::new()
+            .lookup(lookup.clone())
+            .override_host("Discord.COM", addr("127.0.0.1:8443"))
+            .override_host("[::1]", addr("[::1]:1"))
+            .override_host("[::1]", addr("127.0.0.1:2"));
+        assert_eq!(
+            resolver.resolve("discord.com", 443).unwrap(),
+            [addr("127.0.0.1:8443")]
+        );
+        assert_eq!(
+            resolver.resolve("DISCORD.com", 80).unwrap(),
+            [addr("127.0.0.1:8443")]
+        );
+        let both = [addr("[::1]:1"), addr("127.0.0.1:2")];
+        assert_eq!(resolver.override_for("::1"), Some(&both[..]));
+        assert_eq!(resolver.resolve("[::1]", 443).unwrap(), both);
+        assert_eq!(resolver.override_for("example.com"), None);
+        // literal addresses need no lookup either
+        assert_eq!(resolver.resolve("[::2]", 443).unwrap(), [addr("[::2]:443")]);
+        assert_eq!(lookup.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn answers_are_cached_for_the_ttl() {
+        let lookup = FakeLookup {
+            addrs: vec![addr("10.0.0.1:443")],
+            ..Default::default()
+        };
+        let calls = lookup.calls.clone();
+        let resolver = Resolver::new()
+            .lookup(lookup)
+            .ttl(Duration::from_millis(100));
+        resolver.resolve("a.test", 443).unwrap();
+        resolver.resolve("A.test", 443).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // the port is part of the key
+        resolver.resolve("a.test", 80).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        thread::sleep(Duration::from_millis(150));
+        resolver.resolve("a.test", 443).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        resolver.clear_cache();
+        resolver.resolve("a.test", 443).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let resolver = resolver.ttl(Duration::ZERO);
+        resolver.resolve("a.test", 443).unwrap();
+        resolver.resolve("a.test", 443).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn empty_answers_are_errors() {
+        let resolver = Resolver::new().lookup(FakeLookup::default());
+        let err = resolver.resolve("nowhere.test", 443).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn interleaves_the_families() {
+        let v6 = |n: u16| addr(&format!("[::1]:{}", n));
+        let v4 = |n: u16| addr(&format!("127.0.0.1:{}", n));
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v4(3), v4(4), v4(5)]),
+            [v6(1), v4(3), v6(2), v4(4), v4(5)]
+        );
+        assert_eq!(
+            interleave(vec![v4(1), v6(2), v6(3), v4(4)]),
+            [v4(1), v6(2), v4(4), v6(3)]
+        );
+        assert_eq!(interleave(vec![]), []);
+    }
+
+    fn falls_back_setup() -> (Resolver, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lookup = FakeLookup {
+            addrs: vec![refusing(), listener.local_addr().unwrap()],
+            ..Default::default()
+        };
+        (Resolver::new().lookup(lookup), listener)
+    }
+
+    #[test]
+    fn falls_back_to_the_next_address() {
+        let (resolver, listener) = falls_back_setup();
+        let sock = resolver
+            .connect("a.test", 443, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        // nothing to fall back to
+        let resolver = resolver.override_host("b.test", refusing());
+        let err = resolver.connect("b.test", 443, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_address_async() {
+        let (resolver, listener) = falls_back_setup();
+        let sock = resolver
+            .connect_async("a.test", 443, Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        let resolver = resolver
+            .override_host("b.test", refusing())
+            .override_host("b.test", refusing());
+        let err = resolver
+            .connect_async("b.test", 443, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -1,218 +1,569 @@
-use bytes::{Bytes, BytesMut};
-use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::error;
-use std::fmt;
-use std::num::ParseIntError;
+use crate::https::client::Methods;
+use crate::https::encoding;
+use crate::https::error::{HttpError, HttpResult, ProtocolError};
+use crate::https::headers::HeaderMap;
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use std::io::{BufRead, Error, ErrorKind, Read};
 use std::str;
-use std::str::Utf8Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
 
-#[derive(Debug)]
-pub enum HttpResponseError {
-    Empty,
-    ParseStrError(Utf8Error),
-    ParseError(ParseIntError),
-    NoHeaders,
-    InvalidHeader,
+// longest status or header line we accept
+pub(crate) const MAX_LINE_LEN: usize = 8192;
+// largest body read into memory, larger ones have to be streamed
+pub(crate) const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+
+pub(crate) fn read_chunk_length(buf: &[u8]) -> HttpResult<usize> {
+    let str = match str::from_utf8(buf) {
+        Ok(o) => o,
+        Err(e) => return Err(ProtocolError::ParseStrError(e).into()),
+    };
+    // chunk extensions (";name=value") are ignored
+    let len = str.split(';').next().unwrap_or("").trim();
+    match usize::from_str_radix(len, 16) {
+        Ok(num) => Ok(num),
+        Err(e) => Err(ProtocolError::ParseError(e).into()),
+    }
 }
 
-impl fmt::Display for HttpResponseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            HttpResponseError::Empty => write!(f, "the given byte slice was empty"),
-            HttpResponseError::ParseError(..) => {
-                write!(f, "there was a error during parsing a number")
-            }
-            HttpResponseError::ParseStrError(..) => {
-                write!(f, "there was an error with parsing a string")
-            }
-            HttpResponseError::NoHeaders => {
-                write!(f, "no headers were found")
-            }
-            HttpResponseError::InvalidHeader => {
-                write!(f, "invalid header")
-            }
-        }
+// Reads a single line terminated by CRLF (or a bare LF), without the terminator
+pub(crate) fn read_line<R: BufRead>(r: &mut R) -> HttpResult<Vec<u8>> {
+    let mut line = Vec::new();
+    let n = r
+        .take(MAX_LINE_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .map_err(HttpError::from)?;
+    finish_line(line, n)
+}
+
+async fn read_line_async<R: AsyncBufRead + Unpin>(r: &mut R) -> HttpResult<Vec<u8>> {
+    let mut line = Vec::new();
+    let n = AsyncReadExt::take(&mut *r, MAX_LINE_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(HttpError::from)?;
+    finish_line(line, n)
+}
+
+// Appends exactly `len` bytes to `buf`. The buffer grows as they arrive,
+// so a length that was only claimed costs nothing.
+fn read_exactly<R: Read>(r: &mut R, len: usize, buf: &mut Vec<u8>) -> HttpResult<()> {
+    let start = buf.len();
+    r.take(len as u64).read_to_end(buf)?;
+    if buf.len() - start < len {
+        return Err(Error::from(ErrorKind::UnexpectedEof).into());
     }
+    Ok(())
 }
 
-impl error::Error for HttpResponseError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match *self {
-            HttpResponseError::Empty => None,
-            HttpResponseError::ParseError(ref e) => Some(e),
-            HttpResponseError::ParseStrError(ref e) => Some(e),
-            HttpResponseError::NoHeaders => None,
-            HttpResponseError::InvalidHeader => None,
-        }
+async fn read_exactly_async<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    len: usize,
+    buf: &mut Vec<u8>,
+) -> HttpResult<()> {
+    let start = buf.len();
+    AsyncReadExt::take(&mut *r, len as u64)
+        .read_to_end(buf)
+        .await?;
+    if buf.len() - start < len {
+        return Err(Error::from(ErrorKind::UnexpectedEof).into());
     }
+    Ok(())
 }
 
-pub type HttpResult<T> = Result<T, HttpResponseError>;
-pub type HeaderMap<'r> = HashMap<&'r str, &'r str>;
+// The body's length once `more` bytes are added, if it stays within `max`
+fn grown(len: usize, more: usize, max: usize) -> HttpResult<usize> {
+    len.checked_add(more)
+        .filter(|total| *total <= max)
+        .ok_or_else(|| ProtocolError::BodyTooLarge(max).into())
+}
 
-fn read_chunk_length(buf: &[u8]) -> HttpResult<usize> {
-    let str = match str::from_utf8(buf) {
-        Ok(o) => o,
-        Err(e) => return Err(HttpResponseError::ParseStrError(e)),
-    };
-    match usize::from_str_radix(str, 16) {
-        Ok(num) => Ok(num),
-        Err(e) => Err(HttpResponseError::ParseError(e)),
+// Checks that the line is complete and strips the terminator
+fn finish_line(mut line: Vec<u8>, n: usize) -> HttpResult<Vec<u8>> {
+    if n == 0 {
+        return Err(ProtocolError::Empty.into());
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() > MAX_LINE_LEN {
+            return Err(ProtocolError::LineTooLong.into());
+        }
+        return Err(HttpError::Io(Error::from(ErrorKind::UnexpectedEof)));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn read_line_str<R: BufRead>(r: &mut R) -> HttpResult<String> {
+    let line = read_line(r)?;
+    match String::from_utf8(line) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(ProtocolError::ParseStrError(e.utf8_error()).into()),
     }
 }
 
+// Version, status and headers
+pub(crate) type Head = (String, u16, HeaderMap);
+
+// How the end of a body is found
+pub(crate) enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
 #[derive(Debug)]
-pub struct Response<'r> {
+pub struct Response {
     pub status_code: u16,
-    pub headers: HeaderMap<'r>,
+    pub headers: HeaderMap,
     pub content: Bytes,
+    keep_alive: bool,
 }
 
-impl<'r> Response<'r> {
-    pub fn from_slice(d: &'r [u8]) -> HttpResult<Self> {
+impl Response {
+    // Parses a response that is already fully in memory
+    pub fn from_slice(d: &[u8]) -> HttpResult<Self> {
         if d.is_empty() {
-            return Err(HttpResponseError::Empty);
+            return Err(ProtocolError::Empty.into());
         };
+        let mut reader = d;
+        Self::read_from(&mut reader, &Methods::GET)
+    }
 
-        let mut headers: HashMap<&'r str, &'r str> = HashMap::new();
+    // Reads exactly one response from the stream.
+    // The status line and headers come first, then the body is read
+    // according to its framing, so nothing past the end of the message
+    // is consumed and the stream can be used for the next request.
+    pub fn read_from<R: BufRead>(r: &mut R, method: &Methods) -> HttpResult<Self> {
+        let head = Self::read_final_head(r)?;
+        Self::read_rest(r, method, head)
+    }
 
-        let mut iter = d.iter();
+    // The head of the first response that isn't interim
+    pub(crate) fn read_final_head<R: BufRead>(r: &mut R) -> HttpResult<Head> {
+        loop {
+            let head = Self::read_head(r)?;
 
-        // loop to split into lines
-        let mut lines: Vec<&'r str> = Vec::with_capacity(10);
-        let mut line_break = false;
-        let mut count = 0;
-        let mut t_count: usize = 0;
+            // 1xx responses are interim, the real one follows
+            // (101 Switching Protocols is final, but we never ask for it)
+            if (100..200).contains(&head.1) && head.1 != 101 {
+                debug!("Skipping interim response {}", head.1);
+                continue;
+            }
+            return Ok(head);
+        }
+    }
 
-        while let Some(byte) = iter.next() {
-            match byte {
-                b'\r' => {
-                    if iter.next().is_some_and(|v| v == &b'\n') {
-                        line_break = true;
-                        count += 2;
-                        continue;
-                    }
+    // Reads the body of a response whose head was already read
+    pub(crate) fn read_rest<R: BufRead>(
+        r: &mut R,
+        method: &Methods,
+        (version, status_code, headers): Head,
+    ) -> HttpResult<Self> {
+        let keep_alive = Self::wants_keep_alive(&version, method, status_code, &headers);
+        let content = Self::read_body(r, method, status_code, &headers)?;
+        Self::from_parts(status_code, headers, content, keep_alive)
+    }
+
+    // Async version of `read_from`, for connections driven by tokio
+    pub async fn read_from_async<R: AsyncBufRead + Unpin>(
+        r: &mut R,
+        method: &Methods,
+    ) -> HttpResult<Self> {
+        loop {
+            let head = Self::read_head_async(r).await?;
+            let (version, status_code, headers) = Self::read_head(&mut &head[..])?;
+
+            if (100..200).contains(&status_code) && status_code != 101 {
+                debug!("Skipping interim response {}", status_code);
+                continue;
+            }
+
+            let keep_alive = Self::wants_keep_alive(&version, method, status_code, &headers);
+            let (framing, codings) = Self::framing(method, status_code, &headers)?;
+            let body = match framing {
+                Framing::Empty => Bytes::new(),
+                Framing::Length(len) => {
+                    let mut buf = Vec::new();
+                    read_exactly_async(r, grown(0, len, MAX_BODY_LEN)?, &mut buf).await?;
+                    Bytes::from(buf)
                 }
-                _ => {
-                    if line_break {
-                        let line = match str::from_utf8(&d[t_count..t_count + count - 2]) {
-                            Ok(o) => o,
-                            Err(e) => return Err(HttpResponseError::ParseStrError(e)),
-                        };
-                        lines.push(line);
-                        line_break = false;
-                        t_count += count;
-                        count = 0;
-                    };
-                    count += 1
+                Framing::Chunked => Self::read_chunked_async(r).await?,
+                Framing::UntilClose => {
+                    let mut buf = Vec::new();
+                    AsyncReadExt::take(&mut *r, MAX_BODY_LEN as u64 + 1)
+                        .read_to_end(&mut buf)
+                        .await?;
+                    grown(0, buf.len(), MAX_BODY_LEN)?;
+                    Bytes::from(buf)
                 }
-            }
+            };
+            let content = encoding::decode_all(&codings, body).map_err(ProtocolError::Decode)?;
+            return Self::from_parts(status_code, headers, content, keep_alive);
         }
+    }
 
-        let mut lines_iter = lines.into_iter();
+    // A response whose content is already decoded
+    pub(crate) fn new(
+        status_code: u16,
+        headers: HeaderMap,
+        content: Bytes,
+        keep_alive: bool,
+    ) -> Self {
+        Self {
+            status_code,
+            headers,
+            content,
+            keep_alive,
+        }
+    }
+
+    fn from_parts(
+        status_code: u16,
+        mut headers: HeaderMap,
+        content: Bytes,
+        keep_alive: bool,
+    ) -> HttpResult<Self> {
+        let content = Self::decode_content(content, &mut headers)?;
+        Ok(Self::new(status_code, headers, content, keep_alive))
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status_code)
+    }
+
+    // Turns a response that isn't 2xx into an error carrying its status and body
+    pub fn error_for_status(self) -> HttpResult<Self> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(HttpError::Status(self.status_code, self.content))
+        }
+    }
+
+    // Parses a 2xx response's body as json
+    pub fn json<T: DeserializeOwned>(&self) -> HttpResult<T> {
+        if !self.is_success() {
+            return Err(HttpError::Status(self.status_code, self.content.clone()));
+        }
+        serde_json::from_slice(&self.content)
+            .map_err(|e| HttpError::Json(self.status_code, self.content.clone(), e))
+    }
+
+    // If the connection can be used for another request after this response
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    pub(crate) fn wants_keep_alive(
+        version: &str,
+        method: &Methods,
+        status_code: u16,
+        headers: &HeaderMap,
+    ) -> bool {
+        let mut options = headers.connection().into_iter();
+
+        // HTTP/1.0 closes by default, HTTP/1.1 keeps the connection open
+        let persistent = if version == "HTTP/1.0" {
+            options.any(|o| o == "keep-alive")
+        } else {
+            !options.any(|o| o == "close")
+        };
+
+        // a body without framing ends when the connection is closed
+        let framed = match headers.transfer_encoding().last() {
+            Some(te) => te == "chunked",
+            None => headers.contains("Content-Length"),
+        };
+        let framed =
+            framed || matches!(method, Methods::HEAD) || status_code == 204 || status_code == 304;
+
+        persistent && framed
+    }
+
+    // status line and headers
+    pub(crate) fn read_head<R: BufRead>(r: &mut R) -> HttpResult<Head> {
+        // servers may send empty lines before the status line
+        let mut status_line = read_line_str(r)?;
+        while status_line.is_empty() {
+            status_line = read_line_str(r)?;
+        }
 
-        // status code
-        let status_code = match lines_iter.next() {
-            Some(line) => match line[9..12].parse::<u16>() {
-                Ok(str) => str,
-                Err(e) => return Err(HttpResponseError::ParseError(e)),
+        // HTTP/1.1 200 OK
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("").to_string();
+        if !version.starts_with("HTTP/") {
+            return Err(ProtocolError::InvalidStatusLine.into());
+        }
+        let status_code = match parts.next() {
+            Some(code) if code.len() == 3 => match code.parse::<u16>() {
+                Ok(n) => n,
+                Err(e) => return Err(ProtocolError::ParseError(e).into()),
             },
-            None => return Err(HttpResponseError::Empty),
+            _ => return Err(ProtocolError::InvalidStatusLine.into()),
         };
 
-        // headers
-        for line in lines_iter {
-            let mut header = line.split(": ");
-            match header.next() {
-                None => return Err(HttpResponseError::NoHeaders),
-                Some(k) => {
-                    if let Some(v) = header.next() {
-                        headers.insert(k, v);
-                    } else {
-                        return Err(HttpResponseError::InvalidHeader);
+        let mut headers = HeaderMap::new();
+        loop {
+            let line = read_line_str(r)?;
+            if line.is_empty() {
+                break;
+            }
+            // repeated headers like Set-Cookie are all kept
+            match line.split_once(':') {
+                Some((k, v)) => {
+                    if headers.append(k, v).is_err() {
+                        return Err(ProtocolError::InvalidHeader.into());
                     }
                 }
+                None => return Err(ProtocolError::InvalidHeader.into()),
+            }
+        }
+
+        Ok((version, status_code, headers))
+    }
+
+    // How the end of the body is found, and the transfer codings
+    // left to undo once it's read
+    pub(crate) fn framing(
+        method: &Methods,
+        status_code: u16,
+        headers: &HeaderMap,
+    ) -> HttpResult<(Framing, Vec<String>)> {
+        // these never have a body, whatever the headers say,
+        // and after a successful CONNECT the connection is a tunnel
+        if matches!(method, Methods::HEAD)
+            || status_code == 204
+            || status_code == 304
+            || (matches!(method, Methods::CONNECT) && (200..300).contains(&status_code))
+        {
+            return Ok((Framing::Empty, Vec::new()));
+        }
+
+        // transfer encoding wins over content length
+        let mut codings = headers.transfer_encoding();
+        if !codings.is_empty() {
+            if codings
+                .iter()
+                .any(|c| c != "chunked" && !encoding::is_supported(c))
+            {
+                return Err(ProtocolError::UnsupportedTransferEncoding(codings.join(", ")).into());
             }
+
+            // chunked has to be the last coding,
+            // otherwise the body ends when the connection is closed
+            if codings.last().is_some_and(|c| c == "chunked") {
+                codings.pop();
+                return Ok((Framing::Chunked, codings));
+            }
+            return Ok((Framing::UntilClose, codings));
         }
 
         // content length
-        let content_len = match headers.get("Content-Length") {
-            Some(l) => match l.parse::<usize>() {
-                Ok(n) => Some(n),
-                Err(e) => return Err(HttpResponseError::ParseError(e)),
-            },
-            None => None,
-        };
+        if let Some(l) = headers.get("Content-Length") {
+            return match l.parse::<usize>() {
+                Ok(n) => Ok((Framing::Length(n), codings)),
+                Err(e) => Err(ProtocolError::ParseError(e).into()),
+            };
+        }
 
-        // transfer encoding
-        let mut transfer_encoding = None;
-        if let Some(len) = content_len {
-            return Ok(Self {
-                status_code,
-                headers,
-                content: Bytes::copy_from_slice(&d[t_count..t_count + len]),
-            });
-        } else {
-            match headers.get("Transfer-Encoding") {
-                None => {
-                    warn!("No Content-Length and Transfer-Encoding found! Reading everything");
-                }
-                Some(v) => transfer_encoding = Some(*v),
+        // the body ends when the server closes the connection
+        warn!("No Content-Length and Transfer-Encoding found! Reading everything");
+        Ok((Framing::UntilClose, codings))
+    }
+
+    // The raw status line and headers, up to and including the empty line
+    async fn read_head_async<R: AsyncBufRead + Unpin>(r: &mut R) -> HttpResult<Vec<u8>> {
+        let mut line = read_line_async(r).await?;
+        while line.is_empty() {
+            line = read_line_async(r).await?;
+        }
+        let mut head = Vec::new();
+        loop {
+            let end = line.is_empty();
+            head.extend_from_slice(&line);
+            head.extend_from_slice(b"\r\n");
+            if end {
+                return Ok(head);
+            }
+            line = read_line_async(r).await?;
+        }
+    }
+
+    fn read_body<R: BufRead>(
+        r: &mut R,
+        method: &Methods,
+        status_code: u16,
+        headers: &HeaderMap,
+    ) -> HttpResult<Bytes> {
+        let (framing, codings) = Self::framing(method, status_code, headers)?;
+        let body = match framing {
+            Framing::Empty => Bytes::new(),
+            Framing::Length(len) => {
+                let mut buf = Vec::new();
+                read_exactly(r, grown(0, len, MAX_BODY_LEN)?, &mut buf)?;
+                Bytes::from(buf)
+            }
+            Framing::Chunked => Self::read_chunked(r)?,
+            Framing::UntilClose => {
+                let mut buf = Vec::new();
+                r.take(MAX_BODY_LEN as u64 + 1)
+                    .read_to_end(&mut buf)
+                    .map_err(HttpError::from)?;
+                grown(0, buf.len(), MAX_BODY_LEN)?;
+                Bytes::from(buf)
             }
         };
+        Ok(encoding::decode_all(&codings, body).map_err(ProtocolError::Decode)?)
+    }
 
-        match transfer_encoding {
-            Some("chunked") => {
-                let mut data_read = false;
-                let mut length_read = true;
-                let mut read_length = 0;
-                let mut l_count = 0;
-                let mut c_buf = BytesMut::with_capacity(368);
-                t_count += 2;
-
-                while let Some(byte) = iter.next() {
-                    if data_read {
-                        c_buf.extend_from_slice(&d[0..1 + read_length]);
-                        t_count += read_length + 2;
-                        data_read = false;
-                    }
-                    if length_read {
-                        match byte {
-                            b'\r' => {
-                                iter.nth(0);
-                                length_read = false;
-                                data_read = true;
-
-                                read_length = read_chunk_length(&d[t_count..t_count + l_count])?;
-                                l_count = 0;
-
-                                debug!("Chunk length: {}", read_length);
-
-                                if read_length == 0 {
-                                    break;
-                                }
-                            }
-                            _ => {
-                                l_count += 1;
-                                continue;
-                            }
-                        }
-                    }
-                }
+    // Undoes the Content-Encoding. Content-Encoding and Content-Length
+    // are removed afterwards, since they don't describe the content anymore.
+    fn decode_content(content: Bytes, headers: &mut HeaderMap) -> HttpResult<Bytes> {
+        let codings = headers.content_encoding();
+        if codings.is_empty() {
+            return Ok(content);
+        }
+        if let Some(c) = codings.iter().find(|c| !encoding::is_supported(c)) {
+            warn!(
+                "Unsupported Content-Encoding {}, leaving the content as is",
+                c
+            );
+            return Ok(content);
+        }
+        if content.is_empty() {
+            return Ok(content);
+        }
+
+        let decoded = encoding::decode_all(&codings, content).map_err(ProtocolError::Decode)?;
+        headers.remove("Content-Encoding");
+        headers.remove("Content-Length");
+        Ok(decoded)
+    }
+
+    pub(crate) fn read_chunked<R: BufRead>(r: &mut R) -> HttpResult<Bytes> {
+        Self::read_chunked_max(r, MAX_BODY_LEN)
+    }
+
+    // Like `read_chunked`, but gives up once the chunks add up to more than `max` bytes
+    pub(crate) fn read_chunked_max<R: BufRead>(r: &mut R, max: usize) -> HttpResult<Bytes> {
+        let mut c_buf = Vec::new();
 
-                Ok(Self {
-                    status_code,
-                    headers,
-                    content: c_buf.freeze(),
-                })
+        loop {
+            let read_length = read_chunk_length(&read_line(r)?)?;
+            debug!("Chunk length: {}", read_length);
+
+            if read_length == 0 {
+                break;
+            }
+
+            grown(c_buf.len(), read_length, max)?;
+            read_exactly(r, read_length, &mut c_buf)?;
+
+            // every chunk ends with CRLF
+            if !read_line(r)?.is_empty() {
+                return Err(ProtocolError::InvalidChunk.into());
+            }
+        }
+
+        // trailers, which we don't use
+        while !read_line(r)?.is_empty() {}
+
+        Ok(Bytes::from(c_buf))
+    }
+
+    async fn read_chunked_async<R: AsyncBufRead + Unpin>(r: &mut R) -> HttpResult<Bytes> {
+        let mut c_buf = Vec::new();
+
+        loop {
+            let read_length = read_chunk_length(&read_line_async(r).await?)?;
+            if read_length == 0 {
+                break;
+            }
+
+            grown(c_buf.len(), read_length, MAX_BODY_LEN)?;
+            read_exactly_async(r, read_length, &mut c_buf).await?;
+
+            if !read_line_async(r).await?.is_empty() {
+                return Err(ProtocolError::InvalidChunk.into());
             }
-            Some(_) => unimplemented!(),
-            None => Ok(Self {
-                status_code,
-                headers,
-                content: Bytes::copy_from_slice(&d[t_count..]),
-            }),
         }
+
+        while !read_line_async(r).await?.is_empty() {}
+
+        Ok(Bytes::from(c_buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str) -> HttpResult<Response> {
+        Response::read_from(&mut raw.as_bytes(), &Methods::GET)
+    }
+
+    async fn read_async(raw: &str) -> HttpResult<Response> {
+        Response::read_from_async(&mut raw.as_bytes(), &Methods::GET).await
+    }
+
+    fn too_large(r: HttpResult<Response>) -> bool {
+        matches!(r, Err(HttpError::Protocol(ProtocolError::BodyTooLarge(_))))
+    }
+
+    fn truncated(r: HttpResult<Response>) -> bool {
+        matches!(r, Err(HttpError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof)
+    }
+
+    const CHUNKED: &str =
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n2\r\nde\r\n0\r\nT: 1\r\n\r\n";
+    const HUGE_LENGTH: &str = "HTTP/1.1 200 OK\r\nContent-Length: 99999999999\r\n\r\nabc";
+    const HUGE_CHUNK: &str =
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffff\r\nabc";
+    const OVERFLOWING_CHUNKS: &str = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+         1\r\na\r\nffffffffffffffff\r\nabc";
+    const TRUNCATED: &str = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc";
+    const TRUNCATED_CHUNK: &str = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\nabc";
+
+    #[test]
+    fn bodies() {
+        let resp = read("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcextra").unwrap();
+        assert_eq!(&resp.content[..], b"abc");
+        assert_eq!(&read(CHUNKED).unwrap().content[..], b"abcde");
+        let resp = read("HTTP/1.0 200 OK\r\n\r\nuntil close").unwrap();
+        assert_eq!(&resp.content[..], b"until close");
+        assert!(!resp.keep_alive());
+    }
+
+    #[test]
+    fn claimed_sizes_arent_allocated() {
+        assert!(too_large(read(HUGE_LENGTH)));
+        assert!(too_large(read(HUGE_CHUNK)));
+        assert!(too_large(read(OVERFLOWING_CHUNKS)));
+        let mut r = "4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n".as_bytes();
+        assert!(matches!(
+            Response::read_chunked_max(&mut r, 6),
+            Err(HttpError::Protocol(ProtocolError::BodyTooLarge(6)))
+        ));
+    }
+
+    #[test]
+    fn truncated_bodies() {
+        assert!(truncated(read(TRUNCATED)));
+        assert!(truncated(read(TRUNCATED_CHUNK)));
+    }
+
+    #[tokio::test]
+    async fn async_bodies() {
+        assert_eq!(&read_async(CHUNKED).await.unwrap().content[..], b"abcde");
+        assert!(too_large(read_async(HUGE_LENGTH).await));
+        assert!(too_large(read_async(HUGE_CHUNK).await));
+        assert!(too_large(read_async(OVERFLOWING_CHUNKS).await));
+        assert!(truncated(read_async(TRUNCATED).await));
+        assert!(truncated(read_async(TRUNCATED_CHUNK).await));
     }
 }
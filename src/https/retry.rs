@@ -0,0 +1,145 @@
+use super::client::Methods;
+use super::error::{is_retryable_status, HttpResult};
+use super::response::Response;
+use super::timeout::Timeouts;
+use super::util::random;
+use log::info;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+// Decides which failed requests are sent again and how long to wait before that.
+// Waits grow exponentially with full jitter, unless the server asks for a
+// specific wait with Retry-After.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    base_delay: Duration,
+    max_delay: Duration,
+    non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            non_idempotent: false,
+        }
+    }
+
+    // Every request is sent once
+    pub fn none() -> Self {
+        Self::new().max_attempts(1)
+    }
+
+    // Attempts in total, counting the first one
+    pub fn max_attempts(mut self, n: usize) -> Self {
+        self.max_attempts = n.max(1);
+        self
+    }
+
+    // The first wait is around `base`, no wait is longer than `max`.
+    // A Retry-After longer than `max` isn't waited for.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    // Also retry methods like POST, which might then be processed twice
+    pub fn retry_non_idempotent(mut self, b: bool) -> Self {
+        self.non_idempotent = b;
+        self
+    }
+
+    fn is_retryable(res: &HttpResult<Response>) -> bool {
+        match res {
+            Ok(resp) => is_retryable_status(resp.status_code),
+            Err(e) => e.is_retryable(),
+        }
+    }
+
+    // A random wait in [0, min(max, base * 2^attempt)]
+    fn backoff_delay(&self, attempt: usize) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        let nanos = exp.as_nanos() as u64;
+        if nanos == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(random() % (nanos + 1))
+    }
+
+    // How long to wait before sending again, None when giving up.
+    // `attempt` is the number of attempts made so far.
+    pub fn delay(
+        &self,
+        method: &Methods,
+        res: &HttpResult<Response>,
+        attempt: usize,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts
+            || !(method.is_idempotent() || self.non_idempotent)
+            || !Self::is_retryable(res)
+        {
+            return None;
+        }
+        match res.as_ref().ok().and_then(|r| r.headers.retry_after()) {
+            Some(d) if d > self.max_delay => None,
+            Some(d) => Some(d),
+            None => Some(self.backoff_delay(attempt - 1)),
+        }
+    }
+
+    // Calls `send` until it succeeds, fails in a way that isn't worth retrying,
+    // or the attempts run out. Waits never go past the timeouts' deadline.
+    pub fn run<F>(&self, method: &Methods, timeouts: &Timeouts, mut send: F) -> HttpResult<Response>
+    where
+        F: FnMut() -> HttpResult<Response>,
+    {
+        let mut attempt = 1;
+        loop {
+            let res = send();
+            match self.wait_before_retry(method, &res, attempt, timeouts) {
+                Some(delay) => thread::sleep(delay),
+                None => return res,
+            }
+            attempt += 1;
+        }
+    }
+
+    // Like `delay`, but also gives up when the wait would pass the deadline
+    pub fn wait_before_retry(
+        &self,
+        method: &Methods,
+        res: &HttpResult<Response>,
+        attempt: usize,
+        timeouts: &Timeouts,
+    ) -> Option<Duration> {
+        let delay = self.delay(method, res, attempt)?;
+        if timeouts
+            .deadline()
+            .is_some_and(|d| Instant::now() + delay >= d)
+        {
+            return None;
+        }
+        match res {
+            Ok(resp) => info!("Got {}, retrying in {:?}", resp.status_code, delay),
+            Err(e) => info!("Request failed ({}), retrying in {:?}", e, delay),
+        }
+        Some(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
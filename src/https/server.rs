@@ -0,0 +1,648 @@
+use super::client::Methods;
+use super::error::{HttpError, HttpResult, ProtocolError};
+use super::headers::{HeaderError, HeaderMap};
+use super::mock::is_incomplete;
+use super::request::{read_request_body, read_request_head};
+use log::{debug, info, warn};
+use std::io::{BufReader, Error, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How long a kept-alive connection may stay quiet before it's closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Defaults for `Server::max_body_len` and `Server::max_connections`
+const MAX_BODY_LEN: usize = 1024 * 1024;
+const MAX_CONNECTIONS: usize = 64;
+
+// A request received by the server
+#[derive(Debug, Clone)]
+pub struct ServerRequest {
+    pub method: Methods,
+    // as it was on the request line, path and query
+    pub target: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub peer: SocketAddr,
+}
+
+impl ServerRequest {
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((_, query)) => query,
+            None => "",
+        }
+    }
+
+    // The decoded query parameters, empty if the query can't be decoded
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        serde_urlencoded::from_str(self.query()).unwrap_or_default()
+    }
+
+    // The first value of a query parameter
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v)
+    }
+
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    // Unless the client asked for the connection to be closed
+    fn keep_alive(&self) -> bool {
+        !self
+            .headers
+            .connection()
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case("close"))
+    }
+}
+
+// A response to write back. Content-Length is always set by the server.
+#[derive(Debug, Clone)]
+pub struct ServerResponse {
+    status: u16,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl ServerResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(200)
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found")
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Self::new(status)
+            .content_type("text/plain; charset=utf-8")
+            .body(text)
+    }
+
+    pub fn html(status: u16, html: &str) -> Self {
+        Self::new(status)
+            .content_type("text/html; charset=utf-8")
+            .body(html)
+    }
+
+    pub fn json(status: u16, value: &serde_json::Value) -> Self {
+        Self::new(status)
+            .content_type("application/json")
+            .body(value.to_string())
+    }
+
+    fn content_type(mut self, value: &'static str) -> Self {
+        // a valid constant
+        let _ = self.headers.insert("Content-Type", value);
+        self
+    }
+
+    // Adds a header, names or values that would break the response
+    // (like ones with CR or LF from a request) are refused
+    pub fn header(mut self, name: &str, value: &str) -> Result<Self, HeaderError> {
+        self.headers.append(name, value)?;
+        Ok(self)
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn write_to(&self, w: &mut impl Write, method: &Methods, keep_alive: bool) -> HttpResult<()> {
+        let mut buf = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status)).into_bytes();
+        for (k, v) in self.headers.iter() {
+            let framing = k.eq_ignore_ascii_case("Content-Length")
+                || k.eq_ignore_ascii_case("Transfer-Encoding")
+                || k.eq_ignore_ascii_case("Connection");
+            if !framing {
+                buf.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
+            }
+        }
+        if !keep_alive {
+            buf.extend_from_slice(b"Connection: close\r\n");
+        }
+        buf.extend_from_slice(format!("Content-Length: {}\r\n\r\n", self.body.len()).as_bytes());
+        if !matches!(method, Methods::HEAD) {
+            buf.extend_from_slice(&self.body);
+        }
+        w.write_all(&buf)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+type Handler = Arc<dyn Fn(&ServerRequest) -> ServerResponse + Send + Sync>;
+
+// Picks the handler for a request by its method and path.
+// A path ending in "/*" matches everything below it.
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<(Methods, String, Handler)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<F>(mut self, method: Methods, path: &str, handler: F) -> Self
+    where
+        F: Fn(&ServerRequest) -> ServerResponse + Send + Sync + 'static,
+    {
+        self.routes
+            .push((method, path.to_string(), Arc::new(handler)));
+        self
+    }
+
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&ServerRequest) -> ServerResponse + Send + Sync + 'static,
+    {
+        self.route(Methods::GET, path, handler)
+    }
+
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&ServerRequest) -> ServerResponse + Send + Sync + 'static,
+    {
+        self.route(Methods::POST, path, handler)
+    }
+
+    fn path_matches(route: &str, path: &str) -> bool {
+        match route.strip_suffix("/*") {
+            Some(prefix) => path == prefix || path.starts_with(&format!("{}/", prefix)),
+            None => route == path,
+        }
+    }
+
+    // 404 without a route for the path, 405 if only the method differs.
+    // HEAD requests are handled like GET, without the body.
+    pub fn handle(&self, req: &ServerRequest) -> ServerResponse {
+        let mut allowed = Vec::new();
+        for (method, path, handler) in &self.routes {
+            if !Self::path_matches(path, req.path()) {
+                continue;
+            }
+            let head_as_get = *method == Methods::GET && req.method == Methods::HEAD;
+            if *method == req.method || head_as_get {
+                return handler(req);
+            }
+            allowed.push(method.as_str());
+        }
+        if allowed.is_empty() {
+            return ServerResponse::not_found();
+        }
+        let mut resp = ServerResponse::text(405, "method not allowed");
+        // method names are tokens
+        let _ = resp.headers.insert("Allow", &allowed.join(", "));
+        resp
+    }
+}
+
+// A small blocking HTTP/1.1 server, for OAuth redirects, local endpoints
+// and stand-in servers in tests. Every connection gets its own thread,
+// up to `max_connections` at once, and is kept alive until the client
+// closes it or stays quiet too long.
+pub struct Server {
+    listener: TcpListener,
+    router: Arc<Router>,
+    max_body_len: usize,
+    max_connections: usize,
+    open: Arc<AtomicUsize>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, router: Router) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Server listening on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            router: Arc::new(router),
+            max_body_len: MAX_BODY_LEN,
+            max_connections: MAX_CONNECTIONS,
+            open: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    // Requests with a larger body get a 413
+    pub fn max_body_len(mut self, len: usize) -> Self {
+        self.max_body_len = len;
+        self
+    }
+
+    // Connections past this many get a 503 and are closed
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = n;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr()
+    }
+
+    // Serves until accepting a connection fails
+    pub fn serve(self) -> Result<(), Error> {
+        self.serve_until(&AtomicBool::new(false))
+    }
+
+    fn serve_until(&self, stopped: &AtomicBool) -> Result<(), Error> {
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            if stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let open = OpenConnection::count(&self.open);
+            if open.0.load(Ordering::SeqCst) > self.max_connections {
+                warn!("Turning away {}, too many connections", peer);
+                let mut stream = stream;
+                if let Err(e) = ServerResponse::text(503, "too many connections").write_to(
+                    &mut stream,
+                    &Methods::GET,
+                    false,
+                ) {
+                    debug!("Couldn't turn away {}: {}", peer, e);
+                }
+                continue;
+            }
+            let router = self.router.clone();
+            let max_body_len = self.max_body_len;
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, peer, &router, max_body_len) {
+                    debug!("Connection from {} ended: {}", peer, e);
+                }
+                drop(open);
+            });
+        }
+    }
+
+    // Serves on a background thread until the handle is dropped
+    pub fn spawn(self) -> Result<ServerHandle, Error> {
+        let addr = self.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = stopped.clone();
+        thread::spawn(move || {
+            if let Err(e) = self.serve_until(&flag) {
+                warn!("Server on {} stopped: {}", addr, e);
+            }
+        });
+        Ok(ServerHandle { addr, stopped })
+    }
+}
+
+// A server running in the background, see `Server::spawn`
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stops accepting connections, the ones already open are served to the end
+    pub fn shutdown(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // wakes up the accepting thread so it sees the flag
+        if let Err(e) = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1)) {
+            debug!("Couldn't wake up the server on {}: {}", self.addr, e);
+        }
+        info!("Server on {} shut down", self.addr);
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Counts a connection as open until it's dropped
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn count(open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::SeqCst);
+        Self(open.clone())
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    router: &Router,
+    max_body_len: usize,
+) -> HttpResult<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let (method, target, headers) = match read_request_head(&mut reader) {
+            Ok(head) => head,
+            // closed, or quiet for too long, between requests
+            Err(e) if is_incomplete(&e) || is_timeout(&e) => return Ok(()),
+            Err(e) => {
+                ServerResponse::text(400, "bad request").write_to(
+                    &mut writer,
+                    &Methods::GET,
+                    false,
+                )?;
+                return Err(e);
+            }
+        };
+        let expects = headers
+            .get("Expect")
+            .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"));
+        // a body that's too large is refused before the client sends it
+        let too_large = headers
+            .get("Content-Length")
+            .and_then(|l| l.parse::<u64>().ok())
+            .is_some_and(|l| l > max_body_len as u64);
+        if expects && !too_large {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let body = match read_request_body(&mut reader, &headers, max_body_len) {
+            Ok(body) => body,
+            Err(e @ HttpError::Protocol(ProtocolError::BodyTooLarge(_))) => {
+                ServerResponse::text(413, "request body too large").write_to(
+                    &mut writer,
+                    &method,
+                    false,
+                )?;
+                return Err(e);
+            }
+            Err(e @ HttpError::Protocol(ProtocolError::UnsupportedTransferEncoding(_))) => {
+                ServerResponse::text(501, "unsupported transfer encoding").write_to(
+                    &mut writer,
+                    &method,
+                    false,
+                )?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let req = ServerRequest {
+            method,
+            target,
+            headers,
+            body,
+            peer,
+        };
+        let resp = router.handle(&req);
+        debug!(
+            "{} {:?} {} -> {}",
+            peer,
+            req.method,
+            req.path(),
+            resp.status()
+        );
+        let keep_alive = req.keep_alive();
+        resp.write_to(&mut writer, &req.method, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn is_timeout(e: &HttpError) -> bool {
+    match e {
+        HttpError::Io(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn echo_server() -> Server {
+        let router = Router::new().post("/echo", |req| ServerResponse::ok().body(req.body.clone()));
+        Server::bind("127.0.0.1:0", router).unwrap()
+    }
+
+    // Sends `raw` and reads until the server closes the connection
+    fn exchange(addr: SocketAddr, raw: &str) -> String {
+        try_exchange(addr, raw).unwrap()
+    }
+
+    fn try_exchange(addr: SocketAddr, raw: &str) -> Result<String, Error> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(raw.as_bytes())?;
+        let mut out = String::new();
+        stream.read_to_string(&mut out)?;
+        Ok(out)
+    }
+
+    fn request(method: Methods, target: &str) -> ServerRequest {
+        ServerRequest {
+            method,
+            target: target.to_string(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            peer: "127.0.0.1:1".parse().unwrap(),
+        }
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/a", |_| ServerResponse::text(200, "get a"))
+            .post("/a", |_| ServerResponse::text(201, "post a"))
+            .get("/files/*", |req| ServerResponse::text(200, req.path()))
+    }
+
+    fn body(resp: &ServerResponse) -> &str {
+        std::str::from_utf8(&resp.body).unwrap()
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        let router = router();
+        let resp = router.handle(&request(Methods::GET, "/a?q=1"));
+        assert_eq!((resp.status(), body(&resp)), (200, "get a"));
+        let resp = router.handle(&request(Methods::POST, "/a"));
+        assert_eq!((resp.status(), body(&resp)), (201, "post a"));
+
+        assert_eq!(router.handle(&request(Methods::GET, "/b")).status(), 404);
+        assert_eq!(router.handle(&request(Methods::GET, "/a/b")).status(), 404);
+        let resp = router.handle(&request(Methods::PUT, "/a"));
+        assert_eq!(resp.status(), 405);
+        assert_eq!(resp.headers.get("Allow"), Some("GET, POST"));
+    }
+
+    #[test]
+    fn prefix_routes() {
+        let router = router();
+        for path in ["/files", "/files/", "/files/x/y.txt"] {
+            let resp = router.handle(&request(Methods::GET, path));
+            assert_eq!((resp.status(), body(&resp)), (200, path));
+        }
+        assert_eq!(
+            router.handle(&request(Methods::GET, "/filesx")).status(),
+            404
+        );
+    }
+
+    #[test]
+    fn head_is_served_by_get() {
+        let router = router();
+        let resp = router.handle(&request(Methods::HEAD, "/a"));
+        assert_eq!((resp.status(), body(&resp)), (200, "get a"));
+
+        // the length is the GET's, but the body isn't sent
+        let server = Server::bind("127.0.0.1:0", router)
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let out = exchange(
+            server.local_addr(),
+            "HEAD /a HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("Content-Length: 5\r\n\r\n"));
+    }
+
+    #[test]
+    fn headers_cant_split_the_response() {
+        let resp = ServerResponse::new(302);
+        assert!(resp
+            .clone()
+            .header("Location", "/x\r\nSet-Cookie: a=b")
+            .is_err());
+        assert!(resp.clone().header("X\nY", "1").is_err());
+        let resp = resp
+            .header("Location", "/x")
+            .and_then(|r| r.header("Set-Cookie", "a=1"))
+            .and_then(|r| r.header("Set-Cookie", "b=2"))
+            .unwrap();
+        assert_eq!(resp.headers.get_all("Set-Cookie"), ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn bodies_within_the_limit() {
+        let server = echo_server().max_body_len(16).spawn().unwrap();
+        let out = exchange(
+            server.local_addr(),
+            "POST /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\nhello"));
+
+        let out = exchange(
+            server.local_addr(),
+            "POST /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\
+             Transfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+        );
+        assert!(out.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn large_bodies_get_413() {
+        let server = echo_server().max_body_len(16).spawn().unwrap();
+        // refused on the head alone, nothing is allocated for the body
+        let out = exchange(
+            server.local_addr(),
+            "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999\r\n\r\n",
+        );
+        assert!(out.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+        let out = exchange(
+            server.local_addr(),
+            "POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n0123456789abcdef\r\n1\r\n",
+        );
+        assert!(out.starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn large_bodies_arent_continued() {
+        let server = echo_server().max_body_len(16).spawn().unwrap();
+        let out = exchange(
+            server.local_addr(),
+            "POST /echo HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 17\r\n\r\n",
+        );
+        assert!(out.starts_with("HTTP/1.1 413 "));
+        assert!(!out.contains("100 Continue"));
+    }
+
+    #[test]
+    fn too_many_connections_get_503() {
+        let server = echo_server().max_connections(1).spawn().unwrap();
+        let first = TcpStream::connect(server.local_addr()).unwrap();
+        let out = exchange(server.local_addr(), "");
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // the slot is free again once the first connection is closed
+        drop(first);
+        let mut out = String::new();
+        for _ in 0..50 {
+            // a 503 closes with the body unread, which may reset the connection
+            out = try_exchange(
+                server.local_addr(),
+                "POST /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: 2\r\n\r\nhi",
+            )
+            .unwrap_or_default();
+            if out.starts_with("HTTP/1.1 200") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
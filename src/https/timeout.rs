@@ -0,0 +1,167 @@
+use super::resolver::Resolver;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Which limit ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    Read,
+    Total,
+}
+
+impl Display for TimeoutKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutKind::Connect => write!(f, "connecting timed out"),
+            TimeoutKind::Read => write!(f, "the server stopped sending data"),
+            TimeoutKind::Total => write!(f, "the request took too long"),
+        }
+    }
+}
+impl Error for TimeoutKind {}
+
+// An io error of kind TimedOut which remembers which limit ran out,
+// so it can be told apart from other errors further up
+pub fn error(kind: TimeoutKind) -> io::Error {
+    io::Error::new(ErrorKind::TimedOut, kind)
+}
+
+pub fn kind_of(e: &io::Error) -> Option<TimeoutKind> {
+    if e.kind() != ErrorKind::TimedOut {
+        return None;
+    }
+    e.get_ref()?.downcast_ref::<TimeoutKind>().copied()
+}
+
+// Opens a TCP connection to "host:port" with the global resolver,
+// see `Resolver::connect`. Resolving the name can't be limited with std.
+pub fn connect_tcp(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("invalid address {}", addr));
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    Resolver::global().connect(host, port, timeout)
+}
+
+// How long a request may take.
+// `connect` limits opening the connection, `read` how long the server may stay silent,
+// and `total` the whole request, redirects and retries included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    read: Option<Duration>,
+    total: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self {
+            connect: Some(DEFAULT_CONNECT_TIMEOUT),
+            read: Some(DEFAULT_READ_TIMEOUT),
+            total: None,
+            deadline: None,
+        }
+    }
+
+    // Waits forever
+    pub fn none() -> Self {
+        Self {
+            connect: None,
+            read: None,
+            total: None,
+            deadline: None,
+        }
+    }
+
+    pub fn connect(mut self, d: Duration) -> Self {
+        self.connect = Some(d);
+        self
+    }
+
+    pub fn read(mut self, d: Duration) -> Self {
+        self.read = Some(d);
+        self
+    }
+
+    pub fn total(mut self, d: Duration) -> Self {
+        self.total = Some(d);
+        self
+    }
+
+    // Starts the clock for the total timeout
+    pub fn started(mut self) -> Self {
+        self.deadline = self.total.map(|t| Instant::now() + t);
+        self
+    }
+
+    // Set once started
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    // The connect timeout, cut short by the deadline
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        min(self.connect, self.remaining())
+    }
+
+    // How long a single read or write may block, cut short by the deadline
+    pub fn io_timeout(&self) -> Option<Duration> {
+        min(self.read, self.remaining())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    // Arms a blocking socket with the read timeout or what's left until the deadline
+    pub fn arm(&self, sock: &TcpStream) -> io::Result<()> {
+        if self.is_expired() {
+            return Err(error(TimeoutKind::Total));
+        }
+        // a zero timeout isn't accepted by the socket
+        let t = self.io_timeout().map(|t| t.max(Duration::from_millis(1)));
+        sock.set_read_timeout(t)?;
+        sock.set_write_timeout(t)
+    }
+
+    // Blocking sockets report a timeout as WouldBlock (TimedOut on windows)
+    pub fn map_error(&self, e: io::Error) -> io::Error {
+        let timed_out = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+        if !timed_out || kind_of(&e).is_some() {
+            return e;
+        }
+        if self.is_expired() {
+            error(TimeoutKind::Total)
+        } else {
+            error(TimeoutKind::Read)
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn min(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
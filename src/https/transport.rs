@@ -0,0 +1,450 @@
+use super::proxy::{NoProxy, Proxy};
+use super::resolver::Resolver;
+use super::timeout;
+use super::timeout::{TimeoutKind, Timeouts};
+use super::url::Url;
+use crate::tls::async_tls_stream::AsyncTlsStream;
+use crate::tls::tls_stream::TlsStream;
+use log::{debug, info};
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+type TLSResult<T> = Result<T, Error>;
+
+// A connection to a server, encrypted or not.
+// The clients pick one by the url's scheme.
+pub trait Transport: Read + Write + Send {
+    // Limits how long the following reads and writes may block
+    fn set_timeouts(&mut self, timeouts: &Timeouts);
+
+    // Checks, without blocking, if the peer has closed the connection while it was idle
+    fn is_closed(&mut self) -> bool;
+}
+
+impl Transport for TlsStream {
+    fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        TlsStream::set_timeouts(self, timeouts);
+    }
+
+    fn is_closed(&mut self) -> bool {
+        TlsStream::is_closed(self)
+    }
+}
+
+// Opens transports in place of network connections,
+// like `Mock` does for tests and `Recorder` to record them
+pub trait Dialer: Debug + Send + Sync {
+    // `timeouts` are for dialers that open real connections
+    fn dial(&self, url: &Url, timeouts: &Timeouts) -> TLSResult<Box<dyn Transport>>;
+
+    fn dial_async(&self, url: &Url) -> TLSResult<Box<dyn AsyncTransport>>;
+
+    // Keeps the pooled connections of different dialers apart
+    fn name(&self) -> String;
+}
+
+// Opens connections, directly or through a proxy.
+// Every client has one, taken from the environment unless it's given another.
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    http: Option<Proxy>,
+    https: Option<Proxy>,
+    no_proxy: NoProxy,
+    dialer: Option<Arc<dyn Dialer>>,
+    resolver: Option<Resolver>,
+}
+
+impl Connector {
+    // Connects directly
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // HTTPS_PROXY, HTTP_PROXY and NO_PROXY
+    pub fn from_env() -> Self {
+        Self {
+            http: Proxy::from_env("http"),
+            https: Proxy::from_env("https"),
+            no_proxy: NoProxy::from_env(),
+            dialer: None,
+            resolver: None,
+        }
+    }
+
+    // Opens every http and https connection with `dialer`, proxies aren't used then
+    pub fn dialer(mut self, dialer: impl Dialer + 'static) -> Self {
+        self.dialer = Some(Arc::new(dialer));
+        self
+    }
+
+    // Resolves names and connects to them with `resolver` instead of the global one.
+    // Names sent to a proxy are resolved by the proxy.
+    pub fn resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    fn resolver_or_global(&self) -> &Resolver {
+        match &self.resolver {
+            Some(r) => r,
+            None => Resolver::global(),
+        }
+    }
+
+    // What connections go through besides the network, for telling pooled ones apart
+    pub fn route_for(&self, scheme: &str, host: &str) -> Option<String> {
+        if let Some(d) = &self.dialer {
+            return Some(d.name());
+        }
+        if let Some(p) = self.proxy_for(scheme, host) {
+            return Some(p.addr());
+        }
+        self.resolver_or_global()
+            .override_for(host)
+            .map(|addrs| format!("{:?}", addrs))
+    }
+
+    // Sends everything through `proxy`
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.http = Some(proxy.clone());
+        self.https = Some(proxy);
+        self
+    }
+
+    pub fn http_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.http = proxy;
+        self
+    }
+
+    pub fn https_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.https = proxy;
+        self
+    }
+
+    // Hosts which are connected to directly
+    pub fn no_proxy(mut self, no_proxy: NoProxy) -> Self {
+        self.no_proxy = no_proxy;
+        self
+    }
+
+    // The proxy used for a scheme and host, if any.
+    // Anything that isn't http goes through the https proxy.
+    pub fn proxy_for(&self, scheme: &str, host: &str) -> Option<&Proxy> {
+        if self.no_proxy.matches(host) {
+            return None;
+        }
+        match scheme {
+            "http" => self.http.as_ref(),
+            _ => self.https.as_ref(),
+        }
+    }
+
+    // A raw TCP connection to host:port, tunnelled through the proxy if there is one.
+    // Used for protocols other than HTTP, like Minecraft's.
+    pub fn tcp(&self, host: &str, port: u16, timeout: Option<Duration>) -> TLSResult<TcpStream> {
+        match self.proxy_for("tcp", host) {
+            Some(proxy) => proxy.connect(host, port, timeout),
+            None => self.resolver_or_global().connect(host, port, timeout),
+        }
+    }
+
+    pub async fn tcp_async(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> TLSResult<tokio::net::TcpStream> {
+        let proxy = match self.proxy_for("tcp", host) {
+            Some(p) => p.clone(),
+            None => {
+                return self
+                    .resolver_or_global()
+                    .connect_async(host, port, timeout)
+                    .await
+            }
+        };
+        // the handshake is short, so it's done blocking off the runtime
+        let host = host.to_string();
+        let sock =
+            match tokio::task::spawn_blocking(move || proxy.connect(&host, port, timeout)).await {
+                Ok(res) => res?,
+                Err(e) => return Err(Error::other(e)),
+            };
+        sock.set_nonblocking(true)?;
+        tokio::net::TcpStream::from_std(sock)
+    }
+
+    // Opens a connection for the url's scheme.
+    // Through a proxy, plain http also uses a CONNECT tunnel.
+    pub fn connect(&self, url: &Url, timeouts: &Timeouts) -> TLSResult<Box<dyn Transport>> {
+        if let Some(d) = &self.dialer {
+            let mut stream = d.dial(url, timeouts)?;
+            stream.set_timeouts(timeouts);
+            return Ok(stream);
+        }
+        let proxy = self.proxy_for(url.scheme(), url.domain());
+        let timeout = timeouts.connect_timeout();
+        let resolver = self.resolver_or_global();
+        let mut stream: Box<dyn Transport> = match (url.scheme(), proxy) {
+            ("https", None) => {
+                let sock = resolver.connect(url.domain(), url.port(), timeout)?;
+                Box::new(TlsStream::from_tcp(None, url.domain(), sock)?)
+            }
+            ("http", None) => Box::new(PlainStream::from_tcp(resolver.connect(
+                url.domain(),
+                url.port(),
+                timeout,
+            )?)),
+            ("https", Some(p)) => {
+                let sock = p.connect(url.domain(), url.port(), timeout)?;
+                Box::new(TlsStream::from_tcp(None, url.domain(), sock)?)
+            }
+            ("http", Some(p)) => Box::new(PlainStream::from_tcp(p.connect(
+                url.domain(),
+                url.port(),
+                timeout,
+            )?)),
+            (s, _) => return Err(unsupported_scheme(s)),
+        };
+        stream.set_timeouts(timeouts);
+        Ok(stream)
+    }
+
+    pub async fn connect_async(
+        &self,
+        url: &Url,
+        timeouts: &Timeouts,
+    ) -> TLSResult<Box<dyn AsyncTransport>> {
+        if let Some(d) = &self.dialer {
+            let mut stream = d.dial_async(url)?;
+            stream.set_timeouts(timeouts);
+            return Ok(stream);
+        }
+        let timeout = timeouts.connect_timeout();
+        // without a proxy, `tcp_async` goes through the resolver
+        let mut stream: Box<dyn AsyncTransport> = match url.scheme() {
+            "https" => {
+                let sock = self.tcp_async(url.domain(), url.port(), timeout).await?;
+                Box::new(AsyncTlsStream::from_tcp(None, url.domain(), sock, timeout).await?)
+            }
+            "http" => {
+                let sock = self.tcp_async(url.domain(), url.port(), timeout).await?;
+                Box::new(AsyncStream::new(sock))
+            }
+            s => return Err(unsupported_scheme(s)),
+        };
+        stream.set_timeouts(timeouts);
+        Ok(stream)
+    }
+}
+
+fn unsupported_scheme(scheme: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported scheme: {}", scheme),
+    )
+}
+
+// Plain TCP, for http:// urls
+pub struct PlainStream {
+    sock: TcpStream,
+    timeouts: Timeouts,
+}
+
+impl PlainStream {
+    pub fn connect(addr: &str, timeout: Option<Duration>) -> TLSResult<Self> {
+        let sock = timeout::connect_tcp(addr, timeout)?;
+        info!("Connected to {} without TLS", addr);
+        Ok(Self::from_tcp(sock))
+    }
+
+    pub fn from_tcp(sock: TcpStream) -> Self {
+        Self {
+            sock,
+            timeouts: Timeouts::none(),
+        }
+    }
+}
+
+impl Transport for PlainStream {
+    fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.timeouts = *timeouts;
+    }
+
+    // Unexpected data also counts as closed,
+    // since it can't belong to a request we haven't sent yet
+    fn is_closed(&mut self) -> bool {
+        if self.sock.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let mut byte = [0u8; 1];
+        let closed = match self.sock.peek(&mut byte) {
+            Ok(_) => true,
+            Err(ref e) => e.kind() != ErrorKind::WouldBlock,
+        };
+        if self.sock.set_nonblocking(false).is_err() {
+            return true;
+        }
+        if closed {
+            debug!("Connection was closed by the peer");
+        }
+        closed
+    }
+}
+
+impl Read for PlainStream {
+    fn read(&mut self, buf: &mut [u8]) -> TLSResult<usize> {
+        self.timeouts.arm(&self.sock)?;
+        self.sock.read(buf).map_err(|e| self.timeouts.map_error(e))
+    }
+}
+
+impl Write for PlainStream {
+    fn write(&mut self, buf: &[u8]) -> TLSResult<usize> {
+        self.timeouts.arm(&self.sock)?;
+        self.sock.write(buf).map_err(|e| self.timeouts.map_error(e))
+    }
+
+    fn flush(&mut self) -> TLSResult<()> {
+        self.sock.flush()
+    }
+}
+
+// Transport for tokio
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send {
+    // Limits how long the following reads and writes may wait
+    fn set_timeouts(&mut self, timeouts: &Timeouts);
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for AsyncStream<S> {
+    fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        AsyncStream::set_timeouts(self, timeouts);
+    }
+}
+
+pub type AsyncPlainStream = AsyncStream<tokio::net::TcpStream>;
+
+impl AsyncPlainStream {
+    pub async fn connect(addr: &str, timeout: Option<Duration>) -> TLSResult<Self> {
+        Ok(AsyncStream::new(Self::tcp(addr, timeout).await?))
+    }
+
+    async fn tcp(addr: &str, timeout: Option<Duration>) -> TLSResult<tokio::net::TcpStream> {
+        let connect = tokio::net::TcpStream::connect(addr);
+        let sock = match timeout {
+            Some(t) => match tokio::time::timeout(t, connect).await {
+                Ok(res) => res?,
+                Err(_) => return Err(timeout::error(TimeoutKind::Connect)),
+            },
+            None => connect.await?,
+        };
+        info!("Connected to {} without TLS", addr);
+        Ok(sock)
+    }
+}
+
+// Wraps a tokio stream so reads and writes fail with a timeout error
+// when it stays silent for longer than the timeouts allow
+pub struct AsyncStream<S> {
+    inner: S,
+    timeouts: Timeouts,
+    // runs while an operation is pending, reset once it makes progress
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> AsyncStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            timeouts: Timeouts::none(),
+            timer: None,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.timeouts = *timeouts;
+        self.timer = None;
+    }
+
+    // Called when the inner stream is pending.
+    // Ready with an error once the timer runs out.
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<Error> {
+        if self.timer.is_none() {
+            match self.timeouts.io_timeout() {
+                Some(t) => self.timer = Some(Box::pin(sleep(t))),
+                None => return Poll::Pending,
+            }
+        }
+        let timer = match &mut self.timer {
+            Some(t) => t,
+            None => return Poll::Pending,
+        };
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.timer = None;
+                if self.timeouts.is_expired() {
+                    Poll::Ready(timeout::error(TimeoutKind::Total))
+                } else {
+                    Poll::Ready(timeout::error(TimeoutKind::Read))
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_with_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        res: Poll<TLSResult<T>>,
+    ) -> Poll<TLSResult<T>> {
+        match res {
+            Poll::Ready(r) => {
+                self.timer = None;
+                Poll::Ready(r)
+            }
+            Poll::Pending => self.poll_timeout(cx).map(Err),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AsyncStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<TLSResult<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.poll_with_timeout(cx, res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AsyncStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<TLSResult<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.poll_with_timeout(cx, res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TLSResult<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_flush(cx);
+        this.poll_with_timeout(cx, res)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TLSResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,102 +1,839 @@
-use regex::Regex;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 // parsing urls.
+// follows the generic syntax from RFC 3986, restricted to
+// urls that have an authority (scheme://host/...), which is
+// everything the clients can actually connect to.
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UrlError {
-    InvalidUrl(&'static str),
-    RegexError,
+    Empty,
+    NoScheme,
+    InvalidScheme,
+    NoAuthority,
+    EmptyHost,
+    InvalidHost,
+    InvalidIpv6,
+    InvalidPort,
+    NoDefaultPort(String),
+    InvalidCharacter(char),
+    InvalidPercentEncoding,
+    InvalidUtf8,
 }
 impl Display for UrlError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            UrlError::InvalidUrl(t) => write!(f, "{}", t),
-            UrlError::RegexError => write!(f, "the regex failed to compile"),
+            UrlError::Empty => write!(f, "the url is empty"),
+            UrlError::NoScheme => write!(f, "no scheme in url"),
+            UrlError::InvalidScheme => write!(f, "the scheme contains invalid characters"),
+            UrlError::NoAuthority => write!(f, "the url has no authority (missing `//`)"),
+            UrlError::EmptyHost => write!(f, "no domain in url"),
+            UrlError::InvalidHost => write!(f, "the domain contains invalid characters"),
+            UrlError::InvalidIpv6 => write!(f, "invalid IPv6 literal"),
+            UrlError::InvalidPort => write!(f, "the port is not a number between 0 and 65535"),
+            UrlError::NoDefaultPort(s) => {
+                write!(f, "no port given and scheme `{}` has no default port", s)
+            }
+            UrlError::InvalidCharacter(c) => write!(f, "invalid character {:?} in url", c),
+            UrlError::InvalidPercentEncoding => write!(f, "malformed percent-encoding"),
+            UrlError::InvalidUtf8 => write!(f, "percent-decoded data is not valid UTF-8"),
         }
     }
 }
 impl Error for UrlError {}
 
-pub struct Url<'a> {
-    route: &'a str,
-    domain: &'a str,
-    scheme: &'a str,
-    port: u16,
-    query: &'a str,
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Url {
+    scheme: String,
+    userinfo: Option<String>,
+    domain: String,
+    port: Option<u16>,
+    route: String,
+    query: Option<String>,
+    fragment: Option<String>,
 }
 
-impl Debug for Url<'_> {
+impl Debug for Url {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Url")
             .field("route", &self.route)
             .field("domain", &self.domain)
             .field("scheme", &self.scheme)
-            .field("port", &self.port)
+            .field("port", &self.port())
             .field("query", &self.query)
+            .field("fragment", &self.fragment)
+            .field("userinfo", &self.userinfo)
             .finish()
     }
 }
 
-impl<'a> Url<'a> {
-    pub fn new(u: &'a str) -> Result<Url<'a>, UrlError> {
-        let reg = match Regex::new(
-            r"(?<scheme>.*?)://(?<domain>.*?[^\\]?)(?<route>/.*?[^\?]*)(?<query>.*)",
-        ) {
-            Ok(r) => r,
-            Err(_) => return Err(UrlError::RegexError),
-        };
+// Default port for the schemes we know about
+pub fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        "socks5" | "socks5h" => Some(1080),
+        _ => None,
+    }
+}
+
+// unreserved = ALPHA / DIGIT / "-" / "." / "_" / "~"
+fn is_unreserved(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~')
+}
+
+// sub-delims = "!" / "$" / "&" / "'" / "(" / ")" / "*" / "+" / "," / ";" / "="
+fn is_sub_delim(c: char) -> bool {
+    matches!(
+        c,
+        '!' | '$' | '&' | '\'' | '(' | ')' | '*' | '+' | ',' | ';' | '='
+    )
+}
+
+// pchar = unreserved / pct-encoded / sub-delims / ":" / "@"
+// ('%' is checked separately by `validate`)
+fn is_pchar(c: char) -> bool {
+    is_unreserved(c) || is_sub_delim(c) || matches!(c, ':' | '@')
+}
+
+// Checks that every character of `s` is allowed by `allowed`
+// and that every '%' starts a valid percent-encoded octet.
+fn validate(s: &str, allowed: fn(char) -> bool) -> Result<(), UrlError> {
+    let bytes = s.as_bytes();
+    for (i, c) in s.char_indices() {
+        if c == '%' {
+            let valid = bytes.len() > i + 2
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit();
+            if !valid {
+                return Err(UrlError::InvalidPercentEncoding);
+            }
+        } else if !allowed(c) {
+            return Err(UrlError::InvalidCharacter(c));
+        }
+    }
+    Ok(())
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Decodes `%XX` sequences. The result has to be valid UTF-8.
+pub fn percent_decode(s: &str) -> Result<String, UrlError> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hi = bytes.get(i + 1).and_then(|b| hex_value(*b));
+            let lo = bytes.get(i + 2).and_then(|b| hex_value(*b));
+            match (hi, lo) {
+                (Some(h), Some(l)) => out.push(h << 4 | l),
+                _ => return Err(UrlError::InvalidPercentEncoding),
+            }
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| UrlError::InvalidUtf8)
+}
 
-        let matches = match reg.captures(u) {
-            None => return Err(UrlError::InvalidUrl("couldn't identify the url")),
+// Encodes every byte of `s` that isn't `keep` as `%XX`
+fn percent_encode(s: &str, keep: fn(char) -> bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if keep(c) {
+            out.push(c);
+        } else {
+            let mut utf8 = [0; 4];
+            for b in c.encode_utf8(&mut utf8).bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    out
+}
+
+// Encodes a single path segment, so '/' inside of it is escaped too
+pub fn encode_path_segment(s: &str) -> String {
+    percent_encode(s, is_pchar)
+}
+
+// Encodes a key or value the application/x-www-form-urlencoded way:
+// only unreserved characters are kept and spaces become '+'
+pub fn encode_query_component(s: &str) -> String {
+    percent_encode(s, |c| is_unreserved(c) || c == ' ').replace(' ', "+")
+}
+
+// Removes "." and ".." segments from a path (RFC 3986 section 5.2.4)
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = Vec::new();
+    let segments: Vec<&str> = path.split('/').collect();
+    let last = segments.len() - 1;
+
+    for (i, seg) in segments.iter().enumerate() {
+        match *seg {
+            "." => {
+                // "/a/." keeps the trailing slash
+                if i == last {
+                    output.push("");
+                }
+            }
+            ".." => {
+                if output.len() > 1 {
+                    output.pop();
+                }
+                if i == last {
+                    output.push("");
+                }
+            }
+            s => output.push(s),
+        }
+    }
+
+    let joined = output.join("/");
+    if joined.starts_with('/') {
+        joined
+    } else {
+        format!("/{}", joined)
+    }
+}
+
+// Checks if a reference starts with a scheme, like "https:"
+fn has_scheme(reference: &str) -> bool {
+    match reference.split_once(':') {
+        None => false,
+        Some((scheme, _)) => {
+            let mut chars = scheme.chars();
+            chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+                && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+    }
+}
+
+impl Url {
+    pub fn new(u: &str) -> Result<Url, UrlError> {
+        if u.is_empty() {
+            return Err(UrlError::Empty);
+        }
+        if let Some(c) = u.chars().find(|c| c.is_whitespace() || c.is_control()) {
+            return Err(UrlError::InvalidCharacter(c));
+        }
+
+        // scheme = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+        let (scheme, rest) = match u.split_once(':') {
             Some(o) => o,
+            None => return Err(UrlError::NoScheme),
+        };
+        if scheme.is_empty() {
+            return Err(UrlError::NoScheme);
+        }
+        let mut scheme_chars = scheme.chars();
+        let valid_scheme = scheme_chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && scheme_chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+        if !valid_scheme {
+            return Err(UrlError::InvalidScheme);
+        }
+        let scheme = scheme.to_ascii_lowercase();
+
+        let rest = match rest.strip_prefix("//") {
+            Some(r) => r,
+            None => return Err(UrlError::NoAuthority),
         };
 
-        let scheme = match &matches.name("scheme") {
-            None => return Err(UrlError::InvalidUrl("no scheme in url")),
-            Some(o) => o.as_str(),
+        // fragment and query are split off first,
+        // since they may contain '/', '?', '@' and ':'
+        let (rest, fragment) = match rest.split_once('#') {
+            Some((r, f)) => (r, Some(f)),
+            None => (rest, None),
         };
-        let route = match &matches.name("route") {
-            None => "/",
-            Some(o) => o.as_str(),
+        let (rest, query) = match rest.split_once('?') {
+            Some((r, q)) => (r, Some(q)),
+            None => (rest, None),
         };
-        let domain = match &matches.name("domain") {
-            None => return Err(UrlError::InvalidUrl("no domain in url")),
-            Some(o) => o.as_str(),
+        let (authority, route) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
         };
-        let query = &matches.name("query").map_or("", |o| o.as_str());
-        let port = match scheme {
-            "http" => 80,
-            "https" => 443,
-            "ftp" => 21,
-            _ => 1919, // IDK
+
+        // userinfo cannot contain an unencoded '@', so the last one separates it
+        let (userinfo, host_port) = match authority.rfind('@') {
+            Some(i) => (Some(&authority[..i]), &authority[i + 1..]),
+            None => (None, authority),
         };
+        if let Some(ui) = userinfo {
+            validate(ui, |c| is_unreserved(c) || is_sub_delim(c) || c == ':')?;
+        }
+
+        let (domain, port) = Self::parse_host_port(host_port)?;
+        if port.is_none() && default_port(&scheme).is_none() {
+            return Err(UrlError::NoDefaultPort(scheme));
+        }
+
+        validate(route, |c| is_pchar(c) || c == '/')?;
+        if let Some(q) = query {
+            validate(q, |c| is_pchar(c) || matches!(c, '/' | '?'))?;
+        }
+        if let Some(f) = fragment {
+            validate(f, |c| is_pchar(c) || matches!(c, '/' | '?'))?;
+        }
 
         Ok(Url {
-            route,
-            domain,
             scheme,
+            userinfo: userinfo.map(str::to_string),
+            domain,
             port,
-            query,
+            route: if route.is_empty() {
+                "/".to_string()
+            } else {
+                route.to_string()
+            },
+            query: query.map(str::to_string),
+            fragment: fragment.map(str::to_string),
         })
     }
+
+    // host = IP-literal / IPv4address / reg-name, optionally followed by ":" port
+    fn parse_host_port(s: &str) -> Result<(String, Option<u16>), UrlError> {
+        let (domain, port) = if let Some(literal) = s.strip_prefix('[') {
+            let (ip, after) = match literal.split_once(']') {
+                Some(o) => o,
+                None => return Err(UrlError::InvalidIpv6),
+            };
+            if ip.parse::<Ipv6Addr>().is_err() {
+                return Err(UrlError::InvalidIpv6);
+            }
+            let port = match after {
+                "" => None,
+                p => match p.strip_prefix(':') {
+                    Some(p) => Some(p),
+                    None => return Err(UrlError::InvalidIpv6),
+                },
+            };
+            (ip.to_ascii_lowercase(), port)
+        } else {
+            let (host, port) = match s.split_once(':') {
+                Some((h, p)) => (h, Some(p)),
+                None => (s, None),
+            };
+            if host.is_empty() {
+                return Err(UrlError::EmptyHost);
+            }
+            validate(host, |c| is_unreserved(c) || is_sub_delim(c)).map_err(|e| match e {
+                UrlError::InvalidCharacter(_) => UrlError::InvalidHost,
+                e => e,
+            })?;
+            (host.to_ascii_lowercase(), port)
+        };
+
+        // an empty port ("host:") means the default one
+        let port = match port {
+            None | Some("") => None,
+            Some(p) => {
+                if !p.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(UrlError::InvalidPort);
+                }
+                match p.parse::<u16>() {
+                    Ok(n) => Some(n),
+                    Err(_) => return Err(UrlError::InvalidPort),
+                }
+            }
+        };
+        Ok((domain, port))
+    }
+
     pub fn socket_addr(&self) -> String {
-        format!("{}:{}", self.domain, self.port)
+        if self.is_ipv6() {
+            format!("[{}]:{}", self.domain, self.port())
+        } else {
+            format!("{}:{}", self.domain, self.port())
+        }
+    }
+    // value for the `Host` header, the port is left out if it's the default one
+    pub fn host_header(&self) -> String {
+        let host = if self.is_ipv6() {
+            format!("[{}]", self.domain)
+        } else {
+            self.domain.clone()
+        };
+        match self.port {
+            Some(p) if Some(p) != default_port(&self.scheme) => format!("{}:{}", host, p),
+            _ => host,
+        }
     }
-    pub fn route(&self) -> &'a str {
-        self.route
+    // path and query, as they are sent in the request line
+    pub fn request_target(&self) -> String {
+        match &self.query {
+            Some(q) => format!("{}?{}", self.route, q),
+            None => self.route.clone(),
+        }
+    }
+    pub fn route(&self) -> &str {
+        &self.route
     }
-    pub fn domain(&self) -> &'a str {
-        self.domain
+    pub fn domain(&self) -> &str {
+        &self.domain
     }
-    pub fn query(&self) -> &'a str {
-        self.query
+    pub fn query(&self) -> &str {
+        self.query.as_deref().unwrap_or("")
     }
-    pub fn scheme(&self) -> &'a str {
-        self.scheme
+    pub fn scheme(&self) -> &str {
+        &self.scheme
     }
     pub fn port(&self) -> u16 {
-        self.port
+        match self.port {
+            Some(p) => p,
+            // checked while parsing
+            None => default_port(&self.scheme).unwrap_or(0),
+        }
+    }
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+    pub fn userinfo(&self) -> Option<&str> {
+        self.userinfo.as_deref()
+    }
+    pub fn is_ipv6(&self) -> bool {
+        self.domain.contains(':')
+    }
+
+    // Decoded user name and password from the userinfo
+    pub fn username(&self) -> Result<Option<String>, UrlError> {
+        match &self.userinfo {
+            None => Ok(None),
+            Some(ui) => Ok(Some(percent_decode(
+                ui.split_once(':').map_or(ui.as_str(), |(u, _)| u),
+            )?)),
+        }
+    }
+    pub fn password(&self) -> Result<Option<String>, UrlError> {
+        match self.userinfo.as_ref().and_then(|ui| ui.split_once(':')) {
+            None => Ok(None),
+            Some((_, p)) => Ok(Some(percent_decode(p)?)),
+        }
+    }
+
+    // Decoded path segments, "/a/b%20c" -> ["a", "b c"]
+    pub fn path_segments(&self) -> Result<Vec<String>, UrlError> {
+        if self.route == "/" {
+            return Ok(vec![]);
+        }
+        self.route[1..].split('/').map(percent_decode).collect()
+    }
+
+    // Resolves a relative reference against this url,
+    // for example a `Location` header (RFC 3986 section 5.2)
+    pub fn join(&self, reference: &str) -> Result<Url, UrlError> {
+        if has_scheme(reference) {
+            let mut url = Url::new(reference)?;
+            url.route = remove_dot_segments(&url.route);
+            return Ok(url);
+        }
+        if reference.starts_with("//") {
+            let mut url = Url::new(&format!("{}:{}", self.scheme, reference))?;
+            url.route = remove_dot_segments(&url.route);
+            return Ok(url);
+        }
+        if let Some(c) = reference
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(UrlError::InvalidCharacter(c));
+        }
+
+        let (rest, fragment) = match reference.split_once('#') {
+            Some((r, f)) => (r, Some(f)),
+            None => (reference, None),
+        };
+        let (path, query) = match rest.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (rest, None),
+        };
+        validate(path, |c| is_pchar(c) || c == '/')?;
+        if let Some(q) = query {
+            validate(q, |c| is_pchar(c) || matches!(c, '/' | '?'))?;
+        }
+        if let Some(f) = fragment {
+            validate(f, |c| is_pchar(c) || matches!(c, '/' | '?'))?;
+        }
+
+        let mut url = self.clone();
+        url.fragment = fragment.map(str::to_string);
+        if path.is_empty() {
+            if let Some(q) = query {
+                url.query = Some(q.to_string());
+            }
+            return Ok(url);
+        }
+
+        url.query = query.map(str::to_string);
+        url.route = if path.starts_with('/') {
+            remove_dot_segments(path)
+        } else {
+            // merge with everything up to the last '/' of the base path
+            let base = match self.route.rfind('/') {
+                Some(i) => &self.route[..=i],
+                None => "/",
+            };
+            remove_dot_segments(&format!("{}{}", base, path))
+        };
+        Ok(url)
+    }
+
+    pub fn builder(&self) -> UrlBuilder {
+        UrlBuilder::from(self.clone())
+    }
+
+    // Decoded query pairs, '+' is treated as a space
+    // like in application/x-www-form-urlencoded
+    pub fn query_pairs(&self) -> Result<Vec<(String, String)>, UrlError> {
+        let query = match &self.query {
+            None => return Ok(vec![]),
+            Some(q) => q,
+        };
+        query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (k, v) = p.split_once('=').unwrap_or((p, ""));
+                Ok((
+                    percent_decode(&k.replace('+', " "))?,
+                    percent_decode(&v.replace('+', " "))?,
+                ))
+            })
+            .collect()
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.scheme)?;
+        if let Some(ui) = &self.userinfo {
+            write!(f, "{}@", ui)?;
+        }
+        if self.is_ipv6() {
+            write!(f, "[{}]", self.domain)?;
+        } else {
+            write!(f, "{}", self.domain)?;
+        }
+        if let Some(p) = self.port {
+            write!(f, ":{}", p)?;
+        }
+        write!(f, "{}", self.request_target())?;
+        if let Some(fr) = &self.fragment {
+            write!(f, "#{}", fr)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Url {
+    type Err = UrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Url::new(s)
+    }
+}
+
+// Url Builder
+// Takes a base url and appends to it, encoding everything on the way.
+pub struct UrlBuilder {
+    url: Url,
+}
+
+impl UrlBuilder {
+    pub fn new(base: &str) -> Result<Self, UrlError> {
+        Ok(Self {
+            url: Url::new(base)?,
+        })
+    }
+
+    // Appends one path segment, "/" inside of it gets escaped
+    pub fn segment(&mut self, s: &str) -> &mut Self {
+        if !self.url.route.ends_with('/') {
+            self.url.route.push('/');
+        }
+        self.url.route.push_str(&encode_path_segment(s));
+        self
+    }
+
+    pub fn segments(&mut self, s: &[&str]) -> &mut Self {
+        for seg in s {
+            self.segment(seg);
+        }
+        self
+    }
+
+    // Appends `key=value` to the query
+    pub fn query_pair(&mut self, key: &str, value: &str) -> &mut Self {
+        let pair = format!(
+            "{}={}",
+            encode_query_component(key),
+            encode_query_component(value)
+        );
+        match &mut self.url.query {
+            Some(q) if !q.is_empty() => {
+                q.push('&');
+                q.push_str(&pair);
+            }
+            q => *q = Some(pair),
+        }
+        self
+    }
+
+    pub fn port(&mut self, p: u16) -> &mut Self {
+        self.url.port = Some(p);
+        self
+    }
+
+    pub fn fragment(&mut self, f: &str) -> &mut Self {
+        self.url.fragment = Some(percent_encode(f, |c| is_pchar(c) || matches!(c, '/' | '?')));
+        self
+    }
+
+    pub fn build(&self) -> Url {
+        self.url.clone()
+    }
+}
+
+impl From<Url> for UrlBuilder {
+    fn from(url: Url) -> Self {
+        Self { url }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 3986 section 5.4, references without an authority
+    // ("g:h", "http:g") aren't urls the clients can use
+    const BASE: &str = "http://a/b/c/d;p?q";
+    const RESOLVED: &[(&str, &str)] = &[
+        // 5.4.1 normal examples
+        ("g", "http://a/b/c/g"),
+        ("./g", "http://a/b/c/g"),
+        ("g/", "http://a/b/c/g/"),
+        ("/g", "http://a/g"),
+        ("//g", "http://g"),
+        ("?y", "http://a/b/c/d;p?y"),
+        ("g?y", "http://a/b/c/g?y"),
+        ("#s", "http://a/b/c/d;p?q#s"),
+        ("g#s", "http://a/b/c/g#s"),
+        ("g?y#s", "http://a/b/c/g?y#s"),
+        (";x", "http://a/b/c/;x"),
+        ("g;x", "http://a/b/c/g;x"),
+        ("g;x?y#s", "http://a/b/c/g;x?y#s"),
+        ("", "http://a/b/c/d;p?q"),
+        (".", "http://a/b/c/"),
+        ("./", "http://a/b/c/"),
+        ("..", "http://a/b/"),
+        ("../", "http://a/b/"),
+        ("../g", "http://a/b/g"),
+        ("../..", "http://a/"),
+        ("../../", "http://a/"),
+        ("../../g", "http://a/g"),
+        // 5.4.2 abnormal examples
+        ("../../../g", "http://a/g"),
+        ("../../../../g", "http://a/g"),
+        ("/./g", "http://a/g"),
+        ("/../g", "http://a/g"),
+        ("g.", "http://a/b/c/g."),
+        (".g", "http://a/b/c/.g"),
+        ("g..", "http://a/b/c/g.."),
+        ("..g", "http://a/b/c/..g"),
+        ("./../g", "http://a/b/g"),
+        ("./g/.", "http://a/b/c/g/"),
+        ("g/./h", "http://a/b/c/g/h"),
+        ("g/../h", "http://a/b/c/h"),
+        ("g;x=1/./y", "http://a/b/c/g;x=1/y"),
+        ("g;x=1/../y", "http://a/b/c/y"),
+        ("g?y/./x", "http://a/b/c/g?y/./x"),
+        ("g?y/../x", "http://a/b/c/g?y/../x"),
+        ("g#s/./x", "http://a/b/c/g#s/./x"),
+        ("g#s/../x", "http://a/b/c/g#s/../x"),
+    ];
+
+    #[test]
+    fn join_reference_resolution() {
+        let base = Url::new(BASE).unwrap();
+        for (reference, expected) in RESOLVED {
+            assert_eq!(
+                base.join(reference),
+                Url::new(expected),
+                "joining {:?}",
+                reference
+            );
+        }
+        assert_eq!(base.join("g:h"), Err(UrlError::NoAuthority));
+        assert_eq!(
+            base.join("https://b/x/../y").unwrap().to_string(),
+            "https://b/y"
+        );
+    }
+
+    #[test]
+    fn dot_segments() {
+        assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(remove_dot_segments("mid/content=5/../6"), "/mid/6");
+        assert_eq!(remove_dot_segments("/.."), "/");
+        assert_eq!(remove_dot_segments("/a/."), "/a/");
+    }
+
+    #[test]
+    fn parts() {
+        let url = Url::new("HTTPS://Discord.com/api/v10/users?x=1#frag").unwrap();
+        assert_eq!(url.scheme(), "https");
+        assert_eq!(url.domain(), "discord.com");
+        assert_eq!(url.port(), 443);
+        assert_eq!(url.route(), "/api/v10/users");
+        assert_eq!(url.query(), "x=1");
+        assert_eq!(url.fragment(), Some("frag"));
+        assert_eq!(url.request_target(), "/api/v10/users?x=1");
+        assert_eq!(url.host_header(), "discord.com");
+        assert_eq!(Url::new("http://a").unwrap().route(), "/");
+    }
+
+    #[test]
+    fn explicit_ports() {
+        let url = Url::new("http://localhost:6636/cb").unwrap();
+        assert_eq!(url.port(), 6636);
+        assert_eq!(url.socket_addr(), "localhost:6636");
+        assert_eq!(url.host_header(), "localhost:6636");
+        // the default port isn't repeated in the Host header
+        assert_eq!(Url::new("https://a:443/").unwrap().host_header(), "a");
+        assert_eq!(Url::new("https://a:/").unwrap().port(), 443);
+        assert_eq!(Url::new("socks5://a").unwrap().port(), 1080);
+        assert_eq!(
+            Url::new("gopher://a"),
+            Err(UrlError::NoDefaultPort("gopher".to_string()))
+        );
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        let url = Url::new("http://[::1]:8080/x").unwrap();
+        assert!(url.is_ipv6());
+        assert_eq!(url.domain(), "::1");
+        assert_eq!(url.port(), 8080);
+        assert_eq!(url.socket_addr(), "[::1]:8080");
+        assert_eq!(url.host_header(), "[::1]:8080");
+        assert_eq!(url.to_string(), "http://[::1]:8080/x");
+        let url = Url::new("https://[2001:DB8::1]/").unwrap();
+        assert_eq!(url.domain(), "2001:db8::1");
+        assert_eq!(url.socket_addr(), "[2001:db8::1]:443");
+        assert_eq!(Url::new("http://[::1/"), Err(UrlError::InvalidIpv6));
+        assert_eq!(Url::new("http://[zz::1]/"), Err(UrlError::InvalidIpv6));
+        assert_eq!(Url::new("http://[::1]x/"), Err(UrlError::InvalidIpv6));
+    }
+
+    #[test]
+    fn userinfo() {
+        let url = Url::new("http://us%20er:p%40ss:word@proxy:3128").unwrap();
+        assert_eq!(url.userinfo(), Some("us%20er:p%40ss:word"));
+        assert_eq!(url.username().unwrap().as_deref(), Some("us er"));
+        assert_eq!(url.password().unwrap().as_deref(), Some("p@ss:word"));
+        assert_eq!(url.domain(), "proxy");
+        assert_eq!(url.to_string(), "http://us%20er:p%40ss:word@proxy:3128/");
+        let url = Url::new("http://user@a/").unwrap();
+        assert_eq!(url.username().unwrap().as_deref(), Some("user"));
+        assert_eq!(url.password().unwrap(), None);
+        assert_eq!(Url::new("http://a/").unwrap().username().unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(Url::new(""), Err(UrlError::Empty));
+        assert_eq!(Url::new("discord.com"), Err(UrlError::NoScheme));
+        assert_eq!(Url::new("://a"), Err(UrlError::NoScheme));
+        assert_eq!(Url::new("1http://a"), Err(UrlError::InvalidScheme));
+        assert_eq!(Url::new("mailto:a@b"), Err(UrlError::NoAuthority));
+        assert_eq!(Url::new("http:///x"), Err(UrlError::EmptyHost));
+        assert_eq!(
+            Url::new("http://a b/"),
+            Err(UrlError::InvalidCharacter(' '))
+        );
+        assert_eq!(Url::new("http://a\"b/"), Err(UrlError::InvalidHost));
+        assert_eq!(Url::new("http://a:port/"), Err(UrlError::InvalidPort));
+        assert_eq!(Url::new("http://a:65536/"), Err(UrlError::InvalidPort));
+        assert_eq!(Url::new("http://a:+80/"), Err(UrlError::InvalidPort));
+        assert_eq!(
+            Url::new("http://a/b<c"),
+            Err(UrlError::InvalidCharacter('<'))
+        );
+        assert_eq!(
+            Url::new("http://a/%zz"),
+            Err(UrlError::InvalidPercentEncoding)
+        );
+        assert_eq!(
+            Url::new("http://a/?q=%4"),
+            Err(UrlError::InvalidPercentEncoding)
+        );
+        assert!(Url::new("http://a/b").unwrap().join("c d").is_err());
+    }
+
+    #[test]
+    fn percent_coding() {
+        assert_eq!(percent_decode("a%20b%2Fc").unwrap(), "a b/c");
+        assert_eq!(percent_decode("%C3%A9").unwrap(), "é");
+        assert_eq!(percent_decode("%FF"), Err(UrlError::InvalidUtf8));
+        assert_eq!(percent_decode("%2"), Err(UrlError::InvalidPercentEncoding));
+        assert_eq!(encode_path_segment("a b/c?é"), "a%20b%2Fc%3F%C3%A9");
+        assert_eq!(encode_path_segment("a:b@c;d=e"), "a:b@c;d=e");
+        assert_eq!(encode_query_component("a b&c=d+é"), "a+b%26c%3Dd%2B%C3%A9");
+        let url = Url::new("http://a/b%20c/d?k+1=v%26w&x").unwrap();
+        assert_eq!(url.path_segments().unwrap(), ["b c", "d"]);
+        assert_eq!(
+            url.query_pairs().unwrap(),
+            [
+                ("k 1".to_string(), "v&w".to_string()),
+                ("x".to_string(), String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let url = UrlBuilder::new("https://discord.com/api/v10")
+            .unwrap()
+            .segments(&["channels", "12/34"])
+            .segment("messages")
+            .query_pair("limit", "5")
+            .query_pair("q", "a b&c")
+            .fragment("x y")
+            .build();
+        assert_eq!(
+            url.to_string(),
+            "https://discord.com/api/v10/channels/12%2F34/messages?limit=5&q=a+b%26c#x%20y"
+        );
+        assert_eq!(url.path_segments().unwrap()[3], "12/34");
+
+        let url = UrlBuilder::new("http://a/?x=1")
+            .unwrap()
+            .segment("b")
+            .port(8080)
+            .query_pair("y", "2")
+            .build();
+        assert_eq!(url.to_string(), "http://a:8080/b?x=1&y=2");
+        let again = url.builder().query_pair("z", "3").build();
+        assert_eq!(again.query(), "x=1&y=2&z=3");
+        // an empty query is replaced instead of getting a leading '&'
+        let url = UrlBuilder::new("http://a/?")
+            .unwrap()
+            .query_pair("k", "v")
+            .build();
+        assert_eq!(url.query(), "k=v");
     }
 }
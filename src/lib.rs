@@ -0,0 +1,4 @@
+// The HTTP and TLS stack, written like a library so the bot
+// in main.rs only has to use the parts it needs
+pub mod https;
+pub mod tls;
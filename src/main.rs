@@ -1,9 +1,7 @@
-use discord::client::DiscordClient;
-use discord::message::MessageBuilder;
+// not sending anything yet, see the commented out lines in main
+#[allow(dead_code)]
 mod discord;
-mod https;
 mod microsoft;
-mod tls;
 
 use microsoft::oauth2::get_oauth2_code;
 use microsoft::xboxlive::login_to_minecraft;
@@ -35,8 +33,8 @@ fn main() {
         .map(|()| log::set_max_level(LevelFilter::Debug))
         .unwrap();
 
-    //let mut cl = DiscordClient::new("");
-    //let message = MessageBuilder::new().content("Ahaha!").build();
+    //let mut cl = discord::client::DiscordClient::new("");
+    //let message = discord::message::MessageBuilder::new().content("Ahaha!").build();
 
     //cl.send_message(message, "1296137217604849704").unwrap();
 
@@ -1,17 +1,24 @@
-use crate::https::client::HttpsClient;
-use crate::https::response::Response;
+use bigeon_rust::https::client::{HttpsClient, Methods};
+use bigeon_rust::https::request::RequestBuilder;
+use bigeon_rust::https::server::{Router, Server, ServerRequest, ServerResponse};
+use bigeon_rust::https::transport::Connector;
+use bigeon_rust::https::url::{Url, UrlBuilder};
 use log::info;
-use regex::Regex;
 use serde::Deserialize;
 use serde_json;
-use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::net::TcpListener;
-use std::str;
+use std::sync::mpsc;
+use std::time::Duration;
 
 const ACCESS_TOKEN_URL: &str = "https://login.microsoftonline.com/consumers/oauth2/v2.0/token";
+const AUTHORIZE_URL: &str = "https://login.microsoftonline.com/consumers/oauth2/v2.0/authorize";
+const REDIRECT_URI: &str = "http://localhost:6636";
+const REDIRECT_ADDR: &str = "127.0.0.1:6636";
+const STATE: &str = "wersal";
+// how long the user has to log in
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Deserialize)]
 struct Oauth2Settings {
@@ -19,6 +26,7 @@ struct Oauth2Settings {
     client_id: String,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct MsTokenResponse {
     pub access_token: String,
@@ -38,79 +46,164 @@ pub fn get_oauth2_code() -> Result<MsTokenResponse, Box<dyn Error>> {
     info!("Read Oauth2 settings from oauth2.json");
 
     // oauth2 route used for logging in
-    let oauth2_route: String = format!("consumers/oauth2/v2.0/authorize?client_id={}&response_type=code&redirect_uri=http://localhost:6636&scope=XboxLive.signin&response_mode=query&state=wersal", settings.client_id);
-    // regex for code and status
-    let re = Regex::new(r"code=(?<code>.*?[^&]*)&state=(?<state>\w{6})")?;
+    let oauth2_url = UrlBuilder::new(AUTHORIZE_URL)?
+        .query_pair("client_id", &settings.client_id)
+        .query_pair("response_type", "code")
+        .query_pair("redirect_uri", REDIRECT_URI)
+        .query_pair("scope", "XboxLive.signin")
+        .query_pair("response_mode", "query")
+        .query_pair("state", STATE)
+        .build();
 
     // login
-    println!(
-        "Log in here: https://login.microsoftonline.com/{}",
-        oauth2_route
-    );
+    println!("Log in here: {}", oauth2_url);
 
     info!("Starting oauth2 chain!");
 
-    // tcp server to redirect to
-    let server = TcpListener::bind("127.0.0.1:6636")?;
-    let mut buf = Vec::new();
-    let mut conn = server.accept()?;
-    conn.0.read_to_end(&mut buf)?;
-    let mut redirect = str::from_utf8(&buf)?.split_whitespace();
-
-    // obtains the route after GET
-    let response_route = match redirect.next() {
-        Some(method) => {
-            if method != "GET" {
-                panic!("Sent response was NOT a GET request.");
-            }
-            match redirect.next() {
-                None => panic!("No request URI was sent!"),
-                Some(r) => r,
-            }
-        }
-        None => panic!("No valid response was sent."),
-    };
-
-    // checks if there is anything present
-    let captures = match re.captures(response_route) {
-        None => panic!("Couldn't find either code nor state in the response!"),
-        Some(o) => o,
-    };
-
-    // captures the ode
-    let code = match captures.name("code") {
-        None => panic!("No oauth2 code obtained."),
-        Some(c) => c.as_str(),
-    };
-    println!("{:?}", captures);
-    match captures.name("state") {
-        None => panic!("No state was received!"),
-        Some(st) => {
-            if st.as_str() != "wersal" {
-                println!("{}", &st.as_str());
-            };
+    // server the browser is redirected to
+    let code = wait_for_code(REDIRECT_ADDR, LOGIN_TIMEOUT)?;
+    info!("Obtained code! Getting the access token");
+
+    exchange_code(
+        &Connector::from_env(),
+        &settings.client_id,
+        &settings.client_secret,
+        &code,
+    )
+}
+
+// The code, or why there's none, from the request the login page redirects to
+fn code_from_callback(req: &ServerRequest) -> Result<String, String> {
+    if let Some(error) = req.query_param("error") {
+        let description = req.query_param("error_description").unwrap_or_default();
+        return Err(format!("Login failed: {} {}", error, description));
+    }
+    if req.query_param("state").as_deref() != Some(STATE) {
+        return Err("The redirect's state doesn't match the login's".to_string());
+    }
+    req.query_param("code")
+        .ok_or_else(|| "No oauth2 code in the redirect".to_string())
+}
+
+// Serves the redirect uri until the login page sends the browser back to it,
+// with either a code or an error. Anything else sent to "/" gets a 400 and
+// other paths, like the browser's favicon, get a 404.
+pub fn wait_for_code(addr: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let router = Router::new().get("/", move |req| {
+        if req.query_param("code").is_none() && req.query_param("error").is_none() {
+            return ServerResponse::text(400, "Waiting for the login redirect");
         }
+        let result = code_from_callback(req);
+        let page = match &result {
+            Ok(_) => ServerResponse::html(200, "Logged in, you can close this tab."),
+            Err(e) => ServerResponse::text(400, e),
+        };
+        // only the first answer is waited for
+        let _ = tx.send(result);
+        page
+    });
+    let _server = Server::bind(addr, router)?.spawn()?;
+    match rx.recv_timeout(timeout) {
+        Ok(result) => Ok(result?),
+        Err(_) => Err("Timed out waiting for the login redirect".into()),
     }
+}
 
-    // post form with our obtained code
-    let access_token_post_form: String  = format!("client_id={}&scope=XboxLive.signin&redirect_uri=http://localhost:6636&grant_type=authorization_code&code={}&client_secret={}", settings.client_id, code, settings.client_secret);
-    info!("Obtained code! Getting the access token");
+// Trades the code from the redirect for an access token
+pub fn exchange_code(
+    connector: &Connector,
+    client_id: &str,
+    client_secret: &str,
+    code: &str,
+) -> Result<MsTokenResponse, Box<dyn Error>> {
+    let access_token_post_form = [
+        ("client_id", client_id),
+        ("scope", "XboxLive.signin"),
+        ("redirect_uri", REDIRECT_URI),
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("client_secret", client_secret),
+    ];
+
+    let mut client = HttpsClient::new("Bigeon/0.0.2", None)?;
+    client.set_connector(connector.clone());
+    let req = RequestBuilder::new(Url::new(ACCESS_TOKEN_URL)?)
+        .http_method(Methods::POST)
+        .form(&access_token_post_form);
+
+    let response = client.send(req)?;
+    let token_struct = response.json::<MsTokenResponse>()?;
 
-    let mut client = HttpsClient::new("Bigeon/0.0.2", None);
-    let mut headers: HashMap<&str, &str> = HashMap::new();
-    headers.insert("Content-Type", "application/x-www-form-urlencoded");
+    info!("Obtained Access token!");
+
+    Ok(token_struct)
+}
 
-    let bytes = client
-        .post(
-            ACCESS_TOKEN_URL,
-            access_token_post_form.into_bytes(),
-            Some(headers),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // Sends a GET for `target` to the redirect server, once it's up
+    fn get(addr: &str, target: &str) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(s) => break s,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            target
         )
         .unwrap();
-    let response = Response::from_slice(&bytes).unwrap();
-    let token_struct = serde_json::from_slice::<MsTokenResponse>(&response.content)?;
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
 
-    info!("Obtained Access token!");
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
 
-    Ok(token_struct)
+    #[test]
+    fn waits_for_the_redirect() {
+        let addr = free_addr();
+        let waiting = {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                wait_for_code(&addr, Duration::from_secs(10)).map_err(|e| e.to_string())
+            })
+        };
+
+        assert!(get(&addr, "/favicon.ico").starts_with("HTTP/1.1 404 "));
+        // a stray hit on the redirect uri doesn't end the wait
+        assert!(get(&addr, "/").starts_with("HTTP/1.1 400 "));
+        assert!(get(&addr, "/?state=wersal").starts_with("HTTP/1.1 400 "));
+        let page = get(&addr, "/?code=abc&state=wersal");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        assert_eq!(waiting.join().unwrap(), Ok("abc".to_string()));
+    }
+
+    #[test]
+    fn login_errors_end_the_wait() {
+        let addr = free_addr();
+        let waiting = {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                wait_for_code(&addr, Duration::from_secs(10)).map_err(|e| e.to_string())
+            })
+        };
+
+        let page = get(&addr, "/?error=access_denied&error_description=nope");
+        assert!(page.starts_with("HTTP/1.1 400 "));
+        let err = waiting.join().unwrap().unwrap_err();
+        assert_eq!(err, "Login failed: access_denied nope");
+    }
 }
@@ -1,11 +1,12 @@
-use crate::https::client::HttpsClient;
-use crate::https::persistent_client::PersistentClient;
-use crate::https::response::Response;
-use log::debug;
+use bigeon_rust::https::client::HttpsClient;
+use bigeon_rust::https::headers::HeaderMap;
+use bigeon_rust::https::persistent_client::PersistentClient;
+use bigeon_rust::https::pool::ConnectionPool;
+use bigeon_rust::https::response::Response;
+use bigeon_rust::https::transport::Connector;
 use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
 use std::error::Error;
 // This region is used to handle Xbox Live requests and responses.
 // from this we will be able to get a XL token and a Userhash.
@@ -31,6 +32,7 @@ struct XLR_Properties<'a> {
 
 // Implements a function to serialize a request into Vec<u8>
 impl<'a> XboxLiveRequest<'a> {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(token: &str) -> Vec<u8> {
         info!("Created a XboxLive request.");
         let props = XLR_Properties {
@@ -50,6 +52,7 @@ impl<'a> XboxLiveRequest<'a> {
 }
 
 // Xbox Live Response
+#[allow(dead_code)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 #[derive(Deserialize)]
@@ -94,6 +97,7 @@ struct XSTSRequest {
 }
 
 impl XSTSRequest {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(xbl_token: &str) -> Vec<u8> {
         info!("Created a XSTS Request.");
         let req = XSTSRequest {
@@ -117,6 +121,7 @@ struct MCLogin {
     identityToken: String,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 struct MCLoginResponse {
     username: String,
@@ -127,6 +132,7 @@ struct MCLoginResponse {
 }
 
 impl MCLogin {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(uh: &str, xsts_token: &str) -> Vec<u8> {
         info!("Created a Minecraft Login request.");
         serde_json::to_vec(&MCLogin {
@@ -167,74 +173,200 @@ struct MCProfile {
 }
 
 pub fn login_to_minecraft(access_token: &str) -> Result<(String, String, String), Box<dyn Error>> {
+    login_to_minecraft_with(&Connector::from_env(), access_token)
+}
+
+// Same as `login_to_minecraft`, with every connection opened by `connector`
+pub fn login_to_minecraft_with(
+    connector: &Connector,
+    access_token: &str,
+) -> Result<(String, String, String), Box<dyn Error>> {
     // headers to say we want json data and send json data
-    let json_headers = vec![
+    let json_headers = HeaderMap::from_pairs(&[
         ("Accept", "application/json"),
         ("Content-Type", "application/json"),
-    ]
-    .into_iter()
-    .collect::<HashMap<&str, &str>>();
+    ])?;
     info!("Started login process!");
 
     // client and response
-    let mut client = HttpsClient::new(USER_AGENT, Some(&json_headers));
+    let mut client = HttpsClient::new(USER_AGENT, Some(&json_headers))?;
+    client.set_connector(connector.clone());
     let mut response: Response;
 
     // xboxlive
-    let reply = client.post(
+    response = client.post(
         "https://user.auth.xboxlive.com/user/authenticate",
         XboxLiveRequest::new(access_token),
         None,
-    )?.;
-    response = Response::from_slice(&reply)?;
+    )?;
     info!("Sent XboxLive request to the API.");
-    let xl_response = serde_json::from_slice::<XboxLiveResponse>(&response.content)?;
+    let xl_response = response.json::<XboxLiveResponse>()?;
     info!("Received reply from XboxLive!");
-    drop(reply);
 
     // xsts
-    let reply = client.post(
+    response = client.post(
         "https://xsts.auth.xboxlive.com/xsts/authorize",
         XSTSRequest::new(&xl_response.Token),
         None,
     )?;
     info!("Sending request to XSTS!");
-    response = Response::from_slice(&reply)?;
 
-    let xsts_response = serde_json::from_slice::<XboxLiveResponse>(&response.content)?;
+    let xsts_response = response.json::<XboxLiveResponse>()?;
     let (xsts_token, userhash) = (xsts_response.Token, &xsts_response.DisplayClaims.xui[0].uhs);
     info!("Got response from XSTS!");
 
     drop(client);
 
-    let mut client =
-        PersistentClient::new(USER_AGENT, Some(json_headers), "api.minecraftservices.com")?;
+    let mut client = PersistentClient::with_connector(
+        USER_AGENT,
+        "https://api.minecraftservices.com",
+        ConnectionPool::global().clone(),
+        connector.clone(),
+    )?;
+    client.default_headers(&json_headers);
 
     // login with xbox -> minecraft
     info!("Obtaining login info for minecraft!");
-    let reply = client.post(
-        "https://api.minecraftservices.com/authentication/login_with_xbox",
-        &MCLogin::new(&userhash, &xsts_token),
-        None,
-    )?;
-    response = Response::from_slice(&reply)?;
+    let mc_login = MCLogin::new(userhash, &xsts_token);
+    response = client
+        .post("https://api.minecraftservices.com/authentication/login_with_xbox")?
+        .content(&mc_login)
+        .execute(&mut client)?;
 
-    let mc_response = serde_json::from_slice::<MCLoginResponse>(&response.content)?;
+    let mc_response = response.json::<MCLoginResponse>()?;
     let jwt = mc_response.access_token;
 
     // get minecraft profile
     info!("Fetching minecraft profile!");
     let bearer = format!("Bearer: {}", jwt);
-    let header = vec![("Authorization", bearer.as_str())]
-        .into_iter()
-        .collect::<HashMap<&str, &str>>();
-
-    let reply = client.get(
-        "https://api.minecraftservices.com/minecraft/profile",
-        Some(header),
-    )?;
-    response = Response::from_slice(&reply)?;
-    let mc_profile = serde_json::from_slice::<MCProfile>(&response.content)?;
+    response = client
+        .get("https://api.minecraftservices.com/minecraft/profile")?
+        .header(("Authorization", &bearer))
+        .execute(&mut client)?;
+    let mc_profile = response.json::<MCProfile>()?;
     info!("Fetched minecraft profile: {}", mc_profile.name);
     Ok((jwt, mc_profile.id, mc_profile.name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigeon_rust::https::client::Methods;
+    use bigeon_rust::https::mock::{Mock, Route};
+    use serde_json::{json, Value};
+
+    const XBL_URL: &str = "https://user.auth.xboxlive.com/user/authenticate";
+    const XSTS_URL: &str = "https://xsts.auth.xboxlive.com/xsts/authorize";
+    const LOGIN_URL: &str = "https://api.minecraftservices.com/authentication/login_with_xbox";
+    const PROFILE_URL: &str = "https://api.minecraftservices.com/minecraft/profile";
+
+    fn xbox_reply(token: &str) -> Value {
+        json!({
+            "IssueInstant": "2024-01-01T00:00:00Z",
+            "NotAfter": "2024-01-02T00:00:00Z",
+            "Token": token,
+            "DisplayClaims": {"xui": [{"uhs": "hash"}]}
+        })
+    }
+
+    fn login_chain() -> Mock {
+        Mock::new()
+            .route(
+                Route::post(XBL_URL)
+                    .unwrap()
+                    .json(&xbox_reply("xbl"))
+                    .times(1),
+            )
+            .route(
+                Route::post(XSTS_URL)
+                    .unwrap()
+                    .json(&xbox_reply("xsts"))
+                    .times(1),
+            )
+            .route(
+                Route::post(LOGIN_URL)
+                    .unwrap()
+                    .json(&json!({
+                        "username": "uuid-ish",
+                        "roles": [],
+                        "access_token": "jwt",
+                        "token_type": "Bearer",
+                        "expires_in": 86400
+                    }))
+                    .times(1),
+            )
+            .route(
+                Route::get(PROFILE_URL)
+                    .unwrap()
+                    .json(&json!({"id": "abcd", "name": "Steve", "Skins": [], "Capes": []}))
+                    .times(1),
+            )
+    }
+
+    fn body_json(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[test]
+    fn logs_in() {
+        let mock = login_chain();
+        let connector = Connector::new().dialer(mock.clone());
+        let (jwt, uuid, name) = login_to_minecraft_with(&connector, "ms-token").unwrap();
+        assert_eq!(
+            (jwt.as_str(), uuid.as_str(), name.as_str()),
+            ("jwt", "abcd", "Steve")
+        );
+        assert!(mock.is_done());
+
+        let requests = mock.requests();
+        let urls: Vec<String> = requests.iter().map(|r| r.url.to_string()).collect();
+        assert_eq!(urls, [XBL_URL, XSTS_URL, LOGIN_URL, PROFILE_URL]);
+        for req in &requests {
+            assert_eq!(req.headers.get("User-Agent"), Some(USER_AGENT));
+            assert_eq!(req.headers.get("Accept"), Some("application/json"));
+        }
+
+        let xbl = body_json(&requests[0].body);
+        assert_eq!(xbl["Properties"]["RpsTicket"], "d=ms-token");
+        assert_eq!(
+            requests[0].headers.get("Content-Type"),
+            Some("application/json")
+        );
+        let xsts = body_json(&requests[1].body);
+        assert_eq!(xsts["Properties"]["UserTokens"], json!(["xbl"]));
+        let login = body_json(&requests[2].body);
+        assert_eq!(login["identityToken"], "XBL3.0 x=hash;xsts");
+        assert_eq!(requests[3].method, Methods::GET);
+        assert_eq!(
+            requests[3].headers.get("Authorization"),
+            Some("Bearer: jwt")
+        );
+        // login and profile share the api.minecraftservices.com connection
+        assert_eq!(mock.connections(), 3);
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let mock = Mock::new()
+            .route(
+                Route::post(XBL_URL)
+                    .unwrap()
+                    .json(&xbox_reply("xbl"))
+                    .times(1),
+            )
+            .route(
+                Route::post(XSTS_URL)
+                    .unwrap()
+                    .status(401)
+                    .json(&json!({"XErr": 2148916233u64}))
+                    .times(1),
+            )
+            .route(Route::post(LOGIN_URL).unwrap().times(1));
+        let connector = Connector::new().dialer(mock.clone());
+        assert!(login_to_minecraft_with(&connector, "ms-token").is_err());
+
+        assert_eq!(mock.requests().len(), 2);
+        // the login route was never asked
+        assert!(!mock.is_done());
+    }
+}
@@ -1 +1 @@
-pub mod microsoft;
+
@@ -0,0 +1,196 @@
+use crate::https::timeout;
+use crate::https::timeout::TimeoutKind;
+use crate::https::transport::AsyncStream;
+use crate::tls::tls_stream::default_config;
+use log::info;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::client;
+use tokio_rustls::TlsConnector;
+type TLSResult<T> = Result<T, Error>;
+
+// TlsStream for tokio, with the same timeouts
+pub type AsyncTlsStream = AsyncStream<client::TlsStream<TcpStream>>;
+
+impl AsyncTlsStream {
+    // Connects and does the handshake, both limited by `timeout`
+    pub async fn connect(
+        config: Option<&Arc<ClientConfig>>,
+        url: &str,
+        addr: &str,
+        timeout: Option<Duration>,
+    ) -> TLSResult<Self> {
+        let (cfg, server_name) = setup(config, url)?;
+        let connect = async {
+            let sock = TcpStream::connect(addr).await?;
+            TlsConnector::from(cfg).connect(server_name, sock).await
+        };
+        let inner = with_timeout(timeout, connect).await?;
+        info!("Connected to {}", addr);
+
+        Ok(AsyncStream::new(inner))
+    }
+
+    // TLS over a socket that's already connected, like a proxy tunnel.
+    // Only the handshake is limited by `timeout`.
+    pub async fn from_tcp(
+        config: Option<&Arc<ClientConfig>>,
+        url: &str,
+        sock: TcpStream,
+        timeout: Option<Duration>,
+    ) -> TLSResult<Self> {
+        let (cfg, server_name) = setup(config, url)?;
+        let connect = TlsConnector::from(cfg).connect(server_name, sock);
+        Ok(AsyncStream::new(with_timeout(timeout, connect).await?))
+    }
+}
+
+fn setup(
+    config: Option<&Arc<ClientConfig>>,
+    url: &str,
+) -> TLSResult<(Arc<ClientConfig>, ServerName<'static>)> {
+    let cfg = match config {
+        Some(c) => Arc::clone(c),
+        None => default_config(),
+    };
+    match ServerName::try_from(url.to_string()) {
+        Ok(name) => Ok((cfg, name)),
+        Err(e) => Err(Error::new(ErrorKind::InvalidInput, e)),
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = TLSResult<T>>,
+) -> TLSResult<T> {
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(timeout::error(TimeoutKind::Connect)),
+        },
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{RootCertStore, ServerConfig};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    // A self-signed certificate for localhost, and a client config trusting it
+    fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server), Arc::new(client))
+    }
+
+    // Answers every line with the same line, uppercased
+    async fn shouting_server(config: Arc<ServerConfig>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                let acceptor = TlsAcceptor::from(config.clone());
+                tokio::spawn(async move {
+                    let Ok(tls) = acceptor.accept(sock).await else {
+                        return;
+                    };
+                    let mut tls = BufReader::new(tls);
+                    let mut line = String::new();
+                    while tls.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let _ = tls
+                            .get_mut()
+                            .write_all(line.to_uppercase().as_bytes())
+                            .await;
+                        line.clear();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn talks_tls() {
+        let (server, client) = configs();
+        let addr = shouting_server(server).await;
+        let timeout = Some(Duration::from_secs(5));
+        let mut tls = AsyncTlsStream::connect(Some(&client), "localhost", &addr, timeout)
+            .await
+            .unwrap();
+        tls.write_all(b"hello\n").await.unwrap();
+        let mut buf = [0; 6];
+        tls.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO\n");
+
+        let sock = TcpStream::connect(&addr).await.unwrap();
+        let mut tls = AsyncTlsStream::from_tcp(Some(&client), "localhost", sock, timeout)
+            .await
+            .unwrap();
+        tls.write_all(b"again\n").await.unwrap();
+        tls.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"AGAIN\n");
+    }
+
+    #[tokio::test]
+    async fn checks_the_certificate() {
+        let (server, client) = configs();
+        let addr = shouting_server(server).await;
+        // not one of the public roots
+        let err = AsyncTlsStream::connect(None, "localhost", &addr, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // not the name on the certificate
+        let err = AsyncTlsStream::connect(Some(&client), "example.com", &addr, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(
+            AsyncTlsStream::connect(Some(&client), "not a name", &addr, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn handshakes_time_out() {
+        // accepts, then never says anything
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((sock, _)) = listener.accept().await {
+                open.push(sock);
+            }
+        });
+        let err =
+            AsyncTlsStream::connect(None, "localhost", &addr, Some(Duration::from_millis(100)))
+                .await
+                .err()
+                .unwrap();
+        assert_eq!(timeout::kind_of(&err), Some(TimeoutKind::Connect));
+    }
+}
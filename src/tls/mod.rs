@@ -1 +1,2 @@
+pub mod async_tls_stream;
 pub mod tls_stream;
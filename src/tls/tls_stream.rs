@@ -1,8 +1,11 @@
+use crate::https::timeout;
+use crate::https::timeout::Timeouts;
 use log::{debug, error, info};
 use rustls::{ClientConfig, ClientConnection, RootCertStore};
 use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use webpki_roots::TLS_SERVER_ROOTS;
 type TLSResult<T> = Result<T, Error>;
 
@@ -12,48 +15,115 @@ pub struct TlsStream {
     pub(crate) buf_w: BufWriter<TcpStream>,
     #[allow(dead_code)]
     pub(crate) sock: TcpStream,
+    timeouts: Timeouts,
+}
+
+// Trusts the webpki roots, built once and shared by every connection
+pub fn default_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let cfg = CONFIG.get_or_init(|| {
+        let root_store: RootCertStore = RootCertStore {
+            roots: TLS_SERVER_ROOTS.into(),
+        };
+        Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        )
+    });
+    Arc::clone(cfg)
 }
 
 impl TlsStream {
     pub fn new(config: Option<&Arc<ClientConfig>>, url: &str, addr: &str) -> TLSResult<Self> {
+        Self::connect(config, url, addr, None)
+    }
+
+    pub fn connect(
+        config: Option<&Arc<ClientConfig>>,
+        url: &str,
+        addr: &str,
+        timeout: Option<Duration>,
+    ) -> TLSResult<Self> {
+        let sock = timeout::connect_tcp(addr, timeout)?;
+        info!("Connected to {}", addr);
+        Self::from_tcp(config, url, sock)
+    }
+
+    // TLS over a socket that's already connected, like a proxy tunnel
+    pub fn from_tcp(
+        config: Option<&Arc<ClientConfig>>,
+        url: &str,
+        sock: TcpStream,
+    ) -> TLSResult<Self> {
         info!("Creating DNS name for {}", url);
-        let sock = TcpStream::connect(addr)?;
         let server_name = match url.to_string().try_into() {
             Ok(name) => name,
-            Err(_) => panic!("Invalid DNS name!"),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
         };
 
         // if supplied config
         // use that
-        let cfg = if let Some(c) = config {
-            Arc::clone(c)
-        } else {
-            let root_store: RootCertStore = RootCertStore {
-                roots: TLS_SERVER_ROOTS.into(),
-            };
-            Arc::new(
-                ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth(),
-            )
+        let cfg = match config {
+            Some(c) => Arc::clone(c),
+            None => default_config(),
         };
 
         // tls connection
         let client_conn = match ClientConnection::new(cfg, server_name) {
             Ok(conn) => conn,
-            Err(_) => return Err(Error::from(ErrorKind::ConnectionAborted)),
+            Err(e) => return Err(Error::new(ErrorKind::ConnectionAborted, e)),
         };
 
-        info!("Connected to {}", addr);
-
         Ok(Self {
             conn: client_conn,
             buf_r: BufReader::new(TcpStream::try_clone(&sock)?),
             buf_w: BufWriter::new(TcpStream::try_clone(&sock)?),
             sock,
+            timeouts: Timeouts::none(),
         })
     }
 
+    // Limits how long the following reads and writes may block
+    pub fn set_timeouts(&mut self, timeouts: &Timeouts) {
+        self.timeouts = *timeouts;
+    }
+
+    // Checks, without blocking, if the peer has closed the connection
+    // while it was idle. Unexpected data also counts as closed,
+    // since it can't belong to a request we haven't sent yet.
+    pub fn is_closed(&mut self) -> bool {
+        if self.sock.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let mut closed = false;
+        let mut byte = [0u8; 1];
+        match self.sock.peek(&mut byte) {
+            Ok(0) => closed = true,
+            Ok(_) => {
+                // probably an alert or a session ticket, let rustls look at it
+                closed = match self.conn.read_tls(&mut self.buf_r) {
+                    Ok(0) => true,
+                    Ok(_) => match self.conn.process_new_packets() {
+                        Ok(io) => io.peer_has_closed() || io.plaintext_bytes_to_read() > 0,
+                        Err(_) => true,
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+                    Err(_) => true,
+                };
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => closed = true,
+        }
+        if self.sock.set_nonblocking(false).is_err() {
+            return true;
+        }
+        if closed {
+            debug!("Connection was closed by the peer");
+        }
+        closed
+    }
+
     // Does IO for the connection.
     pub fn handshake(&mut self) -> TLSResult<(usize, usize)> {
         let mut eof = false;
@@ -104,7 +174,8 @@ impl TlsStream {
 
             match self.conn.process_new_packets() {
                 Ok(io) => debug!("{:#?}", io),
-                Err(e) => return Err(Error::new(ErrorKind::Interrupted, e.to_string())),
+                // not Interrupted, write_all would retry it forever
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
             }
 
             if !self.conn.is_handshaking() && handshake && self.conn.wants_write() {
@@ -126,10 +197,8 @@ impl TlsStream {
             };
         }
     }
-}
-impl Read for TlsStream {
-    // Reads once
-    fn read(&mut self, buf: &mut [u8]) -> TLSResult<usize> {
+
+    fn read_once(&mut self, buf: &mut [u8]) -> TLSResult<usize> {
         if self.conn.is_handshaking() {
             self.handshake()?;
         }
@@ -150,17 +219,51 @@ impl Read for TlsStream {
                     io.peer_has_closed()
                 );
             }
-            Err(ref e) => return Err(Error::new(ErrorKind::ConnectionAborted, e.to_string())),
+            Err(e) => return Err(Error::new(ErrorKind::ConnectionAborted, e)),
         };
 
         debug!("Finished reading");
         match self.conn.reader().read(buf) {
-            Ok(u) => return Ok(u),
-            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
-            Err(e) => return Err(e),
+            Ok(u) => Ok(u),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e),
         }
     }
 
+    fn write_once(&mut self, buf: &[u8]) -> TLSResult<usize> {
+        if self.conn.is_handshaking() {
+            self.handshake()?;
+        };
+        if self.conn.wants_write() {
+            self.handshake()?;
+        };
+
+        let len = self.conn.writer().write(buf)?;
+        self.conn.writer().flush()?;
+        self.conn.write_tls(&mut self.buf_w)?;
+
+        self.buf_w.flush()?;
+
+        debug!("Finished writing");
+        Ok(len)
+    }
+
+    fn flush_once(&mut self) -> TLSResult<()> {
+        self.handshake()?;
+        self.conn.writer().flush()?;
+        if self.conn.wants_write() {
+            self.handshake()?;
+        }
+        Ok(())
+    }
+}
+impl Read for TlsStream {
+    // Reads once
+    fn read(&mut self, buf: &mut [u8]) -> TLSResult<usize> {
+        self.timeouts.arm(&self.sock)?;
+        self.read_once(buf).map_err(|e| self.timeouts.map_error(e))
+    }
+
     // Reads till EOF
     // Tweaked a bit to work nicely
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> TLSResult<usize> {
@@ -195,29 +298,12 @@ impl Read for TlsStream {
 impl Write for TlsStream {
     // Writes encrypted data to the socket
     fn write(&mut self, buf: &[u8]) -> TLSResult<usize> {
-        if self.conn.is_handshaking() {
-            self.handshake()?;
-        };
-        if self.conn.wants_write() {
-            self.handshake()?;
-        };
-
-        let len = self.conn.writer().write(buf)?;
-        self.conn.writer().flush()?;
-        self.conn.write_tls(&mut self.buf_w)?;
-
-        self.buf_w.flush()?;
-
-        debug!("Finished writing");
-        Ok(len)
+        self.timeouts.arm(&self.sock)?;
+        self.write_once(buf).map_err(|e| self.timeouts.map_error(e))
     }
     // Flushes all buffers
     fn flush(&mut self) -> TLSResult<()> {
-        self.handshake()?;
-        self.conn.writer().flush()?;
-        if self.conn.wants_write() {
-            self.handshake()?;
-        }
-        Ok(())
+        self.timeouts.arm(&self.sock)?;
+        self.flush_once().map_err(|e| self.timeouts.map_error(e))
     }
 }